@@ -0,0 +1,115 @@
+use crate::memory;
+use core::{mem, ptr, slice};
+use x86_64::PhysAddr;
+
+// 全てのACPIテーブルの先頭にあるヘッダ
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+pub struct SdtHeader {
+    pub signature: [u8; 4],
+    pub length: u32,
+    pub revision: u8,
+    pub checksum: u8,
+    pub oem_id: [u8; 6],
+    pub oem_table_id: [u8; 8],
+    pub oem_revision: u32,
+    pub creator_id: u32,
+    pub creator_revision: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+struct Rsdp {
+    signature: [u8; 8],
+    checksum: u8,
+    oem_id: [u8; 6],
+    revision: u8,
+    rsdt_address: u32,
+    // 以下はrevision 2以降（ACPI 2.0）のみ
+    length: u32,
+    xsdt_address: u64,
+    extended_checksum: u8,
+    reserved: [u8; 3],
+}
+
+const RSDP_V1_LENGTH: usize = 20;
+
+// 物理メモリ全体のマッピングを通じて物理アドレスから値を読む
+unsafe fn read_phys<T: Copy>(addr: PhysAddr) -> T {
+    ptr::read_unaligned(memory::phys_to_virt(addr).as_ptr::<T>())
+}
+
+unsafe fn phys_bytes(addr: PhysAddr, len: usize) -> &'static [u8] {
+    slice::from_raw_parts(memory::phys_to_virt(addr).as_ptr::<u8>(), len)
+}
+
+fn checksum_ok(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, &b| sum.wrapping_add(b)) == 0
+}
+
+// EBDAの先頭1KiBとBIOS領域(0xE0000..0x100000)の16バイト境界からRSDPを探す
+fn find_rsdp() -> Option<(PhysAddr, Rsdp)> {
+    let ebda = unsafe { read_phys::<u16>(PhysAddr::new(0x40e)) } as u64 * 16;
+    let ebda_range = ebda..ebda + 1024;
+    let bios_range = 0xe0000..0x100000;
+
+    ebda_range
+        .step_by(16)
+        .chain(bios_range.step_by(16))
+        .filter(|&addr| addr != 0)
+        .find_map(|addr| {
+            let addr = PhysAddr::new(addr);
+            let rsdp: Rsdp = unsafe { read_phys(addr) };
+            if &rsdp.signature != b"RSD PTR " {
+                return None;
+            }
+            if !checksum_ok(unsafe { phys_bytes(addr, RSDP_V1_LENGTH) }) {
+                return None;
+            }
+            Some((addr, rsdp))
+        })
+}
+
+// 指定されたシグネチャのACPIテーブルの物理アドレスを返す
+pub fn find_table(signature: &[u8; 4]) -> Option<PhysAddr> {
+    let (_, rsdp) = find_rsdp()?;
+
+    // ACPI 2.0以降はXSDT（64bitのエントリ）、それ以前はRSDT（32bitのエントリ）を使う
+    let (root, entry_size) = if rsdp.revision >= 2 && rsdp.xsdt_address != 0 {
+        (PhysAddr::new(rsdp.xsdt_address), mem::size_of::<u64>())
+    } else {
+        (
+            PhysAddr::new(rsdp.rsdt_address as u64),
+            mem::size_of::<u32>(),
+        )
+    };
+
+    let header: SdtHeader = unsafe { read_phys(root) };
+    let entries_start = root + mem::size_of::<SdtHeader>();
+    let entry_count = (header.length as usize - mem::size_of::<SdtHeader>()) / entry_size;
+
+    (0..entry_count).find_map(|i| {
+        let entry = entries_start + i * entry_size;
+        let table = if entry_size == mem::size_of::<u64>() {
+            PhysAddr::new(unsafe { read_phys::<u64>(entry) })
+        } else {
+            PhysAddr::new(unsafe { read_phys::<u32>(entry) } as u64)
+        };
+
+        let header: SdtHeader = unsafe { read_phys(table) };
+        if &header.signature != signature {
+            return None;
+        }
+        if !checksum_ok(unsafe { phys_bytes(table, header.length as usize) }) {
+            return None;
+        }
+        Some(table)
+    })
+}
+
+// 物理アドレスにあるテーブルを読む
+/// # Safety
+// 呼び出し元はaddrに型Tのテーブルが存在することを保証しなければならない
+pub unsafe fn read_table<T: Copy>(addr: PhysAddr) -> T {
+    read_phys(addr)
+}
@@ -1,4 +1,7 @@
+use crate::sync::{SpinLock, SpinLockGuard};
+use crate::thread::{self, PreemptGuard};
 use alloc::alloc::{GlobalAlloc, Layout};
+use core::ops::{Deref, DerefMut};
 use core::ptr::null_mut;
 use x86_64::{
     structures::paging::{
@@ -7,17 +10,17 @@ use x86_64::{
     VirtAddr,
 };
 
-use self::bump::BumpAllocator;
+use self::fixed_size_block::FixedSizeBlockAllocator;
 
 pub mod bump;
 pub mod fixed_size_block;
 pub mod linked_list;
 
 #[global_allocator]
-static ALLOCATOR: Locked<BumpAllocator> = Locked::new(BumpAllocator::new());
+static ALLOCATOR: Locked<FixedSizeBlockAllocator> = Locked::new(FixedSizeBlockAllocator::new());
 
 pub const HEAP_START: usize = 0x_4444_4444_0000;
-pub const HEAP_SIZE: usize = 100 * 1024; // 100Kib
+pub const HEAP_SIZE: usize = 1024 * 1024; // 1MiB
 
 pub fn init_heap(
     mapper: &mut impl Mapper<Size4KiB>,
@@ -51,19 +54,45 @@ pub fn init_heap(
     Ok(())
 }
 
+// 割り込みハンドラの中で割り当てても、割り込まれた側が持っているロックを待ち続けないようにSpinLockを使う
+// ロックを持っている間はプリエンプションも禁止し、ロックを持ったままスレッドが切り替わらないようにする
 pub struct Locked<A> {
-    inner: spin::Mutex<A>,
+    inner: SpinLock<A>,
 }
 
 impl<A> Locked<A> {
     pub const fn new(inner: A) -> Self {
         Locked {
-            inner: spin::Mutex::new(inner),
+            inner: SpinLock::new(inner),
         }
     }
 
-    pub fn lock(&self) -> spin::MutexGuard<A> {
-        self.inner.lock()
+    pub fn lock(&self) -> LockedGuard<'_, A> {
+        let preempt = thread::disable_preemption();
+        LockedGuard {
+            inner: self.inner.lock(),
+            _preempt: preempt,
+        }
+    }
+}
+
+// ロックを外してからプリエンプションを許可するよう、innerを先に捨てる
+pub struct LockedGuard<'a, A> {
+    inner: SpinLockGuard<'a, A>,
+    _preempt: PreemptGuard,
+}
+
+impl<A> Deref for LockedGuard<'_, A> {
+    type Target = A;
+
+    fn deref(&self) -> &A {
+        &self.inner
+    }
+}
+
+impl<A> DerefMut for LockedGuard<'_, A> {
+    fn deref_mut(&mut self) -> &mut A {
+        &mut self.inner
     }
 }
 
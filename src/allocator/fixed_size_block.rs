@@ -38,6 +38,12 @@ impl FixedSizeBlockAllocator {
     }
 }
 
+impl Default for FixedSizeBlockAllocator {
+    fn default() -> Self {
+        FixedSizeBlockAllocator::new()
+    }
+}
+
 // 受け取ったlayoutを満たすブロックサイズを返す
 fn list_index(layout: &Layout) -> Option<usize> {
     let required_block_size = layout.size().max(layout.align());
@@ -90,6 +90,12 @@ impl LinkedListAllocator {
     }
 }
 
+impl Default for LinkedListAllocator {
+    fn default() -> Self {
+        LinkedListAllocator::new()
+    }
+}
+
 struct ListNode {
     size: usize,
     next: Option<&'static mut ListNode>,
@@ -0,0 +1,136 @@
+use crate::memory;
+use core::arch::x86_64::__cpuid;
+use core::ptr;
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::registers::model_specific::Msr;
+use x86_64::structures::paging::{FrameAllocator, Mapper, Size4KiB};
+use x86_64::PhysAddr;
+
+const IA32_APIC_BASE: u32 = 0x1b;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+const APIC_BASE_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+// Local APICのレジスタのオフセット
+const ID: u64 = 0x020;
+const TASK_PRIORITY: u64 = 0x080;
+const EOI: u64 = 0x0b0;
+const SPURIOUS_INTERRUPT_VECTOR: u64 = 0x0f0;
+const ICR_LOW: u64 = 0x300;
+const ICR_HIGH: u64 = 0x310;
+
+// スプリアス割り込みのベクタ番号
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+// hltしているCPUを起こすためのIPIのベクタ番号
+pub const WAKEUP_VECTOR: u8 = 0xf0;
+// TLBのエントリを無効にさせるためのIPIのベクタ番号
+pub const TLB_SHOOTDOWN_VECTOR: u8 = 0xf1;
+const SVR_APIC_ENABLE: u32 = 1 << 8;
+
+// ICRのビット
+pub(crate) const DELIVERY_FIXED: u32 = 0b000 << 8;
+pub(crate) const DELIVERY_NMI: u32 = 0b100 << 8;
+const DELIVERY_INIT: u32 = 0b101 << 8;
+const DELIVERY_STARTUP: u32 = 0b110 << 8;
+const DELIVERY_STATUS: u32 = 1 << 12;
+pub(crate) const LEVEL_ASSERT: u32 = 1 << 14;
+const TRIGGER_LEVEL: u32 = 1 << 15;
+// 宛先の省略形。指定した場合ICR_HIGHの宛先は無視される
+pub(crate) const SHORTHAND_SELF: u32 = 0b01 << 18;
+pub(crate) const SHORTHAND_ALL_INCLUDING_SELF: u32 = 0b10 << 18;
+pub(crate) const SHORTHAND_ALL_EXCLUDING_SELF: u32 = 0b11 << 18;
+
+// マップ済みのレジスタの仮想アドレス、未初期化の場合は0
+static BASE: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ApicError {
+    // CPUIDでLocal APICが報告されていない
+    NotPresent,
+    // レジスタをマップできなかった
+    MapFailed,
+}
+
+unsafe fn read(offset: u64) -> u32 {
+    ptr::read_volatile((BASE.load(Ordering::Relaxed) + offset) as *const u32)
+}
+
+unsafe fn write(offset: u64, value: u32) {
+    ptr::write_volatile((BASE.load(Ordering::Relaxed) + offset) as *mut u32, value)
+}
+
+pub fn is_enabled() -> bool {
+    BASE.load(Ordering::Relaxed) != 0
+}
+
+// BSPのLocal APICのレジスタをマップして有効にする
+// レジスタの物理アドレスは全てのCPUで同じなので、APはinit_apで有効にするだけでよい
+pub fn init(
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), ApicError> {
+    // CPUID.01H:EDX[9]
+    if __cpuid(1).edx & (1 << 9) == 0 {
+        return Err(ApicError::NotPresent);
+    }
+    let apic_base = unsafe { Msr::new(IA32_APIC_BASE).read() };
+    let base = memory::map_mmio(
+        PhysAddr::new(apic_base & APIC_BASE_MASK),
+        4096,
+        mapper,
+        frame_allocator,
+    )
+    .map_err(|_| ApicError::MapFailed)?;
+    BASE.store(base.as_u64(), Ordering::Relaxed);
+
+    init_ap();
+    Ok(())
+}
+
+// 実行中のCPUのLocal APICを有効にする
+pub fn init_ap() {
+    unsafe {
+        let mut msr = Msr::new(IA32_APIC_BASE);
+        let apic_base = msr.read();
+        msr.write(apic_base | APIC_BASE_ENABLE);
+
+        // 全ての割り込みを受け付ける
+        write(TASK_PRIORITY, 0);
+        write(
+            SPURIOUS_INTERRUPT_VECTOR,
+            SVR_APIC_ENABLE | SPURIOUS_VECTOR as u32,
+        );
+    }
+}
+
+// 実行中のCPUのLocal APIC ID
+pub fn id() -> u32 {
+    unsafe { read(ID) >> 24 }
+}
+
+// Local APICから届いた割り込みの処理の終わりを通知する
+pub fn end_of_interrupt() {
+    unsafe { write(EOI, 0) };
+}
+
+// ICRに書き込んでIPIを送り、送信が終わるまで待つ
+// ICR_HIGHとICR_LOWの書き込みの間に割り込みハンドラがIPIを送らないよう割り込みを止める
+pub(crate) fn send_ipi(apic_id: u32, command: u32) {
+    x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+        write(ICR_HIGH, apic_id << 24);
+        write(ICR_LOW, command);
+        while read(ICR_LOW) & DELIVERY_STATUS != 0 {
+            core::hint::spin_loop();
+        }
+    });
+}
+
+// APをリセットしてSIPIを待つ状態にする
+pub(crate) fn send_init(apic_id: u32) {
+    send_ipi(apic_id, DELIVERY_INIT | LEVEL_ASSERT | TRIGGER_LEVEL);
+    send_ipi(apic_id, DELIVERY_INIT | TRIGGER_LEVEL);
+}
+
+// APを物理アドレスpage * 4096からリアルモードで実行させる
+pub(crate) fn send_startup(apic_id: u32, page: u8) {
+    send_ipi(apic_id, DELIVERY_STARTUP | LEVEL_ASSERT | page as u32);
+}
@@ -0,0 +1,137 @@
+use core::convert::TryInto;
+use core::mem;
+
+// ELFファイルの解析に失敗した理由
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElfError {
+    // ファイルがヘッダやプログラムヘッダより短い
+    Truncated,
+    // マジックナンバーが一致しない
+    BadMagic,
+    // 64bitリトルエンディアンのx86_64の実行ファイルではない
+    Unsupported,
+}
+
+const ELF_MAGIC: [u8; 4] = [0x7f, b'E', b'L', b'F'];
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const ET_EXEC: u16 = 2;
+const EM_X86_64: u16 = 62;
+
+pub const PT_LOAD: u32 = 1;
+
+// プログラムヘッダのフラグ
+pub const PF_X: u32 = 1 << 0;
+pub const PF_W: u32 = 1 << 1;
+pub const PF_R: u32 = 1 << 2;
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct ElfHeader {
+    pub ident: [u8; 16],
+    pub elf_type: u16,
+    pub machine: u16,
+    pub version: u32,
+    pub entry: u64,
+    pub phoff: u64,
+    pub shoff: u64,
+    pub flags: u32,
+    pub ehsize: u16,
+    pub phentsize: u16,
+    pub phnum: u16,
+    pub shentsize: u16,
+    pub shnum: u16,
+    pub shstrndx: u16,
+}
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct ProgramHeader {
+    pub p_type: u32,
+    pub flags: u32,
+    pub offset: u64,
+    pub vaddr: u64,
+    pub paddr: u64,
+    pub filesz: u64,
+    pub memsz: u64,
+    pub align: u64,
+}
+
+// 検証済みのELF64ファイル
+pub struct Elf<'a> {
+    data: &'a [u8],
+    header: ElfHeader,
+}
+
+// バイト列の指定した位置から構造体を読む
+fn read<T: Copy>(data: &[u8], offset: usize) -> Result<T, ElfError> {
+    let end = offset
+        .checked_add(mem::size_of::<T>())
+        .ok_or(ElfError::Truncated)?;
+    let bytes = data.get(offset..end).ok_or(ElfError::Truncated)?;
+    Ok(unsafe { core::ptr::read_unaligned(bytes.as_ptr() as *const T) })
+}
+
+impl<'a> Elf<'a> {
+    pub fn parse(data: &'a [u8]) -> Result<Self, ElfError> {
+        let header: ElfHeader = read(data, 0)?;
+        if header.ident[0..4] != ELF_MAGIC {
+            return Err(ElfError::BadMagic);
+        }
+        if header.ident[4] != ELFCLASS64
+            || header.ident[5] != ELFDATA2LSB
+            || header.elf_type != ET_EXEC
+            || header.machine != EM_X86_64
+            || (header.phentsize as usize) < mem::size_of::<ProgramHeader>()
+        {
+            return Err(ElfError::Unsupported);
+        }
+
+        let elf = Elf { data, header };
+        // 全てのプログラムヘッダと、PT_LOADセグメントのファイル上の範囲がデータに収まっていること
+        for i in 0..header.phnum as usize {
+            let ph = elf.program_header(i)?;
+            if ph.p_type == PT_LOAD {
+                elf.segment_data(&ph)?;
+            }
+        }
+        Ok(elf)
+    }
+
+    pub fn header(&self) -> &ElfHeader {
+        &self.header
+    }
+
+    pub fn entry(&self) -> u64 {
+        self.header.entry
+    }
+
+    fn program_header(&self, index: usize) -> Result<ProgramHeader, ElfError> {
+        let phoff: usize = self
+            .header
+            .phoff
+            .try_into()
+            .map_err(|_| ElfError::Truncated)?;
+        let offset = index
+            .checked_mul(self.header.phentsize as usize)
+            .and_then(|o| o.checked_add(phoff))
+            .ok_or(ElfError::Truncated)?;
+        read(self.data, offset)
+    }
+
+    pub fn program_headers(&self) -> impl Iterator<Item = ProgramHeader> + '_ {
+        // parseで全て読めることを確認済み
+        (0..self.header.phnum as usize).filter_map(move |i| self.program_header(i).ok())
+    }
+
+    // セグメントのファイル上のデータを返す
+    pub fn segment_data(&self, ph: &ProgramHeader) -> Result<&'a [u8], ElfError> {
+        if ph.filesz > ph.memsz {
+            return Err(ElfError::Unsupported);
+        }
+        let start: usize = ph.offset.try_into().map_err(|_| ElfError::Truncated)?;
+        let len: usize = ph.filesz.try_into().map_err(|_| ElfError::Truncated)?;
+        let end = start.checked_add(len).ok_or(ElfError::Truncated)?;
+        self.data.get(start..end).ok_or(ElfError::Truncated)
+    }
+}
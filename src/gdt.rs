@@ -1,52 +1,86 @@
-use lazy_static::lazy_static;
+use crate::percpu;
 use x86_64::registers::segmentation::Segment;
 use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector};
 use x86_64::structures::tss::TaskStateSegment;
-use x86_64::VirtAddr;
+use x86_64::{PrivilegeLevel, VirtAddr};
 
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
 
+// GDTのエントリの並びはどのCPUでも同じなのでセレクタは固定
+// SYSRETはSTARに設定したセレクタ+8をSS、+16をCSとして使うため
+// ユーザー用のデータセグメントをコードセグメントの直前に置く
+const KERNEL_CODE_SELECTOR: SegmentSelector = SegmentSelector::new(1, PrivilegeLevel::Ring0);
+const KERNEL_DATA_SELECTOR: SegmentSelector = SegmentSelector::new(2, PrivilegeLevel::Ring0);
+const USER_DATA_SELECTOR: SegmentSelector = SegmentSelector::new(3, PrivilegeLevel::Ring3);
+const USER_CODE_SELECTOR: SegmentSelector = SegmentSelector::new(4, PrivilegeLevel::Ring3);
+const TSS_SELECTOR: SegmentSelector = SegmentSelector::new(5, PrivilegeLevel::Ring0);
+
+// 実行中のCPUのTSSとGDTを作ってロードする
+// TSSはCPUごとに必要なため、percpuのデータの中に置く
 pub fn init() {
-    use x86_64::instructions::segmentation::CS;
+    use x86_64::instructions::segmentation::{CS, DS, ES, SS};
     use x86_64::instructions::tables::load_tss;
 
-    GDT.0.load();
+    let cpu = percpu::current();
+    let tss: &'static mut TaskStateSegment = unsafe { &mut *cpu.tss() };
+    tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = cpu.double_fault_stack();
+    tss.privilege_stack_table[0] = cpu.privilege_stack();
+
+    let gdt: &'static mut GlobalDescriptorTable = unsafe { &mut *cpu.gdt() };
+    *gdt = GlobalDescriptorTable::new();
+    let selectors = [
+        gdt.add_entry(Descriptor::kernel_code_segment()),
+        gdt.add_entry(Descriptor::kernel_data_segment()),
+        gdt.add_entry(Descriptor::user_data_segment()),
+        gdt.add_entry(Descriptor::user_code_segment()),
+        gdt.add_entry(Descriptor::tss_segment(tss)),
+    ];
+    assert_eq!(
+        selectors.map(|selector| selector.0),
+        [
+            KERNEL_CODE_SELECTOR.0,
+            KERNEL_DATA_SELECTOR.0,
+            USER_DATA_SELECTOR.0,
+            USER_CODE_SELECTOR.0,
+            TSS_SELECTOR.0,
+        ]
+    );
+
+    gdt.load();
     unsafe {
-        CS::set_reg(GDT.1.code_selector);
-        load_tss(GDT.1.tss_selector);
+        CS::set_reg(KERNEL_CODE_SELECTOR);
+        SS::set_reg(KERNEL_DATA_SELECTOR);
+        DS::set_reg(KERNEL_DATA_SELECTOR);
+        ES::set_reg(KERNEL_DATA_SELECTOR);
+        load_tss(TSS_SELECTOR);
     }
 }
 
-lazy_static! {
-    static ref TSS: TaskStateSegment = {
-        let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096 * 5;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-
-            let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
-            stack_start + STACK_SIZE // stack end
-        };
-        tss
-    };
+// ring 3からの割り込みでring 0に移るときに使うスタック
+// ユーザーモードを使うスレッドは切り替え時にset_kernel_stackで自分のスタックに差し替える
+pub fn default_kernel_stack() -> VirtAddr {
+    percpu::current().privilege_stack()
+}
+
+/// # Safety
+// ring 3から割り込みで入るときのスタックを差し替える
+// 呼び出し元は割り込みを無効にし、topが有効なスタックの終端であることを保証しなければならない
+pub unsafe fn set_kernel_stack(top: VirtAddr) {
+    (*percpu::current().tss()).privilege_stack_table[0] = top;
+}
+
+pub fn kernel_code_selector() -> SegmentSelector {
+    KERNEL_CODE_SELECTOR
+}
+
+pub fn kernel_data_selector() -> SegmentSelector {
+    KERNEL_DATA_SELECTOR
 }
 
-lazy_static! {
-    static ref GDT: (GlobalDescriptorTable, Selectors) = {
-        let mut gdt = GlobalDescriptorTable::new();
-        let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-        let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
-        (
-            gdt,
-            Selectors {
-                code_selector,
-                tss_selector,
-            },
-        )
-    };
+pub fn user_code_selector() -> SegmentSelector {
+    USER_CODE_SELECTOR
 }
 
-struct Selectors {
-    code_selector: SegmentSelector,
-    tss_selector: SegmentSelector,
+pub fn user_data_selector() -> SegmentSelector {
+    USER_DATA_SELECTOR
 }
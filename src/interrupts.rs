@@ -1,22 +1,34 @@
+use crate::percpu::KernelGsGuard;
+use crate::println;
+use crate::sync::SpinLock;
 use crate::{gdt, hlt_loop};
-use crate::{print, println};
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
 use lazy_static::lazy_static;
 use pic8259::ChainedPics;
-use spin;
+use x86_64::instructions::port::Port;
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+use x86_64::PrivilegeLevel;
 
 pub const PIC_1_OFFSET: u8 = 32;
 pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
 
-pub static PICS: spin::Mutex<ChainedPics> =
-    spin::Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
+// ユーザーモードからカーネルに戻るためのソフトウェア割り込み
+pub const USER_EXIT_VECTOR: u8 = 0x80;
+
+pub static PICS: SpinLock<ChainedPics> =
+    SpinLock::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
 
 #[derive(Debug, Clone, Copy)]
 #[repr(u8)]
 pub enum InterruptIndex {
     // Primary Interrupt Controller Index
-    Timer = PIC_1_OFFSET, //32
-    Keyboard,             // 33
+    Timer = PIC_1_OFFSET,               // 32
+    Keyboard,                           // 33
+    PrimarySpurious = PIC_1_OFFSET + 7, // 39 (IRQ7)
+    // Secondary Interrupt Controller Index
+    Rtc = PIC_2_OFFSET,                   // 40
+    SecondarySpurious = PIC_2_OFFSET + 7, // 47 (IRQ15)
 }
 
 impl InterruptIndex {
@@ -33,6 +45,7 @@ lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
         idt.breakpoint.set_handler_fn(breakpoint_handler);
+        idt.non_maskable_interrupt.set_handler_fn(nmi_handler);
         unsafe {
             idt.double_fault
                 .set_handler_fn(double_fault_handler)
@@ -40,7 +53,24 @@ lazy_static! {
         }
         idt[InterruptIndex::Timer.as_usize()].set_handler_fn(timer_interrupt_handler);
         idt[InterruptIndex::Keyboard.as_usize()].set_handler_fn(keyboard_interrupt_handler);
+        idt[InterruptIndex::Rtc.as_usize()].set_handler_fn(rtc_interrupt_handler);
+        idt[InterruptIndex::PrimarySpurious.as_usize()]
+            .set_handler_fn(primary_spurious_interrupt_handler);
+        idt[InterruptIndex::SecondarySpurious.as_usize()]
+            .set_handler_fn(secondary_spurious_interrupt_handler);
+        idt.general_protection_fault
+            .set_handler_fn(general_protection_fault_handler);
         idt.page_fault.set_handler_fn(page_fault_handler);
+        idt[crate::apic::SPURIOUS_VECTOR as usize].set_handler_fn(apic_spurious_interrupt_handler);
+        idt[crate::apic::WAKEUP_VECTOR as usize].set_handler_fn(wakeup_interrupt_handler);
+        idt[crate::apic::TLB_SHOOTDOWN_VECTOR as usize]
+            .set_handler_fn(tlb_shootdown_interrupt_handler);
+        // ring 3からint命令で呼び出せるようにする
+        unsafe {
+            idt[USER_EXIT_VECTOR as usize]
+                .set_handler_addr(crate::usermode::exit_entry_address())
+                .set_privilege_level(PrivilegeLevel::Ring3);
+        }
         idt
     };
 }
@@ -49,27 +79,173 @@ pub fn init_idt() {
     IDT.load();
 }
 
+const PIC_1_COMMAND: u16 = 0x20;
+const PIC_2_COMMAND: u16 = 0xa0;
+// OCW3: 次の読み込みでIn-Service Registerを返す
+const PIC_READ_ISR: u8 = 0x0b;
+const PIC_EOI: u8 = 0x20;
+
+// ベクタ番号ごとの割り込みの回数
+static INTERRUPT_COUNTS: [AtomicU64; 256] = [const { AtomicU64::new(0) }; 256];
+// PICのスプリアス割り込みの回数 ([0]: IRQ7, [1]: IRQ15)
+static SPURIOUS_COUNTS: [AtomicU64; 2] = [AtomicU64::new(0), AtomicU64::new(0)];
+
+// 全ての割り込みハンドラの先頭で呼び出す
+fn record(vector: u8) {
+    INTERRUPT_COUNTS[vector as usize].fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn interrupt_count(vector: u8) -> u64 {
+    INTERRUPT_COUNTS[vector as usize].load(Ordering::Relaxed)
+}
+
+pub fn spurious_count() -> (u64, u64) {
+    (
+        SPURIOUS_COUNTS[0].load(Ordering::Relaxed),
+        SPURIOUS_COUNTS[1].load(Ordering::Relaxed),
+    )
+}
+
+fn vector_name(vector: u8) -> &'static str {
+    const EXCEPTIONS: [&str; 21] = [
+        "divide error",
+        "debug",
+        "non-maskable interrupt",
+        "breakpoint",
+        "overflow",
+        "bound range exceeded",
+        "invalid opcode",
+        "device not available",
+        "double fault",
+        "coprocessor segment overrun",
+        "invalid TSS",
+        "segment not present",
+        "stack-segment fault",
+        "general protection fault",
+        "page fault",
+        "reserved",
+        "x87 floating-point",
+        "alignment check",
+        "machine check",
+        "SIMD floating-point",
+        "virtualization",
+    ];
+    match vector {
+        v if (v as usize) < EXCEPTIONS.len() => EXCEPTIONS[v as usize],
+        v if v == InterruptIndex::Timer.as_u8() => "timer",
+        v if v == InterruptIndex::Keyboard.as_u8() => "keyboard",
+        v if v == InterruptIndex::PrimarySpurious.as_u8() => "IRQ7",
+        v if v == InterruptIndex::Rtc.as_u8() => "rtc",
+        v if v == InterruptIndex::SecondarySpurious.as_u8() => "IRQ15",
+        v if v == crate::apic::SPURIOUS_VECTOR => "APIC spurious",
+        v if v == crate::apic::WAKEUP_VECTOR => "wakeup IPI",
+        v if v == crate::apic::TLB_SHOOTDOWN_VECTOR => "TLB shootdown IPI",
+        _ => "",
+    }
+}
+
+// /proc/interrupts風に一度でも発生した割り込みの回数を書き出す
+pub fn write_stats(writer: &mut impl fmt::Write) -> fmt::Result {
+    writeln!(writer, "{:>6} {:>12}  name", "vector", "count")?;
+    for vector in 0..=u8::MAX {
+        let count = interrupt_count(vector);
+        if count > 0 {
+            writeln!(
+                writer,
+                "{:>6} {:>12}  {}",
+                vector,
+                count,
+                vector_name(vector)
+            )?;
+        }
+    }
+    let (primary, secondary) = spurious_count();
+    writeln!(writer, "{:>6} {:>12}  spurious IRQ7", "SPU", primary)?;
+    writeln!(writer, "{:>6} {:>12}  spurious IRQ15", "SPU", secondary)
+}
+
+pub fn print_stats() {
+    struct Console;
+
+    impl fmt::Write for Console {
+        fn write_str(&mut self, s: &str) -> fmt::Result {
+            crate::print!("{}", s);
+            Ok(())
+        }
+    }
+
+    write_stats(&mut Console).expect("printing interrupt stats failed");
+}
+
+// PICのIn-Service Registerを読み、irqが実際に処理中かを確認する
+// 処理中でなければスプリアス割り込み
+fn pic_in_service(irq: u8) -> bool {
+    let (port, bit) = if irq < 8 {
+        (PIC_1_COMMAND, irq)
+    } else {
+        (PIC_2_COMMAND, irq - 8)
+    };
+    let mut command: Port<u8> = Port::new(port);
+    unsafe {
+        command.write(PIC_READ_ISR);
+        command.read() & (1 << bit) != 0
+    }
+}
+
+// PICのIRQのマスクを解除する。スレーブのIRQの場合はカスケード用のIRQ2も解除する
+pub fn unmask_irq(irq: u8) {
+    let mut primary_data: Port<u8> = Port::new(0x21);
+    let mut secondary_data: Port<u8> = Port::new(0xa1);
+    x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+        if irq < 8 {
+            let mask = primary_data.read();
+            primary_data.write(mask & !(1 << irq));
+        } else {
+            let mask = secondary_data.read();
+            secondary_data.write(mask & !(1 << (irq - 8)));
+            let mask = primary_data.read();
+            primary_data.write(mask & !(1 << 2));
+        }
+    });
+}
+
 extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
+    let _gs = KernelGsGuard::enter(&stack_frame);
+    record(3);
     println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
 }
 
+// 他のCPUからipi::Ipi::Nmiで送られてくる。今は回数を数えるだけ
+extern "x86-interrupt" fn nmi_handler(stack_frame: InterruptStackFrame) {
+    let _gs = KernelGsGuard::enter(&stack_frame);
+    record(2);
+}
+
 extern "x86-interrupt" fn double_fault_handler(
     stack_frame: InterruptStackFrame,
     _error_code: u64,
 ) -> ! {
+    let _gs = KernelGsGuard::enter(&stack_frame);
+    record(8);
     panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
 }
 
-extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    print!(".");
+extern "x86-interrupt" fn timer_interrupt_handler(stack_frame: InterruptStackFrame) {
+    let _gs = KernelGsGuard::enter(&stack_frame);
+    record(InterruptIndex::Timer.as_u8());
+    crate::time::tick();
+
     unsafe {
         PICS.lock()
             .notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
     }
+    // EOIを送ってからでないと切り替え先のスレッドでタイマー割り込みが来ない
+    crate::thread::preempt();
 }
 
-extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    use x86_64::instructions::port::Port;
+extern "x86-interrupt" fn keyboard_interrupt_handler(stack_frame: InterruptStackFrame) {
+    let _gs = KernelGsGuard::enter(&stack_frame);
+    record(InterruptIndex::Keyboard.as_u8());
 
     let mut port = Port::new(0x60);
     let scancode: u8 = unsafe { port.read() };
@@ -81,12 +257,119 @@ extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStac
     }
 }
 
+extern "x86-interrupt" fn rtc_interrupt_handler(stack_frame: InterruptStackFrame) {
+    let _gs = KernelGsGuard::enter(&stack_frame);
+    record(InterruptIndex::Rtc.as_u8());
+    crate::time::rtc::handle_interrupt();
+
+    unsafe {
+        PICS.lock()
+            .notify_end_of_interrupt(InterruptIndex::Rtc.as_u8());
+    }
+}
+
+// IRQ7はPICがスプリアス割り込みに使うため、ISRを確認して本物の場合のみEOIを送る
+extern "x86-interrupt" fn primary_spurious_interrupt_handler(stack_frame: InterruptStackFrame) {
+    let _gs = KernelGsGuard::enter(&stack_frame);
+    if !pic_in_service(7) {
+        SPURIOUS_COUNTS[0].fetch_add(1, Ordering::Relaxed);
+        return;
+    }
+    record(InterruptIndex::PrimarySpurious.as_u8());
+
+    unsafe {
+        PICS.lock()
+            .notify_end_of_interrupt(InterruptIndex::PrimarySpurious.as_u8());
+    }
+}
+
+// IRQ15がスプリアスの場合もマスターはカスケード(IRQ2)を処理中なのでマスターにだけEOIを送る
+extern "x86-interrupt" fn secondary_spurious_interrupt_handler(stack_frame: InterruptStackFrame) {
+    let _gs = KernelGsGuard::enter(&stack_frame);
+    if !pic_in_service(15) {
+        SPURIOUS_COUNTS[1].fetch_add(1, Ordering::Relaxed);
+        let mut command: Port<u8> = Port::new(PIC_1_COMMAND);
+        unsafe { command.write(PIC_EOI) };
+        return;
+    }
+    record(InterruptIndex::SecondarySpurious.as_u8());
+
+    unsafe {
+        PICS.lock()
+            .notify_end_of_interrupt(InterruptIndex::SecondarySpurious.as_u8());
+    }
+}
+
+// hltしているCPUを起こすだけなので、EOIを送るだけでよい
+extern "x86-interrupt" fn wakeup_interrupt_handler(stack_frame: InterruptStackFrame) {
+    let _gs = KernelGsGuard::enter(&stack_frame);
+    record(crate::apic::WAKEUP_VECTOR);
+    crate::apic::end_of_interrupt();
+}
+
+extern "x86-interrupt" fn tlb_shootdown_interrupt_handler(stack_frame: InterruptStackFrame) {
+    let _gs = KernelGsGuard::enter(&stack_frame);
+    record(crate::apic::TLB_SHOOTDOWN_VECTOR);
+    crate::tlb::handle_pending();
+    crate::apic::end_of_interrupt();
+}
+
+// Local APICのスプリアス割り込みにはEOIを送らない
+extern "x86-interrupt" fn apic_spurious_interrupt_handler(stack_frame: InterruptStackFrame) {
+    let _gs = KernelGsGuard::enter(&stack_frame);
+    record(crate::apic::SPURIOUS_VECTOR);
+}
+
+// ring 3で起きた例外はユーザープログラムの誤りなので、カーネルを止めずにプログラムを終了させる
+fn is_user_fault(stack_frame: &InterruptStackFrame) -> bool {
+    stack_frame.code_segment & 3 != 0
+}
+
+// 割り込みゲートで入っていて割り込みは止まっているので、GSをユーザーの値に戻してから
+// int 0x80と同じ入口でenter_user_modeの呼び出し元に戻る
+fn exit_user_program(gs: KernelGsGuard) -> ! {
+    drop(gs);
+    unsafe { crate::usermode::exit_faulted_program() }
+}
+
+extern "x86-interrupt" fn general_protection_fault_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: u64,
+) {
+    let gs = KernelGsGuard::enter(&stack_frame);
+    record(13);
+
+    if is_user_fault(&stack_frame) {
+        println!(
+            "user program caused a general protection fault at {:?} (error code {:#x})",
+            stack_frame.instruction_pointer, error_code
+        );
+        exit_user_program(gs);
+    }
+    panic!(
+        "EXCEPTION: GENERAL PROTECTION FAULT (error code {:#x})\n{:#?}",
+        error_code, stack_frame
+    );
+}
+
 extern "x86-interrupt" fn page_fault_handler(
     stack_frame: InterruptStackFrame,
     error_code: PageFaultErrorCode,
 ) {
     use x86_64::registers::control::Cr2;
 
+    let gs = KernelGsGuard::enter(&stack_frame);
+    record(14);
+
+    if is_user_fault(&stack_frame) {
+        println!(
+            "user program caused a page fault at {:?} accessing {:?} ({:?})",
+            stack_frame.instruction_pointer,
+            Cr2::read(),
+            error_code
+        );
+        exit_user_program(gs);
+    }
     println!("EXCEPTION: PAGE FAULT");
     println!("Accessed Address: {:?}", Cr2::read());
     println!("Error Code: {:?}", error_code);
@@ -98,3 +381,10 @@ extern "x86-interrupt" fn page_fault_handler(
 fn test_breakpoint_exception() {
     x86_64::instructions::interrupts::int3();
 }
+
+#[test_case]
+fn test_breakpoint_is_counted() {
+    let before = interrupt_count(3);
+    x86_64::instructions::interrupts::int3();
+    assert_eq!(interrupt_count(3), before + 1);
+}
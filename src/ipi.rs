@@ -0,0 +1,47 @@
+use crate::apic;
+
+// IPIの宛先
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Destination {
+    // Local APIC IDで指定したCPU
+    Cpu(u32),
+    // 送信元のCPU自身
+    SelfOnly,
+    // 送信元を含む全てのCPU
+    All,
+    // 送信元以外の全てのCPU
+    AllExcludingSelf,
+}
+
+// IPIの種類
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ipi {
+    // 宛先のCPUでvectorの割り込みを発生させる
+    Fixed(u8),
+    // 宛先のCPUにNMIを発生させる。割り込みが無効でも届く
+    Nmi,
+}
+
+// Local APICを通じて他のCPUにIPIを送る
+// Local APICが有効になる前は何もしない
+pub fn send(destination: Destination, ipi: Ipi) {
+    if !apic::is_enabled() {
+        return;
+    }
+    let delivery = match ipi {
+        Ipi::Fixed(vector) => apic::DELIVERY_FIXED | vector as u32,
+        Ipi::Nmi => apic::DELIVERY_NMI,
+    };
+    let (apic_id, shorthand) = match destination {
+        Destination::Cpu(apic_id) => (apic_id, 0),
+        Destination::SelfOnly => (0, apic::SHORTHAND_SELF),
+        Destination::All => (0, apic::SHORTHAND_ALL_INCLUDING_SELF),
+        Destination::AllExcludingSelf => (0, apic::SHORTHAND_ALL_EXCLUDING_SELF),
+    };
+    apic::send_ipi(apic_id, delivery | apic::LEVEL_ASSERT | shorthand);
+}
+
+// 送信元以外の全てのCPUにvectorの割り込みを発生させる
+pub fn broadcast(vector: u8) {
+    send(Destination::AllExcludingSelf, Ipi::Fixed(vector));
+}
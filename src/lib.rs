@@ -4,19 +4,32 @@
 #![test_runner(crate::test_runner)]
 #![reexport_test_harness_main = "test_main"]
 #![feature(abi_x86_interrupt)]
-#![feature(const_mut_refs)]
 #![feature(alloc_error_handler)]
 
 #[cfg(test)]
 use bootloader::{entry_point, BootInfo};
 use core::panic::PanicInfo;
 
+pub mod acpi;
 pub mod allocator;
+pub mod apic;
+pub mod elf;
 pub mod gdt;
 pub mod interrupts;
+pub mod ipi;
+pub mod loader;
 pub mod memory;
+pub mod percpu;
+pub mod process;
 pub mod serial;
+pub mod smp;
+pub mod sync;
+pub mod syscall;
 pub mod task;
+pub mod thread;
+pub mod time;
+pub mod tlb;
+pub mod usermode;
 pub mod vga_buffer;
 
 extern crate alloc;
@@ -26,9 +39,13 @@ extern crate alloc;
 entry_point!(test_kernel_main);
 
 pub fn init() {
+    percpu::init_bsp();
     gdt::init();
+    syscall::init();
     interrupts::init_idt();
+    tlb::init_cpu();
     unsafe { interrupts::PICS.lock().initialize() };
+    time::init();
     x86_64::instructions::interrupts::enable();
 }
 
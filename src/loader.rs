@@ -0,0 +1,221 @@
+use crate::elf::{self, Elf, ElfError};
+use crate::memory::{self, AddressSpace};
+use crate::thread;
+use crate::usermode::{self, USER_STACK_SIZE, USER_STACK_TOP};
+use alloc::vec::Vec;
+use x86_64::structures::paging::{
+    mapper::{MapToError, TranslateResult},
+    FrameAllocator, Mapper, OffsetPageTable, Page, PageTableFlags, Size4KiB, Translate,
+};
+use x86_64::VirtAddr;
+
+// ユーザープログラムを置ける仮想アドレスの範囲
+pub const USER_IMAGE_START: u64 = usermode::USER_CODE_START;
+pub const USER_IMAGE_END: u64 = USER_STACK_TOP - USER_STACK_SIZE;
+
+// 補助ベクタのタイプ
+const AT_NULL: u64 = 0;
+const AT_PHDR: u64 = 3;
+const AT_PHENT: u64 = 4;
+const AT_PHNUM: u64 = 5;
+const AT_PAGESZ: u64 = 6;
+const AT_ENTRY: u64 = 9;
+
+#[derive(Debug)]
+pub enum LoadError {
+    Elf(ElfError),
+    // セグメントがユーザープログラムを置ける範囲の外にある
+    BadSegment,
+    // エントリポイントが読み込んだセグメントの外にある
+    BadEntry,
+    // 引数と環境変数がスタックに収まらない
+    ArgumentsTooLarge,
+    Map(MapToError<Size4KiB>),
+}
+
+impl From<ElfError> for LoadError {
+    fn from(err: ElfError) -> Self {
+        LoadError::Elf(err)
+    }
+}
+
+impl From<MapToError<Size4KiB>> for LoadError {
+    fn from(err: MapToError<Size4KiB>) -> Self {
+        LoadError::Map(err)
+    }
+}
+
+// 読み込みが終わり、実行を開始できるユーザープログラム
+#[derive(Debug)]
+pub struct LoadedProgram {
+    pub address_space: AddressSpace,
+    pub entry: VirtAddr,
+    pub stack_pointer: VirtAddr,
+}
+
+// ELFファイルを新しいアドレス空間に読み込み、argv/envp/auxvを積んだユーザースタックを用意する
+pub fn load(
+    data: &[u8],
+    args: &[&str],
+    env: &[&str],
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<LoadedProgram, LoadError> {
+    let elf = Elf::parse(data)?;
+    let mut address_space = AddressSpace::new(frame_allocator)
+        .ok_or(LoadError::Map(MapToError::FrameAllocationFailed))?;
+    let mut mapper = address_space.mapper();
+
+    let mut entry_mapped = false;
+    let mut phdr_addr = 0;
+    for ph in elf.program_headers().filter(|ph| ph.p_type == elf::PT_LOAD) {
+        let end = ph
+            .vaddr
+            .checked_add(ph.memsz)
+            .ok_or(LoadError::BadSegment)?;
+        if ph.vaddr < USER_IMAGE_START || end > USER_IMAGE_END {
+            return Err(LoadError::BadSegment);
+        }
+        // カーネルと共有しているレベル4エントリに置くと全てのプロセスから見えてしまう
+        if memory::shared_kernel_slot(VirtAddr::new(ph.vaddr), VirtAddr::new(end)).is_some() {
+            return Err(LoadError::BadSegment);
+        }
+        if ph.memsz == 0 {
+            continue;
+        }
+
+        map_segment(&mut mapper, ph.vaddr, ph.memsz, ph.flags, frame_allocator)?;
+        usermode::copy_to_user(&mapper, VirtAddr::new(ph.vaddr), elf.segment_data(&ph)?);
+
+        if (ph.vaddr..end).contains(&elf.entry()) {
+            entry_mapped = true;
+        }
+        // プログラムヘッダを含むセグメントからAT_PHDRを求める
+        let phoff = elf.header().phoff;
+        if (ph.offset..ph.offset + ph.filesz).contains(&phoff) {
+            phdr_addr = ph.vaddr + (phoff - ph.offset);
+        }
+    }
+    if !entry_mapped {
+        return Err(LoadError::BadEntry);
+    }
+
+    let stack_top = VirtAddr::new(USER_STACK_TOP);
+    usermode::map_user_region(
+        stack_top - USER_STACK_SIZE,
+        USER_STACK_SIZE,
+        PageTableFlags::WRITABLE,
+        &mut mapper,
+        frame_allocator,
+    )?;
+    let auxv = [
+        (AT_PHDR, phdr_addr),
+        (AT_PHENT, elf.header().phentsize as u64),
+        (AT_PHNUM, elf.header().phnum as u64),
+        (AT_PAGESZ, 4096),
+        (AT_ENTRY, elf.entry()),
+        (AT_NULL, 0),
+    ];
+    let stack_pointer = push_initial_stack(&mapper, stack_top, args, env, &auxv)?;
+
+    Ok(LoadedProgram {
+        entry: VirtAddr::new(elf.entry()),
+        stack_pointer,
+        address_space,
+    })
+}
+
+// セグメントのページをマップする。前のセグメントと共有するページは両方の権限を合わせる
+fn map_segment(
+    mapper: &mut OffsetPageTable,
+    vaddr: u64,
+    memsz: u64,
+    segment_flags: u32,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), LoadError> {
+    let executable = segment_flags & elf::PF_X != 0;
+    let mut flags = PageTableFlags::empty();
+    if segment_flags & elf::PF_W != 0 {
+        flags |= PageTableFlags::WRITABLE;
+    }
+    if !executable {
+        flags |= PageTableFlags::NO_EXECUTE;
+    }
+
+    let first_page = Page::<Size4KiB>::containing_address(VirtAddr::new(vaddr));
+    let last_page = Page::<Size4KiB>::containing_address(VirtAddr::new(vaddr + memsz - 1));
+    for page in Page::range_inclusive(first_page, last_page) {
+        if let TranslateResult::Mapped { flags: current, .. } =
+            mapper.translate(page.start_address())
+        {
+            let mut page_flags = current | (flags & PageTableFlags::WRITABLE);
+            if executable {
+                page_flags.remove(PageTableFlags::NO_EXECUTE);
+            }
+            if page_flags != current {
+                unsafe {
+                    mapper
+                        .update_flags(page, page_flags)
+                        .map_err(|_| LoadError::BadSegment)?
+                        .ignore();
+                }
+            }
+            continue;
+        }
+        usermode::map_user_region(page.start_address(), 4096, flags, mapper, frame_allocator)?;
+    }
+    Ok(())
+}
+
+// System V ABIの初期スタックを作る
+// 下位アドレスから argc, argv[], NULL, envp[], NULL, auxv[], 文字列 の順に並べる
+fn push_initial_stack(
+    mapper: &OffsetPageTable,
+    stack_top: VirtAddr,
+    args: &[&str],
+    env: &[&str],
+    auxv: &[(u64, u64)],
+) -> Result<VirtAddr, LoadError> {
+    // 文字列をスタックの最上部に置き、それぞれのアドレスを記録する
+    let mut strings = Vec::new();
+    let mut string_offsets = Vec::new();
+    for s in args.iter().chain(env.iter()) {
+        string_offsets.push(strings.len() as u64);
+        strings.extend_from_slice(s.as_bytes());
+        strings.push(0);
+    }
+    let strings_start = (stack_top - strings.len() as u64).align_down(16u64);
+
+    let mut words: Vec<u64> = Vec::new();
+    words.push(args.len() as u64);
+    let addr_of = |i: usize| strings_start.as_u64() + string_offsets[i];
+    words.extend((0..args.len()).map(addr_of));
+    words.push(0);
+    words.extend((args.len()..args.len() + env.len()).map(addr_of));
+    words.push(0);
+    for &(key, value) in auxv {
+        words.push(key);
+        words.push(value);
+    }
+
+    let table_size = (words.len() * 8) as u64;
+    let stack_pointer = (strings_start - table_size).align_down(16u64);
+    if stack_pointer.as_u64() < USER_STACK_TOP - USER_STACK_SIZE + 4096 {
+        return Err(LoadError::ArgumentsTooLarge);
+    }
+
+    let table: Vec<u8> = words.iter().flat_map(|w| w.to_le_bytes()).collect();
+    usermode::copy_to_user(mapper, strings_start, &strings);
+    usermode::copy_to_user(mapper, stack_pointer, &table);
+    Ok(stack_pointer)
+}
+
+/// # Safety
+// アドレス空間を切り替えてユーザープログラムをring 3で実行し、終了コードを返す
+// 呼び出し元は割り込みハンドラなど他のコードがユーザー空間のマッピングに依存していないことを保証しなければならない
+pub unsafe fn run(program: &LoadedProgram) -> u64 {
+    thread::set_address_space(Some(program.address_space.level_4_frame()));
+    thread::prepare_user_mode();
+    let code = usermode::enter_user_mode(program.entry, program.stack_pointer);
+    thread::set_address_space(None);
+    code
+}
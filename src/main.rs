@@ -8,8 +8,9 @@ extern crate alloc;
 
 use bootloader::{entry_point, BootInfo};
 use core::panic::PanicInfo;
-use toy_rust_os::task::{executor::Executor, keyboard, Task};
-use toy_rust_os::{allocator, println};
+use toy_rust_os::task::executor::{Builder, Executor};
+use toy_rust_os::task::{keyboard, Priority};
+use toy_rust_os::{allocator, println, smp, thread, time};
 
 // bootloaderクレートによりkernel_mainの引数の型を確認しエントリポイントとして定義
 entry_point!(kernel_main);
@@ -27,16 +28,27 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
 
     allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
 
-    let mut executor = Executor::new();
-    executor.spawn(Task::new(example_task()));
-    executor.spawn(Task::new(keyboard::print_keyboard()));
-    executor.run();
+    let clock_source = time::init_hpet(&mut mapper, &mut frame_allocator);
+    println!("clock source: {:?}", clock_source);
+    println!("wall clock: {}", time::wall_clock());
+    memory::install_frame_allocator(frame_allocator);
+    thread::init();
+    match smp::init(&mut mapper, &mut memory::GlobalFrameAllocator) {
+        Ok(cpus) => println!("SMP: {} CPUs online", cpus),
+        Err(err) => println!("SMP unavailable ({:?}); running on the BSP only", err),
+    }
 
     #[cfg(test)]
     test_main();
 
-    println!("It did not crash!");
-    toy_rust_os::hlt_loop();
+    let mut executor = Executor::new();
+    Builder::new().name("example").spawn(example_task());
+    // キー入力にはすぐに応答させたいので、他のタスクより優先する
+    Builder::new()
+        .name("keyboard")
+        .priority(Priority::High)
+        .spawn(keyboard::print_keyboard());
+    executor.run();
 }
 
 async fn async_number() -> u32 {
@@ -52,6 +64,8 @@ async fn example_task() {
 #[cfg(not(test))]
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
+    // タスクの中のパニックはそのタスクだけを失敗させる（unwind featureが有効な場合）
+    toy_rust_os::task::unwind::unwind_task_panic(info);
     println!("{}", info);
     toy_rust_os::hlt_loop();
 }
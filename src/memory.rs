@@ -1,13 +1,32 @@
 use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
 use x86_64::structures::paging::{
-    FrameAllocator, Mapper, OffsetPageTable, Page, PhysFrame, Size4KiB,
+    mapper::{FlagUpdateError, MapToError, UnmapError},
+    FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageTableFlags, PhysFrame,
+    Size4KiB,
 };
 use x86_64::{structures::paging::PageTable, PhysAddr, VirtAddr};
 
+// 物理メモリ全体がマップされている仮想アドレスのオフセット
+static PHYSICAL_MEMORY_OFFSET: AtomicU64 = AtomicU64::new(0);
+// 起動時のレベル4テーブル（カーネルのアドレス空間）の物理アドレス
+static KERNEL_LEVEL_4_TABLE: AtomicU64 = AtomicU64::new(0);
+
+// デバイスのレジスタ（MMIO）をマップする仮想アドレスの範囲
+pub const MMIO_START: u64 = 0x_5555_5555_0000;
+pub const MMIO_SIZE: u64 = 1024 * 1024; // 1MiB
+static NEXT_MMIO: AtomicU64 = AtomicU64::new(MMIO_START);
+
+// 解放されたフレームのリストの終端
+const FREE_LIST_END: u64 = u64::MAX;
+
 // ブートローダのメモリマップから使用可能なフレームを返すFrameAllocator
+// 解放されたフレームは先頭の8バイトに次のフレームの物理アドレスを書いてつなぎ、優先して再利用する
 pub struct BootInfoFrameAllocator {
     memory_map: &'static MemoryMap,
     next: usize,
+    free_list: u64,
 }
 
 impl BootInfoFrameAllocator {
@@ -18,6 +37,7 @@ impl BootInfoFrameAllocator {
         BootInfoFrameAllocator {
             memory_map,
             next: 0,
+            free_list: FREE_LIST_END,
         }
     }
 
@@ -39,21 +59,164 @@ impl BootInfoFrameAllocator {
 
 unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
     fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
+        if self.free_list != FREE_LIST_END {
+            let frame = PhysFrame::containing_address(PhysAddr::new(self.free_list));
+            self.free_list = unsafe { *phys_to_virt(frame.start_address()).as_ptr::<u64>() };
+            return Some(frame);
+        }
         let frame = self.usable_frames().nth(self.next);
         self.next += 1;
         frame
     }
 }
 
+impl FrameDeallocator<Size4KiB> for BootInfoFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size4KiB>) {
+        *phys_to_virt(frame.start_address()).as_mut_ptr::<u64>() = self.free_list;
+        self.free_list = frame.start_address().as_u64();
+    }
+}
+
+// システムコールなど起動後の処理からフレームを割り当てるための共有のFrameAllocator
+static FRAME_ALLOCATOR: Mutex<Option<BootInfoFrameAllocator>> = Mutex::new(None);
+
+// 起動時の初期化が終わった後にFrameAllocatorを共有する
+pub fn install_frame_allocator(frame_allocator: BootInfoFrameAllocator) {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        *FRAME_ALLOCATOR.lock() = Some(frame_allocator);
+    });
+}
+
+// install_frame_allocatorで共有されたFrameAllocatorから割り当てる
+pub struct GlobalFrameAllocator;
+
+unsafe impl FrameAllocator<Size4KiB> for GlobalFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
+        x86_64::instructions::interrupts::without_interrupts(|| {
+            FRAME_ALLOCATOR.lock().as_mut()?.allocate_frame()
+        })
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for GlobalFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size4KiB>) {
+        x86_64::instructions::interrupts::without_interrupts(|| {
+            if let Some(allocator) = FRAME_ALLOCATOR.lock().as_mut() {
+                allocator.deallocate_frame(frame);
+            }
+        })
+    }
+}
+
 /// # Safety
 // 全物理メモリが渡された physical_memory_offset （だけずらしたうえ）で仮想メモリへとマップされていることを呼び出し元が保証しなければならない。
 // また &mut 参照が複数の名称を持つこと（mutable aliasingといい、動作が未定義）につながるためこの関数は一度しか呼び出してはならない
 // ページテーブルへの参照が可変（&mut）なので複数呼ばれると動作が不安定
 pub unsafe fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static> {
+    PHYSICAL_MEMORY_OFFSET.store(physical_memory_offset.as_u64(), Ordering::Relaxed);
+    let (level_4_frame, _) = x86_64::registers::control::Cr3::read();
+    KERNEL_LEVEL_4_TABLE.store(level_4_frame.start_address().as_u64(), Ordering::Relaxed);
     let level_4_table = active_level_4_table(physical_memory_offset);
     OffsetPageTable::new(level_4_table, physical_memory_offset)
 }
 
+// プロセスごとのページテーブル
+// カーネルのマッピングは現在のページテーブルと共有し、ユーザー空間は空の状態で作る
+#[derive(Debug)]
+pub struct AddressSpace {
+    level_4_frame: PhysFrame,
+}
+
+impl AddressSpace {
+    pub fn new(frame_allocator: &mut impl FrameAllocator<Size4KiB>) -> Option<Self> {
+        let level_4_frame = frame_allocator.allocate_frame()?;
+        let offset = physical_memory_offset();
+        unsafe {
+            let current = active_level_4_table(offset);
+            let table =
+                &mut *(offset + level_4_frame.start_address().as_u64()).as_mut_ptr::<PageTable>();
+            table.zero();
+            // ユーザーがアクセスできるエントリ以外（カーネル、ヒープ、物理メモリのマッピング）を共有する
+            for (entry, current_entry) in table.iter_mut().zip(current.iter()) {
+                if !current_entry
+                    .flags()
+                    .contains(PageTableFlags::USER_ACCESSIBLE)
+                {
+                    *entry = current_entry.clone();
+                }
+            }
+        }
+        Some(AddressSpace { level_4_frame })
+    }
+
+    pub fn level_4_frame(&self) -> PhysFrame {
+        self.level_4_frame
+    }
+
+    // このアドレス空間のページテーブルを操作するOffsetPageTableを返す
+    pub fn mapper(&mut self) -> OffsetPageTable<'_> {
+        let offset = physical_memory_offset();
+        unsafe {
+            let table = &mut *(offset + self.level_4_frame.start_address().as_u64())
+                .as_mut_ptr::<PageTable>();
+            OffsetPageTable::new(table, offset)
+        }
+    }
+
+    /// # Safety
+    // CR3を切り替えてこのアドレス空間を有効にし、それまでのレベル4テーブルを返す
+    // 呼び出し元は実行中のコードとスタックがこのアドレス空間でもマップされていることを保証しなければならない
+    pub unsafe fn activate(&self) -> PhysFrame {
+        use x86_64::registers::control::Cr3;
+
+        let (previous, flags) = Cr3::read();
+        Cr3::write(self.level_4_frame, flags);
+        previous
+    }
+
+    /// # Safety
+    // このアドレス空間だけが持つページテーブルと、そこからマップされたフレームを全て解放する
+    // 呼び出し元はどのCPUもこのアドレス空間を使っておらず、マップしたフレームを他で使っていないことを保証しなければならない
+    pub unsafe fn free(self, frame_deallocator: &mut impl FrameDeallocator<Size4KiB>) {
+        let table = &*phys_to_virt(self.level_4_frame.start_address()).as_ptr::<PageTable>();
+        // カーネルと共有しているエントリはユーザーがアクセスできないので、それ以外がこのアドレス空間のもの
+        for entry in table.iter() {
+            if entry.flags().contains(PageTableFlags::USER_ACCESSIBLE) {
+                if let Ok(frame) = entry.frame() {
+                    free_page_table(frame, 3, frame_deallocator);
+                }
+            }
+        }
+        frame_deallocator.deallocate_frame(self.level_4_frame);
+    }
+}
+
+// levelのページテーブルとその下のテーブル、マップされたフレームを解放する
+unsafe fn free_page_table(
+    table_frame: PhysFrame,
+    level: u8,
+    frame_deallocator: &mut impl FrameDeallocator<Size4KiB>,
+) {
+    let table = &*phys_to_virt(table_frame.start_address()).as_ptr::<PageTable>();
+    // ユーザー空間は4KiBのページだけでマップするので、巨大ページ（frameがエラーになる）は無い
+    for frame in table.iter().filter_map(|entry| entry.frame().ok()) {
+        if level == 1 {
+            frame_deallocator.deallocate_frame(frame);
+        } else {
+            free_page_table(frame, level - 1, frame_deallocator);
+        }
+    }
+    frame_deallocator.deallocate_frame(table_frame);
+}
+
+/// # Safety
+// 現在のCR3のページテーブルを操作するOffsetPageTableを返す
+// 呼び出し元は返り値を使っている間に同じページテーブルへの他の可変参照が使われないことを保証しなければならない
+pub unsafe fn active_mapper() -> OffsetPageTable<'static> {
+    let offset = physical_memory_offset();
+    OffsetPageTable::new(active_level_4_table(offset), offset)
+}
+
 /// # Safety
 // 有効なレベル4テーブルへの参照を返す
 // 全物理メモリが渡された physical_memory_offset （だけずらしたうえ）で仮想メモリへとマップされていることを呼び出し元が保証しなければならない。
@@ -78,6 +241,122 @@ unsafe fn active_level_4_table(physical_memory_offset: VirtAddr) -> &'static mut
     &mut *page_table_ptr // 仮想アドレスのポインタ参照を可変で返す
 }
 
+pub fn physical_memory_offset() -> VirtAddr {
+    VirtAddr::new(PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed))
+}
+
+pub fn kernel_level_4_frame() -> PhysFrame {
+    PhysFrame::containing_address(PhysAddr::new(KERNEL_LEVEL_4_TABLE.load(Ordering::Relaxed)))
+}
+
+// [start, end)と重なる、カーネルが全てのアドレス空間で共有しているレベル4テーブルのエントリのうち
+// 最初のものが受け持つ範囲の終わりを返す。重ならない場合はNone
+// AddressSpace::newはユーザーがアクセスできないエントリを共有するので、そこにユーザーのページをマップすると
+// 共有しているページテーブルを通じて全てのプロセスから見えてしまう
+pub fn shared_kernel_slot(start: VirtAddr, end: VirtAddr) -> Option<VirtAddr> {
+    const SLOT_SIZE: u64 = 1 << 39;
+    if end <= start {
+        return None;
+    }
+    let table =
+        unsafe { &*phys_to_virt(kernel_level_4_frame().start_address()).as_ptr::<PageTable>() };
+    let first = u16::from(start.p4_index());
+    let last = u16::from((end - 1u64).p4_index());
+    (first..=last)
+        .find(|&index| {
+            let entry = &table[usize::from(index)];
+            !entry.is_unused() && !entry.flags().contains(PageTableFlags::USER_ACCESSIBLE)
+        })
+        .map(|index| VirtAddr::new((u64::from(index) + 1) * SLOT_SIZE))
+}
+
+// 物理アドレスを物理メモリ全体のマッピングを通じた仮想アドレスに変換する
+pub fn phys_to_virt(addr: PhysAddr) -> VirtAddr {
+    physical_memory_offset() + addr.as_u64()
+}
+
+#[derive(Debug)]
+pub enum MmioError {
+    // MMIO領域に空きが無い
+    Exhausted,
+    Map(MapToError<Size4KiB>),
+}
+
+impl From<MapToError<Size4KiB>> for MmioError {
+    fn from(err: MapToError<Size4KiB>) -> Self {
+        MmioError::Map(err)
+    }
+}
+
+// デバイスのレジスタをキャッシュ無効でMMIO領域にマップし、対応する仮想アドレスを返す
+pub fn map_mmio(
+    phys_addr: PhysAddr,
+    size: u64,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<VirtAddr, MmioError> {
+    let first_frame = PhysFrame::<Size4KiB>::containing_address(phys_addr);
+    let last_frame = PhysFrame::<Size4KiB>::containing_address(phys_addr + (size.max(1) - 1));
+    let frame_count = last_frame - first_frame + 1;
+
+    // 空きが足りない場合はNEXT_MMIOを進めない
+    let start = NEXT_MMIO
+        .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |next| {
+            let end = next.checked_add(frame_count * 4096)?;
+            if end <= MMIO_START + MMIO_SIZE {
+                Some(end)
+            } else {
+                None
+            }
+        })
+        .map_err(|_| MmioError::Exhausted)?;
+
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_CACHE;
+    let start_page = Page::containing_address(VirtAddr::new(start));
+    for (i, frame) in PhysFrame::range_inclusive(first_frame, last_frame).enumerate() {
+        let page = start_page + i as u64;
+        unsafe { mapper.map_to(page, frame, flags, frame_allocator)?.flush() };
+    }
+
+    Ok(start_page.start_address() + (phys_addr - first_frame.start_address()))
+}
+
+// startからsizeバイトの範囲のページのマッピングを外し、全てのCPUのTLBから無効にする
+// フレームは解放しない
+pub fn unmap_region(
+    start: VirtAddr,
+    size: u64,
+    mapper: &mut impl Mapper<Size4KiB>,
+) -> Result<(), UnmapError> {
+    let result = pages(start, size).try_for_each(|page| {
+        // 他のCPUのTLBもまとめて無効にするので、ここでのflushは省く
+        mapper.unmap(page).map(|(_, flush)| flush.ignore())
+    });
+    // 途中で失敗しても、それまでに外したページは無効にする必要がある
+    crate::tlb::shootdown(start, size);
+    result
+}
+
+// startからsizeバイトの範囲のページのフラグを書き換え、全てのCPUのTLBから古いフラグを無効にする
+pub fn protect_region(
+    start: VirtAddr,
+    size: u64,
+    flags: PageTableFlags,
+    mapper: &mut impl Mapper<Size4KiB>,
+) -> Result<(), FlagUpdateError> {
+    let result = pages(start, size).try_for_each(|page| {
+        unsafe { mapper.update_flags(page, flags) }.map(|flush| flush.ignore())
+    });
+    crate::tlb::shootdown(start, size);
+    result
+}
+
+fn pages(start: VirtAddr, size: u64) -> impl Iterator<Item = Page> {
+    let first = Page::containing_address(start);
+    let last = Page::containing_address(start + (size.max(1) - 1));
+    Page::range_inclusive(first, last)
+}
+
 pub fn create_example_mapping(
     page: Page,
     mapper: &mut OffsetPageTable,
@@ -0,0 +1,355 @@
+use crate::sync::lockdep::HeldLocks;
+use crate::sync::SpinLock;
+use crate::task::run_queue::RunQueue;
+use crate::thread::ThreadId;
+use alloc::collections::VecDeque;
+use core::arch::asm;
+use core::cell::UnsafeCell;
+use core::ptr::addr_of;
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use x86_64::registers::model_specific::{GsBase, KernelGsBase};
+use x86_64::structures::gdt::GlobalDescriptorTable;
+use x86_64::structures::idt::InterruptStackFrame;
+use x86_64::structures::tss::TaskStateSegment;
+use x86_64::VirtAddr;
+
+// 対応するCPUの最大数
+pub const MAX_CPUS: usize = 8;
+
+// 割り込みやシステムコールで使うカーネルスタックのサイズ
+pub const STACK_SIZE: usize = 4096 * 5;
+
+// 値が設定されていないことを表すID
+const NONE: u64 = u64::MAX;
+
+// CPUごとのデータ。IA32_GS_BASEにこの構造体のアドレスを設定し、gs経由で参照する
+// ユーザーモードの間はIA32_KERNEL_GS_BASEに退避し、SWAPGSで入れ替える
+// 先頭の4つのフィールドはアセンブリからオフセットで参照するため順番を変えないこと
+#[repr(C)]
+pub struct PerCpu {
+    // gs:[0] 自分自身のアドレス。current()はここを読んで参照を作る
+    self_ptr: AtomicU64,
+    // gs:[8] SYSCALLで切り替えるカーネルスタック
+    syscall_kernel_rsp: AtomicU64,
+    // gs:[16] SYSCALLで入ったときのユーザーのスタックポインタ
+    #[allow(dead_code)]
+    syscall_user_rsp: AtomicU64,
+    // gs:[24] ユーザーモードに入る直前のカーネルのスタックポインタ
+    user_return_rsp: AtomicU64,
+    id: AtomicUsize,
+    apic_id: AtomicU32,
+    current_thread: AtomicU64,
+    idle_thread: AtomicU64,
+    // executorがpoll中のタスクのID
+    current_task: AtomicU64,
+    // poll中のタスクがパニックし、巻き戻してタスクを捨て終わるまで立てる
+    task_panicking: AtomicBool,
+    // 割り込みハンドラの入れ子の深さ
+    interrupt_depth: AtomicU32,
+    // プリエンプションを禁止している入れ子の深さ。0でなければタイマー割り込みでスレッドを切り替えない
+    preempt_disabled: AtomicU32,
+    // スレッドがユーザーモード用のスタックを持たない場合に使うスタックの終端
+    double_fault_stack: AtomicU64,
+    privilege_stack: AtomicU64,
+    syscall_stack: AtomicU64,
+    tss: UnsafeCell<TaskStateSegment>,
+    gdt: UnsafeCell<GlobalDescriptorTable>,
+    // このCPUで実行を待っているスレッド。スケジューラのロックを取った状態で操作する
+    pub(crate) run_queue: SpinLock<VecDeque<ThreadId>>,
+    // このCPUのexecutorが実行するタスク。他のCPUも盗むため、またwakerが割り込みハンドラから追加するためSpinLockで守る
+    pub(crate) task_queue: SpinLock<RunQueue>,
+    // executorが仕事がなくhltしているか。立っている場合はIPIで起こす
+    halted: AtomicBool,
+    // このCPUが処理を終えたTLBシュートダウンの世代。tlb::init_cpuまではNONE
+    tlb_generation: AtomicU64,
+    // このCPUが持っているスピンロック。デバッグビルドでロックの順番を確認するのに使う
+    pub(crate) held_locks: UnsafeCell<HeldLocks>,
+}
+
+// TSSとGDTとheld_locksは自分のCPUからしか書き換えない
+unsafe impl Sync for PerCpu {}
+
+impl PerCpu {
+    const fn new() -> Self {
+        PerCpu {
+            self_ptr: AtomicU64::new(0),
+            syscall_kernel_rsp: AtomicU64::new(0),
+            syscall_user_rsp: AtomicU64::new(0),
+            user_return_rsp: AtomicU64::new(0),
+            id: AtomicUsize::new(0),
+            apic_id: AtomicU32::new(0),
+            current_thread: AtomicU64::new(NONE),
+            idle_thread: AtomicU64::new(NONE),
+            current_task: AtomicU64::new(NONE),
+            task_panicking: AtomicBool::new(false),
+            interrupt_depth: AtomicU32::new(0),
+            preempt_disabled: AtomicU32::new(0),
+            double_fault_stack: AtomicU64::new(0),
+            privilege_stack: AtomicU64::new(0),
+            syscall_stack: AtomicU64::new(0),
+            tss: UnsafeCell::new(TaskStateSegment::new()),
+            gdt: UnsafeCell::new(GlobalDescriptorTable::new()),
+            run_queue: SpinLock::new(VecDeque::new()),
+            task_queue: SpinLock::new(RunQueue::new()),
+            halted: AtomicBool::new(false),
+            tlb_generation: AtomicU64::new(NONE),
+            held_locks: UnsafeCell::new(HeldLocks::new()),
+        }
+    }
+
+    pub fn id(&self) -> usize {
+        self.id.load(Ordering::Relaxed)
+    }
+
+    // Local APICのID。IPIの宛先に使う
+    pub fn apic_id(&self) -> u32 {
+        self.apic_id.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn set_apic_id(&self, apic_id: u32) {
+        self.apic_id.store(apic_id, Ordering::Relaxed);
+    }
+
+    pub fn current_thread(&self) -> Option<ThreadId> {
+        match self.current_thread.load(Ordering::Relaxed) {
+            NONE => None,
+            id => Some(ThreadId::from_u64(id)),
+        }
+    }
+
+    pub(crate) fn set_current_thread(&self, id: ThreadId) {
+        self.current_thread.store(id.as_u64(), Ordering::Relaxed);
+    }
+
+    pub(crate) fn idle_thread(&self) -> Option<ThreadId> {
+        match self.idle_thread.load(Ordering::Relaxed) {
+            NONE => None,
+            id => Some(ThreadId::from_u64(id)),
+        }
+    }
+
+    pub(crate) fn set_idle_thread(&self, id: ThreadId) {
+        self.idle_thread.store(id.as_u64(), Ordering::Relaxed);
+    }
+
+    pub fn current_task(&self) -> Option<u64> {
+        match self.current_task.load(Ordering::Relaxed) {
+            NONE => None,
+            id => Some(id),
+        }
+    }
+
+    pub(crate) fn set_current_task(&self, id: Option<u64>) {
+        self.current_task
+            .store(id.unwrap_or(NONE), Ordering::Relaxed);
+    }
+
+    pub(crate) fn task_panicking(&self) -> bool {
+        self.task_panicking.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn set_task_panicking(&self, panicking: bool) {
+        self.task_panicking.store(panicking, Ordering::Relaxed);
+    }
+
+    // 割り込みハンドラの中で実行しているか
+    pub fn in_interrupt(&self) -> bool {
+        self.interrupt_depth.load(Ordering::Relaxed) > 0
+    }
+
+    // スレッドを切り替えるときに、スレッドごとの深さを保存・復元する
+    pub(crate) fn interrupt_depth(&self) -> u32 {
+        self.interrupt_depth.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn set_interrupt_depth(&self, depth: u32) {
+        self.interrupt_depth.store(depth, Ordering::Relaxed);
+    }
+
+    pub(crate) fn preemptible(&self) -> bool {
+        self.preempt_disabled.load(Ordering::Relaxed) == 0
+    }
+
+    pub(crate) fn disable_preemption(&self) {
+        self.preempt_disabled.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn enable_preemption(&self) {
+        self.preempt_disabled.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn is_halted(&self) -> bool {
+        self.halted.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn set_halted(&self, halted: bool) {
+        self.halted.store(halted, Ordering::SeqCst);
+    }
+
+    pub(crate) fn tlb_generation(&self) -> u64 {
+        self.tlb_generation.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn set_tlb_generation(&self, generation: u64) {
+        self.tlb_generation.store(generation, Ordering::SeqCst);
+    }
+
+    pub(crate) fn user_return_rsp(&self) -> u64 {
+        self.user_return_rsp.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn set_user_return_rsp(&self, rsp: u64) {
+        self.user_return_rsp.store(rsp, Ordering::Relaxed);
+    }
+
+    pub(crate) fn set_syscall_kernel_rsp(&self, top: VirtAddr) {
+        self.syscall_kernel_rsp
+            .store(top.as_u64(), Ordering::Relaxed);
+    }
+
+    pub fn double_fault_stack(&self) -> VirtAddr {
+        VirtAddr::new(self.double_fault_stack.load(Ordering::Relaxed))
+    }
+
+    pub fn privilege_stack(&self) -> VirtAddr {
+        VirtAddr::new(self.privilege_stack.load(Ordering::Relaxed))
+    }
+
+    pub fn syscall_stack(&self) -> VirtAddr {
+        VirtAddr::new(self.syscall_stack.load(Ordering::Relaxed))
+    }
+
+    // gdt::initから使う。自分のCPUのTSSとGDTだけを書き換える
+    pub(crate) fn tss(&'static self) -> *mut TaskStateSegment {
+        self.tss.get()
+    }
+
+    pub(crate) fn gdt(&'static self) -> *mut GlobalDescriptorTable {
+        self.gdt.get()
+    }
+}
+
+static CPUS: [PerCpu; MAX_CPUS] = [const { PerCpu::new() }; MAX_CPUS];
+
+// 起動したCPUの数
+static CPU_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+// 中身はアドレスを通してCPUが使うだけで、Rustからは読まない
+#[repr(align(16))]
+struct Stack(#[allow(dead_code)] [u8; STACK_SIZE]);
+
+static mut BSP_DOUBLE_FAULT_STACK: Stack = Stack([0; STACK_SIZE]);
+static mut BSP_PRIVILEGE_STACK: Stack = Stack([0; STACK_SIZE]);
+static mut BSP_SYSCALL_STACK: Stack = Stack([0; STACK_SIZE]);
+
+fn stack_end(stack: *const Stack) -> VirtAddr {
+    VirtAddr::from_ptr(stack) + STACK_SIZE
+}
+
+// 起動したCPU(BSP)のデータを用意してGSのベースに設定する
+// gdt::initより前に呼び出す
+pub fn init_bsp() {
+    // CPUが書き込むスタックなので、参照を作らずにアドレスだけを取る
+    let stacks = [
+        stack_end(addr_of!(BSP_DOUBLE_FAULT_STACK)),
+        stack_end(addr_of!(BSP_PRIVILEGE_STACK)),
+        stack_end(addr_of!(BSP_SYSCALL_STACK)),
+    ];
+    unsafe { init_cpu(0, stacks) };
+}
+
+/// # Safety
+// idのデータにスタックを設定し、実行中のCPUのGSのベースをそのデータに向ける
+// 1つのCPUから1度だけ、idが他のCPUと重ならないように呼び出さなければならない
+pub(crate) unsafe fn init_cpu(id: usize, stacks: [VirtAddr; 3]) {
+    let cpu = &CPUS[id];
+    cpu.self_ptr
+        .store(cpu as *const PerCpu as u64, Ordering::Relaxed);
+    cpu.id.store(id, Ordering::Relaxed);
+    cpu.double_fault_stack
+        .store(stacks[0].as_u64(), Ordering::Relaxed);
+    cpu.privilege_stack
+        .store(stacks[1].as_u64(), Ordering::Relaxed);
+    cpu.syscall_stack
+        .store(stacks[2].as_u64(), Ordering::Relaxed);
+    cpu.set_syscall_kernel_rsp(stacks[2]);
+
+    GsBase::write(VirtAddr::from_ptr(cpu));
+    // ユーザーモードのGSのベースは0
+    KernelGsBase::write(VirtAddr::new(0));
+    CPU_COUNT.fetch_add(1, Ordering::SeqCst);
+}
+
+// init_bspやinit_cpuの前に呼び出される可能性がある場所ではcurrentの代わりに使う
+pub fn try_current() -> Option<&'static PerCpu> {
+    if GsBase::read().as_u64() == 0 {
+        None
+    } else {
+        Some(current())
+    }
+}
+
+// 実行中のCPUのデータ
+// スレッドは他のCPUに移らない（起こすときも持ち主のCPUに戻す）ため、返された参照はスレッドが動いている間有効
+pub fn current() -> &'static PerCpu {
+    let ptr: u64;
+    unsafe {
+        asm!("mov {}, gs:[0]", out(reg) ptr, options(nostack, preserves_flags, readonly));
+        &*(ptr as *const PerCpu)
+    }
+}
+
+// idのCPUのデータ。初期化前のCPUのデータも返す
+pub(crate) fn get(id: usize) -> &'static PerCpu {
+    &CPUS[id]
+}
+
+pub fn cpu_id() -> usize {
+    current().id()
+}
+
+pub fn cpu_count() -> usize {
+    CPU_COUNT.load(Ordering::SeqCst)
+}
+
+// 初期化済みのCPUのデータ
+pub fn cpus() -> impl Iterator<Item = &'static PerCpu> {
+    CPUS.iter()
+        .filter(|cpu| cpu.self_ptr.load(Ordering::Relaxed) != 0)
+}
+
+// ring 3から割り込みで入った場合にSWAPGSでカーネルのGSに切り替え、ハンドラから戻るときに元に戻す
+// 割り込みハンドラの先頭で作り、ハンドラの終わりまで保持する。割り込みハンドラの入れ子の深さも数える
+pub(crate) struct KernelGsGuard {
+    swapped: bool,
+}
+
+impl KernelGsGuard {
+    pub(crate) fn enter(stack_frame: &InterruptStackFrame) -> Self {
+        let swapped = stack_frame.code_segment & 3 != 0;
+        if swapped {
+            unsafe { asm!("swapgs", options(nostack, preserves_flags)) };
+        }
+        if let Some(cpu) = try_current() {
+            cpu.interrupt_depth.fetch_add(1, Ordering::Relaxed);
+        }
+        KernelGsGuard { swapped }
+    }
+}
+
+impl Drop for KernelGsGuard {
+    fn drop(&mut self) {
+        if let Some(cpu) = try_current() {
+            cpu.interrupt_depth.fetch_sub(1, Ordering::Relaxed);
+        }
+        if self.swapped {
+            unsafe { asm!("swapgs", options(nostack, preserves_flags)) };
+        }
+    }
+}
+
+#[test_case]
+fn test_current_cpu_is_bsp() {
+    assert_eq!(cpu_id(), 0);
+    assert!(core::ptr::eq(current(), &CPUS[0]));
+}
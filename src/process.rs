@@ -0,0 +1,225 @@
+use crate::loader::{self, LoadError};
+use crate::memory::{AddressSpace, GlobalFrameAllocator};
+use crate::sync::SpinLock;
+use crate::thread::{self, JoinHandle, ThreadId};
+use crate::{syscall, usermode};
+use alloc::{collections::BTreeMap, string::String, vec, vec::Vec};
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::instructions::interrupts;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Pid(u64);
+
+impl Pid {
+    fn new() -> Self {
+        // 0はカーネル自身を表すので1から割り当てる
+        static NEXT_PID: AtomicU64 = AtomicU64::new(1);
+        Pid(NEXT_PID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub fn as_u64(&self) -> u64 {
+        self.0
+    }
+}
+
+impl fmt::Display for Pid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProcessState {
+    Running,
+    Blocked,
+    // 終了したがwaitで回収されていない
+    Zombie,
+}
+
+// プロセスが開いているハンドル。ファイルディスクリプタの番号で引く
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Handle {
+    Console,
+}
+
+struct Process {
+    pid: Pid,
+    parent: Option<Pid>,
+    name: String,
+    handles: Vec<Option<Handle>>,
+    // waitで回収するときに解放する
+    address_space: AddressSpace,
+    // アドレスを指定しないmmapで次に割り当てるアドレス
+    next_mmap: u64,
+    state: ProcessState,
+    exit_code: Option<u64>,
+    thread: ThreadId,
+    main_thread: Option<JoinHandle<u64>>,
+}
+
+// デバッグ用にプロセステーブルから取り出した情報
+#[derive(Debug, Clone)]
+pub struct ProcessInfo {
+    pub pid: Pid,
+    pub parent: Option<Pid>,
+    pub name: String,
+    pub state: ProcessState,
+    pub exit_code: Option<u64>,
+}
+
+static PROCESS_TABLE: SpinLock<BTreeMap<Pid, Process>> = SpinLock::new(BTreeMap::new());
+
+// ELFファイルを読み込み、新しいスレッドでユーザープログラムとして実行する
+pub fn spawn(name: &str, elf: &[u8], args: &[&str]) -> Result<Pid, LoadError> {
+    let program = loader::load(elf, args, &[], &mut GlobalFrameAllocator)?;
+    let pid = Pid::new();
+    let parent = current_pid();
+    let level_4_frame = program.address_space.level_4_frame();
+    let (entry, stack_pointer) = (program.entry, program.stack_pointer);
+
+    // 新しいスレッドがテーブルに登録される前に実行されないよう、割り込みを止めておく
+    interrupts::without_interrupts(|| {
+        let main_thread = thread::spawn(move || {
+            thread::set_address_space(Some(level_4_frame));
+            thread::prepare_user_mode();
+            let code = unsafe { usermode::enter_user_mode(entry, stack_pointer) };
+            thread::set_address_space(None);
+            exit(pid, code);
+            code
+        });
+        let process = Process {
+            pid,
+            parent,
+            name: String::from(name),
+            // 0: 標準入力, 1: 標準出力, 2: 標準エラー出力
+            handles: vec![None, Some(Handle::Console), Some(Handle::Console)],
+            address_space: program.address_space,
+            next_mmap: syscall::USER_MMAP_START,
+            state: ProcessState::Running,
+            exit_code: None,
+            thread: main_thread.id(),
+            main_thread: Some(main_thread),
+        };
+        PROCESS_TABLE.lock().insert(pid, process);
+    });
+    Ok(pid)
+}
+
+// プロセスを終了状態にする。ハンドルを閉じ、waitで回収されるまで終了コードを残す
+pub fn exit(pid: Pid, code: u64) {
+    if let Some(process) = PROCESS_TABLE.lock().get_mut(&pid) {
+        process.state = ProcessState::Zombie;
+        process.exit_code = Some(code);
+        process.handles.clear();
+    }
+}
+
+// プロセスの終了を待って回収し、終了コードを返す
+// 存在しないプロセスや、他のスレッドが既に待っているプロセスの場合はNone
+pub fn wait(pid: Pid) -> Option<u64> {
+    let main_thread = PROCESS_TABLE
+        .lock()
+        .get_mut(&pid)
+        .and_then(|process| process.main_thread.take())?;
+    main_thread.join();
+
+    let process = PROCESS_TABLE.lock().remove(&pid)?;
+    // メインスレッドは終了前にカーネルのアドレス空間に戻っているので、もうどのCPUも使っていない
+    // フレームを返すのはロックの外で行う
+    unsafe { process.address_space.free(&mut GlobalFrameAllocator) };
+    process.exit_code
+}
+
+pub(crate) fn set_state(pid: Pid, state: ProcessState) {
+    if let Some(process) = PROCESS_TABLE.lock().get_mut(&pid) {
+        if process.state != ProcessState::Zombie {
+            process.state = state;
+        }
+    }
+}
+
+// 実行中のスレッドが属するプロセス。カーネルのスレッドの場合はNone
+pub fn current_pid() -> Option<Pid> {
+    let current = thread::current()?;
+    PROCESS_TABLE
+        .lock()
+        .values()
+        .find(|process| process.thread == current)
+        .map(|process| process.pid)
+}
+
+// プロセスのmmapの割り当て位置をfで進める。存在しないプロセスの場合はNone
+pub(crate) fn with_next_mmap<R>(pid: Pid, f: impl FnOnce(&mut u64) -> R) -> Option<R> {
+    let mut table = PROCESS_TABLE.lock();
+    Some(f(&mut table.get_mut(&pid)?.next_mmap))
+}
+
+// 実行中のプロセスのハンドルを返す
+// プロセスに属さないカーネルのスレッドでは標準出力と標準エラー出力だけをコンソールとして扱う
+pub fn handle(fd: u64) -> Option<Handle> {
+    let pid = match current_pid() {
+        Some(pid) => pid,
+        None if fd == 1 || fd == 2 => return Some(Handle::Console),
+        None => return None,
+    };
+    let table = PROCESS_TABLE.lock();
+    let process = table.get(&pid)?;
+    *process.handles.get(usize::try_from(fd).ok()?)?
+}
+
+pub fn list() -> Vec<ProcessInfo> {
+    PROCESS_TABLE
+        .lock()
+        .values()
+        .map(|process| ProcessInfo {
+            pid: process.pid,
+            parent: process.parent,
+            name: process.name.clone(),
+            state: process.state,
+            exit_code: process.exit_code,
+        })
+        .collect()
+}
+
+pub fn write_process_table(writer: &mut impl fmt::Write) -> fmt::Result {
+    writeln!(
+        writer,
+        "{:>5} {:>5} {:<8} {:>5}  name",
+        "pid", "ppid", "state", "exit"
+    )?;
+    for info in list() {
+        let parent = info.parent.map_or(0, |pid| pid.as_u64());
+        let state = match info.state {
+            ProcessState::Running => "running",
+            ProcessState::Blocked => "blocked",
+            ProcessState::Zombie => "zombie",
+        };
+        match info.exit_code {
+            Some(code) => writeln!(
+                writer,
+                "{:>5} {:>5} {:<8} {:>5}  {}",
+                info.pid, parent, state, code, info.name
+            )?,
+            None => writeln!(
+                writer,
+                "{:>5} {:>5} {:<8} {:>5}  {}",
+                info.pid, parent, state, "-", info.name
+            )?,
+        }
+    }
+    Ok(())
+}
+
+pub fn print_process_table() {
+    struct Console;
+
+    impl fmt::Write for Console {
+        fn write_str(&mut self, s: &str) -> fmt::Result {
+            crate::print!("{}", s);
+            Ok(())
+        }
+    }
+
+    write_process_table(&mut Console).expect("printing process table failed");
+}
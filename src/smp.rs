@@ -0,0 +1,422 @@
+use crate::acpi::{self, SdtHeader};
+use crate::apic::{self, ApicError};
+use crate::time::Instant;
+use crate::{gdt, interrupts, memory, percpu, println, syscall, thread, tlb};
+use alloc::{boxed::Box, vec::Vec};
+use core::arch::global_asm;
+use core::mem;
+use core::sync::atomic::{AtomicU64, AtomicU8, AtomicUsize, Ordering};
+use core::time::Duration;
+use x86_64::structures::paging::{
+    mapper::MapToError, FrameAllocator, FrameDeallocator, Mapper, Page, PageTableFlags, PhysFrame,
+    Size4KiB, Translate,
+};
+use x86_64::{PhysAddr, VirtAddr};
+
+// APが最初にリアルモードで実行するコードを置く物理アドレス
+// SIPIで指定できるのは1MiB未満の4KiB境界なので、ブートローダが使い終わった0x8000に置く
+pub const TRAMPOLINE_ADDR: u64 = 0x8000;
+
+// APのスタックを置く仮想アドレスの範囲
+pub const AP_STACK_START: u64 = 0x_6666_6666_0000;
+static NEXT_AP_STACK: AtomicU64 = AtomicU64::new(AP_STACK_START);
+
+// ACPIのMADT (APIC)テーブル
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+struct Madt {
+    header: SdtHeader,
+    local_apic_address: u32,
+    flags: u32,
+}
+
+// MADTのエントリの共通ヘッダ
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+struct MadtEntryHeader {
+    entry_type: u8,
+    length: u8,
+}
+
+// エントリタイプ0: プロセッサのLocal APIC
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+struct MadtLocalApic {
+    header: MadtEntryHeader,
+    processor_id: u8,
+    apic_id: u8,
+    flags: u32,
+}
+
+const MADT_LOCAL_APIC: u8 = 0;
+const LOCAL_APIC_ENABLED: u32 = 1 << 0;
+const LOCAL_APIC_ONLINE_CAPABLE: u32 = 1 << 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SmpError {
+    // ACPIにMADTが存在しない
+    NoMadt,
+    Apic(ApicError),
+    // トランポリンやスタックをマップできなかった
+    MapFailed,
+}
+
+impl From<ApicError> for SmpError {
+    fn from(err: ApicError) -> Self {
+        SmpError::Apic(err)
+    }
+}
+
+impl From<MapToError<Size4KiB>> for SmpError {
+    fn from(_: MapToError<Size4KiB>) -> Self {
+        SmpError::MapFailed
+    }
+}
+
+// MADTに載っている起動可能なプロセッサのLocal APIC ID
+pub fn apic_ids() -> Result<Vec<u32>, SmpError> {
+    let table = acpi::find_table(b"APIC").ok_or(SmpError::NoMadt)?;
+    let madt: Madt = unsafe { acpi::read_table(table) };
+
+    let mut ids = Vec::new();
+    let end = table + madt.header.length as u64;
+    let mut entry = table + mem::size_of::<Madt>();
+    while entry + mem::size_of::<MadtEntryHeader>() <= end {
+        let header: MadtEntryHeader = unsafe { acpi::read_table(entry) };
+        if header.length < 2 {
+            break;
+        }
+        if header.entry_type == MADT_LOCAL_APIC {
+            let local_apic: MadtLocalApic = unsafe { acpi::read_table(entry) };
+            if local_apic.flags & (LOCAL_APIC_ENABLED | LOCAL_APIC_ONLINE_CAPABLE) != 0 {
+                ids.push(local_apic.apic_id as u32);
+            }
+        }
+        entry += header.length as u64;
+    }
+    Ok(ids)
+}
+
+extern "C" {
+    static ap_trampoline_start: u8;
+    static ap_trampoline_end: u8;
+    static ap_trampoline_cr3: u8;
+    static ap_trampoline_stack: u8;
+    static ap_trampoline_entry: u8;
+    static ap_trampoline_arg: u8;
+}
+
+// APのトランポリン。TRAMPOLINE_ADDRにコピーして実行する
+// リアルモードからページングを有効にして直接ロングモードに移り、スタックを設定してap_mainを呼び出す
+// コピー先で動くように、ラベルのアドレスはap_trampoline_startからのオフセットで計算する
+global_asm!(
+    ".global ap_trampoline_start",
+    ".global ap_trampoline_end",
+    ".global ap_trampoline_cr3",
+    ".global ap_trampoline_stack",
+    ".global ap_trampoline_entry",
+    ".global ap_trampoline_arg",
+    ".code16",
+    "ap_trampoline_start:",
+    "cli",
+    "cld",
+    "xor ax, ax",
+    "mov ds, ax",
+    "mov es, ax",
+    "mov ss, ax",
+    "lgdt [0x8000 + ap_trampoline_gdt_pointer - ap_trampoline_start]",
+    // CR4.PAE
+    "mov eax, cr4",
+    "or eax, 1 << 5",
+    "mov cr4, eax",
+    "mov eax, dword ptr [0x8000 + ap_trampoline_cr3 - ap_trampoline_start]",
+    "mov cr3, eax",
+    // EFER.LMEとEFER.NXE（カーネルのページテーブルはNO_EXECUTEを使う）
+    "mov ecx, 0xc0000080",
+    "rdmsr",
+    "or eax, (1 << 8) | (1 << 11)",
+    "wrmsr",
+    // CR0.PG | CR0.WP | CR0.PE
+    "mov eax, cr0",
+    "or eax, 0x80010001",
+    "mov cr0, eax",
+    // 64bitのコードセグメントへのfar jmp (jmp 0x08:ap_trampoline_long_mode)
+    ".byte 0x66, 0xea",
+    ".long 0x8000 + ap_trampoline_long_mode - ap_trampoline_start",
+    ".word 0x08",
+    ".code64",
+    "ap_trampoline_long_mode:",
+    "mov ax, 0x10",
+    "mov ds, ax",
+    "mov es, ax",
+    "mov ss, ax",
+    "mov rsp, qword ptr [0x8000 + ap_trampoline_stack - ap_trampoline_start]",
+    "mov rdi, qword ptr [0x8000 + ap_trampoline_arg - ap_trampoline_start]",
+    "mov rax, qword ptr [0x8000 + ap_trampoline_entry - ap_trampoline_start]",
+    "call rax",
+    "2:",
+    "hlt",
+    "jmp 2b",
+    ".align 8",
+    "ap_trampoline_gdt:",
+    ".quad 0",
+    // 64bitコードセグメント
+    ".quad 0x00af9a000000ffff",
+    // データセグメント
+    ".quad 0x00cf92000000ffff",
+    "ap_trampoline_gdt_pointer:",
+    ".word 3 * 8 - 1",
+    ".long 0x8000 + ap_trampoline_gdt - ap_trampoline_start",
+    ".align 8",
+    "ap_trampoline_cr3:",
+    ".quad 0",
+    "ap_trampoline_stack:",
+    ".quad 0",
+    "ap_trampoline_entry:",
+    ".quad 0",
+    "ap_trampoline_arg:",
+    ".quad 0",
+    "ap_trampoline_end:",
+);
+
+// ApBootのstateの値
+const AP_STARTING: u8 = 0;
+// APがap_mainに入り、起動情報を使い始めた
+const AP_CLAIMED: u8 = 1;
+const AP_ONLINE: u8 = 2;
+// BSPが待つのをやめた。遅れて起動したAPは起動情報を使わずに止まる
+const AP_ABANDONED: u8 = 3;
+
+// APに渡す起動情報
+// 起動に失敗したAPが遅れてトランポリンから読んでも壊れないよう、確保したまま解放しない
+struct ApBoot {
+    cpu_id: usize,
+    apic_id: u32,
+    // [ダブルフォルト用, ring 0用, SYSCALL用]
+    stacks: [VirtAddr; 3],
+    // 起動の進み具合（AP_*）
+    state: AtomicU8,
+}
+
+// 起動したCPUの数（BSPを含む）
+static ONLINE_CPUS: AtomicUsize = AtomicUsize::new(1);
+
+pub fn online_cpus() -> usize {
+    ONLINE_CPUS.load(Ordering::SeqCst)
+}
+
+fn trampoline_offset(symbol: &u8) -> u64 {
+    symbol as *const u8 as u64 - unsafe { &ap_trampoline_start as *const u8 as u64 }
+}
+
+// トランポリンのパラメータを物理メモリ経由で書き込む
+fn write_trampoline_param(symbol: &u8, value: u64) {
+    let addr = memory::phys_to_virt(PhysAddr::new(TRAMPOLINE_ADDR + trampoline_offset(symbol)));
+    unsafe { addr.as_mut_ptr::<u64>().write_volatile(value) };
+}
+
+// ガードページを挟んでAP用のスタックを新しいフレームにマップし、スタックの終端を返す
+fn map_stack(
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<VirtAddr, SmpError> {
+    let size = percpu::STACK_SIZE as u64;
+    // 先頭の1ページはマップせずにガードページとして残す
+    let start = NEXT_AP_STACK.fetch_add(size + 4096, Ordering::Relaxed) + 4096;
+    let start_page = Page::<Size4KiB>::containing_address(VirtAddr::new(start));
+    let end_page = Page::containing_address(VirtAddr::new(start + size - 1));
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    for page in Page::range_inclusive(start_page, end_page) {
+        let frame = frame_allocator
+            .allocate_frame()
+            .ok_or(MapToError::FrameAllocationFailed)?;
+        unsafe { mapper.map_to(page, frame, flags, frame_allocator)?.flush() };
+    }
+    Ok(VirtAddr::new(start + size))
+}
+
+// map_stackでマップしたスタックのマッピングを外し、フレームを解放する
+fn unmap_stack(
+    end: VirtAddr,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_deallocator: &mut impl FrameDeallocator<Size4KiB>,
+) {
+    let start_page = Page::<Size4KiB>::containing_address(end - percpu::STACK_SIZE as u64);
+    let end_page = Page::containing_address(end - 1u64);
+    for page in Page::range_inclusive(start_page, end_page) {
+        if let Ok((frame, flush)) = mapper.unmap(page) {
+            flush.flush();
+            unsafe { frame_deallocator.deallocate_frame(frame) };
+        }
+    }
+}
+
+fn delay(duration: Duration) {
+    let start = Instant::now();
+    while start.elapsed() < duration {
+        core::hint::spin_loop();
+    }
+}
+
+// MADTに載っている全てのAPをINIT-SIPI-SIPIで起動し、起動したCPUの数を返す
+// ヒープとthread::initの後にBSPから1度だけ呼び出す
+pub fn init(
+    mapper: &mut (impl Mapper<Size4KiB> + Translate),
+    frame_allocator: &mut (impl FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>),
+) -> Result<usize, SmpError> {
+    let ids = apic_ids()?;
+    apic::init(mapper, frame_allocator)?;
+    let bsp_apic_id = apic::id();
+    percpu::current().set_apic_id(bsp_apic_id);
+
+    // APはページングを有効にした直後もトランポリンを実行し続けるので、同じアドレスにマップする
+    let frame = PhysFrame::<Size4KiB>::containing_address(PhysAddr::new(TRAMPOLINE_ADDR));
+    let page = Page::<Size4KiB>::containing_address(VirtAddr::new(TRAMPOLINE_ADDR));
+    let identity_mapped =
+        match unsafe { mapper.identity_map(frame, PageTableFlags::PRESENT, frame_allocator) } {
+            Ok(flush) => {
+                flush.flush();
+                true
+            }
+            // ブートローダが低位のメモリを恒等マップしている場合はそのまま使う
+            Err(MapToError::PageAlreadyMapped(_))
+                if mapper.translate_addr(page.start_address()) == Some(frame.start_address()) =>
+            {
+                false
+            }
+            Err(err) => return Err(err.into()),
+        };
+
+    let trampoline = unsafe {
+        let start = &ap_trampoline_start as *const u8;
+        let len = &ap_trampoline_end as *const u8 as usize - start as usize;
+        core::slice::from_raw_parts(start, len)
+    };
+    unsafe {
+        let dest = memory::phys_to_virt(PhysAddr::new(TRAMPOLINE_ADDR)).as_mut_ptr::<u8>();
+        core::ptr::copy_nonoverlapping(trampoline.as_ptr(), dest, trampoline.len());
+    }
+    let cr3 = memory::kernel_level_4_frame().start_address().as_u64();
+    write_trampoline_param(unsafe { &ap_trampoline_cr3 }, cr3);
+    write_trampoline_param(unsafe { &ap_trampoline_entry }, ap_main as *const () as u64);
+
+    let mut next_cpu_id = 1;
+    for apic_id in ids.into_iter().filter(|&id| id != bsp_apic_id) {
+        if next_cpu_id >= percpu::MAX_CPUS {
+            println!(
+                "SMP: ignoring CPU with APIC id {} (MAX_CPUS reached)",
+                apic_id
+            );
+            continue;
+        }
+        let boot_stack = map_stack(mapper, frame_allocator)?;
+        let stacks = [
+            map_stack(mapper, frame_allocator)?,
+            map_stack(mapper, frame_allocator)?,
+            map_stack(mapper, frame_allocator)?,
+        ];
+        let boot: &'static ApBoot = Box::leak(Box::new(ApBoot {
+            cpu_id: next_cpu_id,
+            apic_id,
+            stacks,
+            state: AtomicU8::new(AP_STARTING),
+        }));
+        write_trampoline_param(unsafe { &ap_trampoline_stack }, boot_stack.as_u64());
+        write_trampoline_param(unsafe { &ap_trampoline_arg }, boot as *const ApBoot as u64);
+
+        if start_ap(boot) {
+            next_cpu_id += 1;
+        } else {
+            println!("SMP: CPU with APIC id {} did not start", apic_id);
+            // APはINITで止めたので、スタックはもう使われない
+            for stack in core::iter::once(boot_stack).chain(stacks) {
+                unmap_stack(stack, mapper, frame_allocator);
+            }
+        }
+    }
+
+    // APのTLBにもトランポリンのマッピングが残っているのでシュートダウンする
+    if identity_mapped {
+        let _ = memory::unmap_region(page.start_address(), 4096, mapper);
+    }
+    Ok(online_cpus())
+}
+
+// INIT-SIPI-SIPIでAPを起動し、ap_mainでonlineになるまで待つ
+// 起動しなかったAPはINITで止めてfalseを返す
+fn start_ap(boot: &ApBoot) -> bool {
+    let vector = (TRAMPOLINE_ADDR / 4096) as u8;
+    let started = || boot.state.load(Ordering::SeqCst) != AP_STARTING;
+    apic::send_init(boot.apic_id);
+    delay(Duration::from_millis(10));
+    for _ in 0..2 {
+        apic::send_startup(boot.apic_id, vector);
+        let start = Instant::now();
+        while !started() && start.elapsed() < Duration::from_millis(100) {
+            core::hint::spin_loop();
+        }
+        if started() {
+            break;
+        }
+    }
+
+    if boot
+        .state
+        .compare_exchange(
+            AP_STARTING,
+            AP_ABANDONED,
+            Ordering::SeqCst,
+            Ordering::SeqCst,
+        )
+        .is_ok()
+    {
+        // トランポリンの途中にいるかもしれないので、INITでSIPI待ちの状態に戻す
+        apic::send_init(boot.apic_id);
+        delay(Duration::from_millis(10));
+        return false;
+    }
+    // ap_mainに入ったAPは初期化を終えるまで待つ
+    while boot.state.load(Ordering::SeqCst) != AP_ONLINE {
+        core::hint::spin_loop();
+    }
+    true
+}
+
+// トランポリンから呼び出されるAPのエントリポイント
+extern "C" fn ap_main(boot: *const ApBoot) -> ! {
+    let boot = unsafe { &*boot };
+    // BSPが待つのをやめた後に起動した場合は、cpu_idやスタックを他のAPに使われるので何もせずに止まる
+    if boot
+        .state
+        .compare_exchange(AP_STARTING, AP_CLAIMED, Ordering::SeqCst, Ordering::SeqCst)
+        .is_err()
+    {
+        loop {
+            x86_64::instructions::interrupts::disable();
+            x86_64::instructions::hlt();
+        }
+    }
+    unsafe { percpu::init_cpu(boot.cpu_id, boot.stacks) };
+    let cpu = percpu::current();
+    cpu.set_apic_id(boot.apic_id);
+
+    gdt::init();
+    interrupts::init_idt();
+    syscall::init();
+    apic::init_ap();
+    tlb::init_cpu();
+    thread::init();
+
+    let online = ONLINE_CPUS.fetch_add(1, Ordering::SeqCst) + 1;
+    println!(
+        "CPU {} online (APIC id {}, {} online)",
+        cpu.id(),
+        cpu.apic_id(),
+        online
+    );
+    boot.state.store(AP_ONLINE, Ordering::SeqCst);
+
+    // タスクが来るまではexecutorの中でhltして待つ
+    x86_64::instructions::interrupts::enable();
+    crate::task::executor::run();
+}
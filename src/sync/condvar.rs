@@ -0,0 +1,100 @@
+use super::mutex::MutexGuard;
+use super::wait_queue::WaitQueue;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::task::{Context, Poll};
+
+// sync::Mutexと組み合わせて使う非同期の条件変数
+// 通知されていなくても戻ることがあるので、待つ側は条件をループで確認する
+pub struct Condvar {
+    // 通知のたびに増える。waitを呼んだときの値から変わったら戻る
+    generation: AtomicU64,
+    waiters: WaitQueue,
+}
+
+impl Condvar {
+    pub const fn new() -> Self {
+        Condvar {
+            generation: AtomicU64::new(0),
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    // ロックを外して通知を待ち、戻る前にロックを取り直す
+    pub async fn wait<'a, T: ?Sized>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+        // ロックを外す前に世代を読むので、外した直後の通知も取りこぼさない
+        let generation = self.generation.load(Ordering::SeqCst);
+        let mutex = guard.unlock();
+        Notified {
+            condvar: self,
+            generation,
+        }
+        .await;
+        mutex.lock().await
+    }
+
+    pub fn notify_one(&self) {
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        self.waiters.wake_one();
+    }
+
+    pub fn notify_all(&self) {
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        self.waiters.wake_all();
+    }
+}
+
+impl Default for Condvar {
+    fn default() -> Self {
+        Condvar::new()
+    }
+}
+
+struct Notified<'a> {
+    condvar: &'a Condvar,
+    generation: u64,
+}
+
+impl Future for Notified<'_> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        let notified = || self.condvar.generation.load(Ordering::SeqCst) != self.generation;
+        if notified() {
+            return Poll::Ready(());
+        }
+        self.condvar.waiters.register(cx.waker());
+        if notified() {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+#[test_case]
+fn test_condvar_wakes_waiter() {
+    use super::Mutex;
+    use crate::task::{simple_executor::SimpleExecutor, Task};
+    use alloc::sync::Arc;
+
+    let state = Arc::new((Mutex::new(false), Condvar::new()));
+    let mut executor = SimpleExecutor::new();
+    let waiter = state.clone();
+    executor.spawn(Task::new(async move {
+        let (ready, condvar) = &*waiter;
+        let mut guard = ready.lock().await;
+        while !*guard {
+            guard = condvar.wait(guard).await;
+        }
+    }));
+    let notifier = state.clone();
+    executor.spawn(Task::new(async move {
+        let (ready, condvar) = &*notifier;
+        *ready.lock().await = true;
+        condvar.notify_all();
+    }));
+    executor.run();
+    assert!(*state.0.try_lock().expect("mutex still locked"));
+}
@@ -0,0 +1,242 @@
+// スピンロックを取る順番を記録し、逆の順番で取ろうとしたらパニックする（デバッグビルドのみ）
+// ロックはアドレスで区別し、あるロックを持ったまま別のロックを取るたびに「先→後」の辺をグラフに加える
+// 取ろうとしているロックから持っているロックへ辺を辿れる場合、2つのCPUが互いを待つデッドロックが起こりうる
+#![cfg_attr(not(debug_assertions), allow(dead_code))]
+
+use crate::percpu;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+// 1つのCPUが同時に持てるスピンロックの数
+const MAX_HELD: usize = 16;
+// 記録できる順番の数。超えた分は記録しない
+const MAX_EDGES: usize = 256;
+
+// 順番の違反を見つけた後は検出を止める。パニックの表示でロックを取るときに再び違反しないように
+static ENABLED: AtomicBool = AtomicBool::new(true);
+static GRAPH: spin::Mutex<Graph> = spin::Mutex::new(Graph::new());
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LockOrderViolation {
+    // 取ろうとしたロックのアドレス
+    pub acquiring: u64,
+    // すでに持っていたロックのアドレス
+    pub held: u64,
+}
+
+// CPUごとの持っているロックのスタック
+// 割り込みを止めた状態で自分のCPUからしか触らない
+pub(crate) struct HeldLocks {
+    locks: [u64; MAX_HELD],
+    depth: usize,
+}
+
+impl HeldLocks {
+    pub(crate) const fn new() -> Self {
+        HeldLocks {
+            locks: [0; MAX_HELD],
+            depth: 0,
+        }
+    }
+
+    fn as_slice(&self) -> &[u64] {
+        &self.locks[..self.depth]
+    }
+
+    fn push(&mut self, lock: u64) {
+        assert!(self.depth < MAX_HELD, "too many spinlocks held");
+        self.locks[self.depth] = lock;
+        self.depth += 1;
+    }
+
+    // ロックは取ったのと逆順に外すとは限らないので、最後に取ったものから探して取り除く
+    fn remove(&mut self, lock: u64) {
+        if let Some(i) = self.as_slice().iter().rposition(|&held| held == lock) {
+            self.locks.copy_within(i + 1..self.depth, i);
+            self.depth -= 1;
+        }
+    }
+}
+
+struct Graph {
+    edges: [(u64, u64); MAX_EDGES],
+    len: usize,
+}
+
+impl Graph {
+    const fn new() -> Self {
+        Graph {
+            edges: [(0, 0); MAX_EDGES],
+            len: 0,
+        }
+    }
+
+    fn edges(&self) -> &[(u64, u64)] {
+        &self.edges[..self.len]
+    }
+
+    fn add(&mut self, from: u64, to: u64) {
+        if self.len == MAX_EDGES || self.edges().contains(&(from, to)) {
+            return;
+        }
+        self.edges[self.len] = (from, to);
+        self.len += 1;
+    }
+
+    // 解放されたロックのアドレスは再利用されるので、関係する辺を消す
+    fn forget(&mut self, lock: u64) {
+        let mut kept = 0;
+        for i in 0..self.len {
+            let (from, to) = self.edges[i];
+            if from != lock && to != lock {
+                self.edges[kept] = (from, to);
+                kept += 1;
+            }
+        }
+        self.len = kept;
+    }
+
+    // fromから辺を辿ってtoに着くか
+    fn reachable(&self, from: u64, to: u64) -> bool {
+        // 辺ごとに一度しか辿らないので、スタックは辺の数より大きくならない
+        let mut visited = [false; MAX_EDGES];
+        let mut stack = [0u64; MAX_EDGES];
+        let mut top = 0;
+        stack[top] = from;
+        top += 1;
+        while top > 0 {
+            top -= 1;
+            let node = stack[top];
+            for (i, &(edge_from, edge_to)) in self.edges().iter().enumerate() {
+                if visited[i] || edge_from != node {
+                    continue;
+                }
+                if edge_to == to {
+                    return true;
+                }
+                visited[i] = true;
+                stack[top] = edge_to;
+                top += 1;
+            }
+        }
+        false
+    }
+}
+
+// heldを持ったままlockを取ってよいかを確認する
+// recursiveが真の場合は同じロックを重ねて取れる（RwLockの読み込み）
+fn check(
+    graph: &Graph,
+    held: &[u64],
+    lock: u64,
+    recursive: bool,
+) -> Result<(), LockOrderViolation> {
+    for &held_lock in held {
+        let violated = if held_lock == lock {
+            !recursive
+        } else {
+            graph.reachable(lock, held_lock)
+        };
+        if violated {
+            return Err(LockOrderViolation {
+                acquiring: lock,
+                held: held_lock,
+            });
+        }
+    }
+    Ok(())
+}
+
+// 実行中のCPUの持っているロックのスタック。percpuの初期化前はNone
+fn held_locks() -> Option<&'static mut HeldLocks> {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return None;
+    }
+    let cpu = percpu::try_current()?;
+    Some(unsafe { &mut *cpu.held_locks.get() })
+}
+
+// ロックを待ち始める前に呼び出す。割り込みは止めておくこと
+pub(crate) fn acquire(lock: u64, recursive: bool) {
+    #[cfg(debug_assertions)]
+    {
+        let held = match held_locks() {
+            Some(held) => held,
+            None => return,
+        };
+        let mut graph = GRAPH.lock();
+        if let Err(violation) = check(&graph, held.as_slice(), lock, recursive) {
+            ENABLED.store(false, Ordering::Relaxed);
+            drop(graph);
+            panic!(
+                "lock order violation: acquiring {:#x} while holding {:#x}",
+                violation.acquiring, violation.held
+            );
+        }
+        for &held_lock in held.as_slice() {
+            if held_lock != lock {
+                graph.add(held_lock, lock);
+            }
+        }
+        held.push(lock);
+    }
+    #[cfg(not(debug_assertions))]
+    let _ = (lock, recursive);
+}
+
+// try_lockで取れた場合に呼び出す。待たないのでデッドロックにはならず、順番も記録しない
+pub(crate) fn acquire_nonblocking(lock: u64) {
+    #[cfg(debug_assertions)]
+    if let Some(held) = held_locks() {
+        held.push(lock);
+    }
+    #[cfg(not(debug_assertions))]
+    let _ = lock;
+}
+
+pub(crate) fn release(lock: u64) {
+    #[cfg(debug_assertions)]
+    if let Some(held) = held_locks() {
+        held.remove(lock);
+    }
+    #[cfg(not(debug_assertions))]
+    let _ = lock;
+}
+
+// ロックが破棄されたときに呼び出す
+pub(crate) fn forget(lock: u64) {
+    #[cfg(debug_assertions)]
+    x86_64::instructions::interrupts::without_interrupts(|| GRAPH.lock().forget(lock));
+    #[cfg(not(debug_assertions))]
+    let _ = lock;
+}
+
+#[test_case]
+fn test_inverted_order_is_detected() {
+    let mut graph = Graph::new();
+    graph.add(1, 2);
+    assert!(check(&graph, &[1], 2, false).is_ok());
+    assert_eq!(
+        check(&graph, &[2], 1, false),
+        Err(LockOrderViolation {
+            acquiring: 1,
+            held: 2
+        })
+    );
+}
+
+#[test_case]
+fn test_transitive_inversion_is_detected() {
+    let mut graph = Graph::new();
+    graph.add(1, 2);
+    graph.add(2, 3);
+    assert!(check(&graph, &[3], 1, false).is_err());
+    graph.forget(2);
+    assert!(check(&graph, &[3], 1, false).is_ok());
+}
+
+#[test_case]
+fn test_recursive_acquire() {
+    let graph = Graph::new();
+    assert!(check(&graph, &[1], 1, false).is_err());
+    assert!(check(&graph, &[1], 1, true).is_ok());
+}
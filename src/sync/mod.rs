@@ -0,0 +1,17 @@
+// カーネル内の同期のための型
+// SpinLockとRwLockは持っている間割り込みを止めるので、割り込みハンドラと共有するデータに使う
+// Mutex、Semaphore、Condvarは取れるまで非同期タスクを眠らせるので、awaitをまたいで持つデータに使う
+pub mod condvar;
+pub mod lockdep;
+pub mod mutex;
+pub mod rwlock;
+pub mod semaphore;
+pub mod spinlock;
+mod wait_queue;
+
+pub use condvar::Condvar;
+pub use mutex::{Mutex, MutexGuard};
+pub use rwlock::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+pub use semaphore::{Semaphore, SemaphorePermit};
+pub use spinlock::{SpinLock, SpinLockGuard};
+pub(crate) use wait_queue::WaitQueue;
@@ -0,0 +1,146 @@
+use super::wait_queue::WaitQueue;
+use core::cell::UnsafeCell;
+use core::future::Future;
+use core::ops::{Deref, DerefMut};
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::{Context, Poll};
+
+// 非同期タスクから使う眠るミューテックス
+// 取れないときはスピンせずにタスクを眠らせ、他のタスクを実行させる
+// ガードを持ったままawaitできるが、割り込みハンドラからは使えない
+pub struct Mutex<T: ?Sized> {
+    locked: AtomicBool,
+    waiters: WaitQueue,
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Sync for Mutex<T> {}
+unsafe impl<T: ?Sized + Send> Send for Mutex<T> {}
+
+impl<T> Mutex<T> {
+    pub const fn new(value: T) -> Self {
+        Mutex {
+            locked: AtomicBool::new(false),
+            waiters: WaitQueue::new(),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+}
+
+impl<T: ?Sized> Mutex<T> {
+    pub fn lock(&self) -> MutexLockFuture<'_, T> {
+        MutexLockFuture { mutex: self }
+    }
+
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        self.locked
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .ok()
+            .map(|_| MutexGuard { mutex: self })
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.locked.load(Ordering::Relaxed)
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
+}
+
+pub struct MutexLockFuture<'a, T: ?Sized> {
+    mutex: &'a Mutex<T>,
+}
+
+impl<'a, T: ?Sized> Future for MutexLockFuture<'a, T> {
+    type Output = MutexGuard<'a, T>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<MutexGuard<'a, T>> {
+        if let Some(guard) = self.mutex.try_lock() {
+            return Poll::Ready(guard);
+        }
+        self.mutex.waiters.register(cx.waker());
+        // 登録する前に外された場合に起こされないままにならないよう、もう一度試す
+        match self.mutex.try_lock() {
+            Some(guard) => Poll::Ready(guard),
+            None => Poll::Pending,
+        }
+    }
+}
+
+pub struct MutexGuard<'a, T: ?Sized> {
+    mutex: &'a Mutex<T>,
+}
+
+impl<'a, T: ?Sized> MutexGuard<'a, T> {
+    // ロックを外して元のMutexを返す。Condvar::waitで使う
+    pub(crate) fn unlock(self) -> &'a Mutex<T> {
+        let mutex = self.mutex;
+        drop(self);
+        mutex
+    }
+}
+
+impl<T: ?Sized> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.value.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.value.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.mutex.locked.store(false, Ordering::Release);
+        // 起こしたタスクのfutureが既に捨てられていると誰もロックを取らなくなるので、全員を起こして競わせる
+        self.mutex.waiters.wake_all();
+    }
+}
+
+#[test_case]
+fn test_mutex_is_shared_between_tasks() {
+    use crate::task::{simple_executor::SimpleExecutor, Task};
+    use alloc::sync::Arc;
+
+    // 一度だけPendingを返して他のタスクに譲る
+    struct YieldOnce(bool);
+
+    impl Future for YieldOnce {
+        type Output = ();
+
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+            if self.0 {
+                return Poll::Ready(());
+            }
+            self.0 = true;
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }
+    }
+
+    let mutex = Arc::new(Mutex::new(0));
+    let mut executor = SimpleExecutor::new();
+    for _ in 0..3 {
+        let mutex = mutex.clone();
+        executor.spawn(Task::new(async move {
+            let mut value = mutex.lock().await;
+            let before = *value;
+            // ガードを持ったまま他のタスクに譲っても、他のタスクは待たされる
+            YieldOnce(false).await;
+            *value = before + 1;
+        }));
+    }
+    executor.run();
+    assert_eq!(*mutex.try_lock().expect("mutex still locked"), 3);
+}
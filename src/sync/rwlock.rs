@@ -0,0 +1,187 @@
+use super::lockdep;
+use core::cell::UnsafeCell;
+use core::marker::PhantomData;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use x86_64::instructions::interrupts;
+
+// 書き込み中を表すstateの値。それ以外は読み込み中のガードの数
+const WRITER: usize = usize::MAX;
+
+// 複数の読み込みか1つの書き込みを許すスピンロック。持っている間はSpinLockと同じく割り込みを止める
+// 読み込みが続くと書き込みは待たされ続けるので、書き込みが多いデータにはSpinLockを使う
+pub struct RwLock<T: ?Sized> {
+    state: AtomicUsize,
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send + Sync> Sync for RwLock<T> {}
+unsafe impl<T: ?Sized + Send> Send for RwLock<T> {}
+
+impl<T> RwLock<T> {
+    pub const fn new(value: T) -> Self {
+        RwLock {
+            state: AtomicUsize::new(0),
+            value: UnsafeCell::new(value),
+        }
+    }
+}
+
+impl<T: ?Sized> RwLock<T> {
+    pub fn read(&self) -> RwLockReadGuard<'_, T> {
+        let interrupts_enabled = interrupts::are_enabled();
+        interrupts::disable();
+        lockdep::acquire(self.id(), true);
+        loop {
+            let state = self.state.load(Ordering::Relaxed);
+            if state != WRITER
+                && self
+                    .state
+                    .compare_exchange_weak(state, state + 1, Ordering::Acquire, Ordering::Relaxed)
+                    .is_ok()
+            {
+                break;
+            }
+            // SpinLockと同じく、待っている間もシュートダウンに応答する
+            crate::tlb::handle_pending();
+            core::hint::spin_loop();
+        }
+        RwLockReadGuard {
+            lock: self,
+            interrupts_enabled,
+            _not_send: PhantomData,
+        }
+    }
+
+    pub fn write(&self) -> RwLockWriteGuard<'_, T> {
+        let interrupts_enabled = interrupts::are_enabled();
+        interrupts::disable();
+        lockdep::acquire(self.id(), false);
+        while self
+            .state
+            .compare_exchange_weak(0, WRITER, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            crate::tlb::handle_pending();
+            core::hint::spin_loop();
+        }
+        RwLockWriteGuard {
+            lock: self,
+            interrupts_enabled,
+            _not_send: PhantomData,
+        }
+    }
+
+    pub fn try_write(&self) -> Option<RwLockWriteGuard<'_, T>> {
+        let interrupts_enabled = interrupts::are_enabled();
+        interrupts::disable();
+        if self
+            .state
+            .compare_exchange(0, WRITER, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+        {
+            lockdep::acquire_nonblocking(self.id());
+            Some(RwLockWriteGuard {
+                lock: self,
+                interrupts_enabled,
+                _not_send: PhantomData,
+            })
+        } else {
+            if interrupts_enabled {
+                interrupts::enable();
+            }
+            None
+        }
+    }
+
+    // 読み込み中のガードの数
+    pub fn reader_count(&self) -> usize {
+        match self.state.load(Ordering::Relaxed) {
+            WRITER => 0,
+            readers => readers,
+        }
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
+
+    fn id(&self) -> u64 {
+        self as *const Self as *const u8 as u64
+    }
+}
+
+impl<T: ?Sized> Drop for RwLock<T> {
+    fn drop(&mut self) {
+        lockdep::forget(self.id());
+    }
+}
+
+pub struct RwLockReadGuard<'a, T: ?Sized> {
+    lock: &'a RwLock<T>,
+    interrupts_enabled: bool,
+    _not_send: PhantomData<*const ()>,
+}
+
+impl<T: ?Sized> Deref for RwLockReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for RwLockReadGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.state.fetch_sub(1, Ordering::Release);
+        lockdep::release(self.lock.id());
+        if self.interrupts_enabled {
+            interrupts::enable();
+        }
+    }
+}
+
+pub struct RwLockWriteGuard<'a, T: ?Sized> {
+    lock: &'a RwLock<T>,
+    interrupts_enabled: bool,
+    _not_send: PhantomData<*const ()>,
+}
+
+impl<T: ?Sized> Deref for RwLockWriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for RwLockWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.value.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for RwLockWriteGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.state.store(0, Ordering::Release);
+        lockdep::release(self.lock.id());
+        if self.interrupts_enabled {
+            interrupts::enable();
+        }
+    }
+}
+
+#[test_case]
+fn test_multiple_readers() {
+    let lock = RwLock::new(1);
+    let first = lock.read();
+    let second = lock.read();
+    assert_eq!(*first + *second, 2);
+    assert_eq!(lock.reader_count(), 2);
+    assert!(lock.try_write().is_none());
+    drop(first);
+    drop(second);
+    *lock.try_write().expect("no readers left") = 2;
+    assert_eq!(*lock.read(), 2);
+    assert!(interrupts::are_enabled());
+}
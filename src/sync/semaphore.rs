@@ -0,0 +1,102 @@
+use super::wait_queue::WaitQueue;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::task::{Context, Poll};
+
+// 非同期タスクから使う計数セマフォ。許可が無いときはタスクを眠らせる
+pub struct Semaphore {
+    permits: AtomicUsize,
+    waiters: WaitQueue,
+}
+
+impl Semaphore {
+    pub const fn new(permits: usize) -> Self {
+        Semaphore {
+            permits: AtomicUsize::new(permits),
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    pub fn acquire(&self) -> Acquire<'_> {
+        Acquire { semaphore: self }
+    }
+
+    pub fn try_acquire(&self) -> Option<SemaphorePermit<'_>> {
+        self.permits
+            .fetch_update(Ordering::Acquire, Ordering::Relaxed, |permits| {
+                permits.checked_sub(1)
+            })
+            .ok()
+            .map(|_| SemaphorePermit { semaphore: self })
+    }
+
+    // 許可を増やして待っているタスクを起こす
+    // 割り込みハンドラからも呼び出せる
+    pub fn add_permits(&self, permits: usize) {
+        self.permits.fetch_add(permits, Ordering::Release);
+        self.waiters.wake_all();
+    }
+
+    pub fn available_permits(&self) -> usize {
+        self.permits.load(Ordering::Relaxed)
+    }
+}
+
+pub struct Acquire<'a> {
+    semaphore: &'a Semaphore,
+}
+
+impl<'a> Future for Acquire<'a> {
+    type Output = SemaphorePermit<'a>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<SemaphorePermit<'a>> {
+        if let Some(permit) = self.semaphore.try_acquire() {
+            return Poll::Ready(permit);
+        }
+        self.semaphore.waiters.register(cx.waker());
+        match self.semaphore.try_acquire() {
+            Some(permit) => Poll::Ready(permit),
+            None => Poll::Pending,
+        }
+    }
+}
+
+// 破棄すると許可を返す
+pub struct SemaphorePermit<'a> {
+    semaphore: &'a Semaphore,
+}
+
+impl SemaphorePermit<'_> {
+    // 許可を返さずに捨てる。add_permitsと組み合わせて、イベントの数を数えるのに使う
+    pub fn forget(self) {
+        core::mem::forget(self);
+    }
+}
+
+impl Drop for SemaphorePermit<'_> {
+    fn drop(&mut self) {
+        self.semaphore.add_permits(1);
+    }
+}
+
+#[test_case]
+fn test_semaphore_limits_concurrency() {
+    use crate::task::{simple_executor::SimpleExecutor, Task};
+    use alloc::sync::Arc;
+
+    let semaphore = Arc::new(Semaphore::new(2));
+    let first = semaphore.try_acquire().expect("no permit");
+    let _second = semaphore.try_acquire().expect("no permit");
+    assert!(semaphore.try_acquire().is_none());
+    drop(first);
+    assert_eq!(semaphore.available_permits(), 1);
+
+    let mut executor = SimpleExecutor::new();
+    let task_semaphore = semaphore.clone();
+    executor.spawn(Task::new(async move {
+        task_semaphore.acquire().await.forget();
+    }));
+    executor.run();
+    assert_eq!(semaphore.available_permits(), 0);
+}
@@ -0,0 +1,157 @@
+use super::lockdep;
+use core::cell::UnsafeCell;
+use core::marker::PhantomData;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::instructions::interrupts;
+
+// 持っている間は割り込みを止めるスピンロック
+// 同じCPUの割り込みハンドラが同じロックを取ろうとしてデッドロックすることがない
+pub struct SpinLock<T: ?Sized> {
+    locked: AtomicBool,
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Sync for SpinLock<T> {}
+unsafe impl<T: ?Sized + Send> Send for SpinLock<T> {}
+
+impl<T> SpinLock<T> {
+    pub const fn new(value: T) -> Self {
+        SpinLock {
+            locked: AtomicBool::new(false),
+            value: UnsafeCell::new(value),
+        }
+    }
+}
+
+impl<T: ?Sized> SpinLock<T> {
+    pub fn lock(&self) -> SpinLockGuard<'_, T> {
+        let interrupts_enabled = interrupts::are_enabled();
+        interrupts::disable();
+        lockdep::acquire(self.id(), false);
+        while self
+            .locked
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            while self.locked.load(Ordering::Relaxed) {
+                // ロックを持っているCPUがシュートダウンの完了を待っているかもしれないので、割り込みを止めたままでも応答する
+                crate::tlb::handle_pending();
+                core::hint::spin_loop();
+            }
+        }
+        SpinLockGuard::new(self, interrupts_enabled)
+    }
+
+    pub fn try_lock(&self) -> Option<SpinLockGuard<'_, T>> {
+        let interrupts_enabled = interrupts::are_enabled();
+        interrupts::disable();
+        if self
+            .locked
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+        {
+            lockdep::acquire_nonblocking(self.id());
+            Some(SpinLockGuard::new(self, interrupts_enabled))
+        } else {
+            if interrupts_enabled {
+                interrupts::enable();
+            }
+            None
+        }
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.locked.load(Ordering::Relaxed)
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
+
+    fn id(&self) -> u64 {
+        self as *const Self as *const u8 as u64
+    }
+}
+
+impl<T: ?Sized> Drop for SpinLock<T> {
+    fn drop(&mut self) {
+        lockdep::forget(self.id());
+    }
+}
+
+impl<T: Default> Default for SpinLock<T> {
+    fn default() -> Self {
+        SpinLock::new(T::default())
+    }
+}
+
+pub struct SpinLockGuard<'a, T: ?Sized> {
+    lock: &'a SpinLock<T>,
+    // ロックを取る前に割り込みが有効だったか。外したときに元に戻す
+    interrupts_enabled: bool,
+    // 割り込みの状態はCPUごとなので、他のスレッドに渡さない
+    _not_send: PhantomData<*const ()>,
+}
+
+impl<'a, T: ?Sized> SpinLockGuard<'a, T> {
+    fn new(lock: &'a SpinLock<T>, interrupts_enabled: bool) -> Self {
+        SpinLockGuard {
+            lock,
+            interrupts_enabled,
+            _not_send: PhantomData,
+        }
+    }
+}
+
+unsafe impl<T: ?Sized + Sync> Sync for SpinLockGuard<'_, T> {}
+
+impl<T: ?Sized> Deref for SpinLockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for SpinLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.value.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for SpinLockGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.locked.store(false, Ordering::Release);
+        lockdep::release(self.lock.id());
+        if self.interrupts_enabled {
+            interrupts::enable();
+        }
+    }
+}
+
+#[test_case]
+fn test_interrupts_disabled_while_held() {
+    let lock = SpinLock::new(0);
+    assert!(interrupts::are_enabled());
+    {
+        let mut value = lock.lock();
+        *value += 1;
+        assert!(!interrupts::are_enabled());
+        assert!(lock.try_lock().is_none());
+    }
+    assert!(interrupts::are_enabled());
+    assert_eq!(*lock.lock(), 1);
+}
+
+#[test_case]
+fn test_nested_guard_keeps_interrupts_disabled() {
+    let outer = SpinLock::new(());
+    let inner = SpinLock::new(());
+    let outer_guard = outer.lock();
+    drop(inner.lock());
+    // 内側のロックを外しても、外側を持っている間は割り込みを止めたまま
+    assert!(!interrupts::are_enabled());
+    drop(outer_guard);
+    assert!(interrupts::are_enabled());
+}
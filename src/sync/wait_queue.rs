@@ -0,0 +1,40 @@
+use super::SpinLock;
+use alloc::collections::VecDeque;
+use core::task::Waker;
+
+// 眠っている非同期タスクのwakerの列
+// 割り込みハンドラからも起こせるようにSpinLockで守る
+pub(crate) struct WaitQueue {
+    wakers: SpinLock<VecDeque<Waker>>,
+}
+
+impl WaitQueue {
+    pub(crate) const fn new() -> Self {
+        WaitQueue {
+            wakers: SpinLock::new(VecDeque::new()),
+        }
+    }
+
+    // 同じタスクが何度pollしても1つだけ登録する
+    pub(crate) fn register(&self, waker: &Waker) {
+        let mut wakers = self.wakers.lock();
+        if !wakers.iter().any(|registered| registered.will_wake(waker)) {
+            wakers.push_back(waker.clone());
+        }
+    }
+
+    // wakeはexecutorのキューを操作するので、ロックを外してから呼び出す
+    pub(crate) fn wake_one(&self) {
+        let waker = self.wakers.lock().pop_front();
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+
+    pub(crate) fn wake_all(&self) {
+        let wakers = core::mem::take(&mut *self.wakers.lock());
+        for waker in wakers {
+            waker.wake();
+        }
+    }
+}
@@ -0,0 +1,325 @@
+use crate::memory::{self, GlobalFrameAllocator};
+use crate::process::{self, Handle, ProcessState};
+use crate::sync::SpinLock;
+use crate::{gdt, percpu, print, thread, usermode};
+use core::arch::global_asm;
+use core::time::Duration;
+use x86_64::registers::model_specific::{Efer, EferFlags, LStar, SFMask, Star};
+use x86_64::registers::rflags::RFlags;
+use x86_64::structures::paging::{
+    mapper::TranslateResult, Page, PageTableFlags, Size4KiB, Translate,
+};
+use x86_64::VirtAddr;
+
+// システムコール番号
+pub const SYS_WRITE: u64 = 0;
+pub const SYS_EXIT: u64 = 1;
+pub const SYS_YIELD: u64 = 2;
+pub const SYS_SLEEP: u64 = 3;
+pub const SYS_MMAP: u64 = 4;
+pub const SYS_GETPID: u64 = 5;
+pub const SYS_MUNMAP: u64 = 6;
+pub const SYS_MPROTECT: u64 = 7;
+
+// mmap, mprotectのprot引数
+pub const PROT_WRITE: u64 = 1 << 1;
+
+// ユーザーが使える空間の終わり
+// 下位半分の最後のページでsyscallすると戻り先のRCXが非正規アドレスになり、sysretqがring 0で#GPを起こすため、
+// 最後のページはユーザーに渡さない
+pub const USER_SPACE_END: u64 = 0x_0000_7fff_ffff_f000;
+// アドレスを指定しないmmapで割り当てる領域の開始アドレス
+pub const USER_MMAP_START: u64 = 0x_2000_0000_0000;
+
+// システムコールのエラー。ユーザーには負の値として返す
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(i64)]
+pub enum SyscallError {
+    BadFileDescriptor = -9,
+    NoMemory = -12,
+    Fault = -14,
+    InvalidArgument = -22,
+    NoSys = -38,
+}
+
+type SyscallResult = Result<u64, SyscallError>;
+type SyscallHandler = fn(u64, u64, u64, u64, u64) -> SyscallResult;
+
+// システムコール番号で引くディスパッチテーブル
+static SYSCALL_TABLE: [SyscallHandler; 8] = [
+    sys_write,    // 0
+    sys_exit,     // 1
+    sys_yield,    // 2
+    sys_sleep,    // 3
+    sys_mmap,     // 4
+    sys_getpid,   // 5
+    sys_munmap,   // 6
+    sys_mprotect, // 7
+];
+
+extern "C" {
+    fn syscall_entry();
+}
+
+// SYSCALL命令の入口
+// rax: システムコール番号, rdi, rsi, rdx, r10, r8: 引数
+// rcx, r11にはCPUがユーザーのRIPとRFLAGSを保存している
+// SWAPGSでpercpuのデータに切り替え、gs:[16]にユーザーのスタックポインタを退避してgs:[8]のカーネルスタックに移る
+global_asm!(
+    ".global syscall_entry",
+    "syscall_entry:",
+    "swapgs",
+    "mov qword ptr gs:[16], rsp",
+    "mov rsp, qword ptr gs:[8]",
+    "push qword ptr gs:[16]",
+    "push rcx",
+    "push r11",
+    "push rdi",
+    "push rsi",
+    "push rdx",
+    "push r8",
+    "push r9",
+    "push r10",
+    // 呼び出し前にスタックを16バイト境界に揃える
+    "sub rsp, 8",
+    // System V ABIの引数レジスタに並べ替えてsyscall_dispatchを呼び出す
+    "mov r9, r8",
+    "mov r8, r10",
+    "mov rcx, rdx",
+    "mov rdx, rsi",
+    "mov rsi, rdi",
+    "mov rdi, rax",
+    "sti",
+    "call syscall_dispatch",
+    "cli",
+    "add rsp, 8",
+    "pop r10",
+    "pop r9",
+    "pop r8",
+    "pop rdx",
+    "pop rsi",
+    "pop rdi",
+    "pop r11",
+    "pop rcx",
+    "pop rsp",
+    "swapgs",
+    "sysretq",
+);
+
+pub fn default_kernel_stack() -> VirtAddr {
+    percpu::current().syscall_stack()
+}
+
+/// # Safety
+// SYSCALLで切り替えるカーネルスタックを差し替える
+// 呼び出し元は割り込みを無効にし、topが有効なスタックの終端であることを保証しなければならない
+pub unsafe fn set_kernel_stack(top: VirtAddr) {
+    percpu::current().set_syscall_kernel_rsp(top);
+}
+
+pub fn init() {
+    unsafe {
+        set_kernel_stack(default_kernel_stack());
+
+        Efer::update(|flags| flags.insert(EferFlags::SYSTEM_CALL_EXTENSIONS));
+    }
+    Star::write(
+        gdt::user_code_selector(),
+        gdt::user_data_selector(),
+        gdt::kernel_code_selector(),
+        gdt::kernel_data_selector(),
+    )
+    .expect("invalid GDT layout for SYSCALL/SYSRET");
+    LStar::write(VirtAddr::new(syscall_entry as *const () as u64));
+    // カーネルに入るときに割り込みを無効にし、方向フラグとトラップフラグを消す
+    SFMask::write(RFlags::INTERRUPT_FLAG | RFlags::DIRECTION_FLAG | RFlags::TRAP_FLAG);
+}
+
+#[no_mangle]
+extern "C" fn syscall_dispatch(number: u64, a1: u64, a2: u64, a3: u64, a4: u64, a5: u64) -> u64 {
+    let result = match SYSCALL_TABLE.get(number as usize) {
+        Some(handler) => handler(a1, a2, a3, a4, a5),
+        None => Err(SyscallError::NoSys),
+    };
+    match result {
+        Ok(value) => value,
+        Err(err) => err as i64 as u64,
+    }
+}
+
+// ユーザーが渡したアドレス範囲が全てユーザーのページにマップされているかを確認する
+// 不正なポインタでカーネルがページフォルトを起こさないように、アクセスする前に必ず呼び出す
+pub fn validate_user_range(addr: u64, len: u64, writable: bool) -> Result<(), SyscallError> {
+    if len == 0 {
+        return Ok(());
+    }
+    let end = addr.checked_add(len).ok_or(SyscallError::Fault)?;
+    if addr == 0 || end > USER_SPACE_END {
+        return Err(SyscallError::Fault);
+    }
+
+    let mapper = unsafe { memory::active_mapper() };
+    let first_page = Page::<Size4KiB>::containing_address(VirtAddr::new(addr));
+    let last_page = Page::<Size4KiB>::containing_address(VirtAddr::new(end - 1));
+    for page in Page::range_inclusive(first_page, last_page) {
+        let flags = match mapper.translate(page.start_address()) {
+            TranslateResult::Mapped { flags, .. } => flags,
+            _ => return Err(SyscallError::Fault),
+        };
+        if !flags.contains(PageTableFlags::USER_ACCESSIBLE) {
+            return Err(SyscallError::Fault);
+        }
+        if writable && !flags.contains(PageTableFlags::WRITABLE) {
+            return Err(SyscallError::Fault);
+        }
+    }
+    Ok(())
+}
+
+fn user_slice(addr: u64, len: u64) -> Result<&'static [u8], SyscallError> {
+    validate_user_range(addr, len, false)?;
+    if len == 0 {
+        return Ok(&[]);
+    }
+    Ok(unsafe { core::slice::from_raw_parts(addr as *const u8, len as usize) })
+}
+
+// write(fd, buf, len): プロセスのハンドルに書き出す
+fn sys_write(fd: u64, buf: u64, len: u64, _: u64, _: u64) -> SyscallResult {
+    let handle = process::handle(fd).ok_or(SyscallError::BadFileDescriptor)?;
+    let bytes = user_slice(buf, len)?;
+    match handle {
+        Handle::Console => {
+            let s = core::str::from_utf8(bytes).map_err(|_| SyscallError::InvalidArgument)?;
+            print!("{}", s);
+        }
+    }
+    Ok(len)
+}
+
+// exit(code): enter_user_modeの呼び出し元に戻る
+fn sys_exit(code: u64, _: u64, _: u64, _: u64, _: u64) -> SyscallResult {
+    unsafe { usermode::exit_to_kernel(code) }
+}
+
+// yield(): 他のスレッドに実行を譲る
+fn sys_yield(_: u64, _: u64, _: u64, _: u64, _: u64) -> SyscallResult {
+    thread::yield_now();
+    Ok(0)
+}
+
+// sleep(ms): 眠っている間はプロセスをBlockedにする
+fn sys_sleep(ms: u64, _: u64, _: u64, _: u64, _: u64) -> SyscallResult {
+    let pid = process::current_pid();
+    if let Some(pid) = pid {
+        process::set_state(pid, ProcessState::Blocked);
+    }
+    thread::sleep(Duration::from_millis(ms));
+    if let Some(pid) = pid {
+        process::set_state(pid, ProcessState::Running);
+    }
+    Ok(0)
+}
+
+// プロセスに属さないカーネルのスレッドがアドレスを指定せずにmmapしたときに次に割り当てるアドレス
+static KERNEL_NEXT_MMAP: SpinLock<u64> = SpinLock::new(USER_MMAP_START);
+
+// [start, start + len)がユーザーにマップしてよい範囲かを確認する
+fn check_user_mapping(start: u64, len: u64) -> Result<(), SyscallError> {
+    let end = match start.checked_add(len) {
+        Some(end) if end <= USER_SPACE_END => end,
+        _ => return Err(SyscallError::InvalidArgument),
+    };
+    match memory::shared_kernel_slot(VirtAddr::new(start), VirtAddr::new(end)) {
+        Some(_) => Err(SyscallError::InvalidArgument),
+        None => Ok(()),
+    }
+}
+
+// nextから探してカーネルと共有しているエントリに重ならない場所を選び、nextをその後ろに進める
+// ユーザー空間に収まらない場合はnextを変えない
+fn place_mmap(next: &mut u64, len: u64) -> Option<u64> {
+    let mut start = *next;
+    loop {
+        let end = start
+            .checked_add(len)
+            .filter(|&end| end <= USER_SPACE_END)?;
+        match memory::shared_kernel_slot(VirtAddr::new(start), VirtAddr::new(end)) {
+            Some(slot_end) => start = slot_end.as_u64(),
+            None => {
+                *next = end;
+                return Some(start);
+            }
+        }
+    }
+}
+
+// mmap(addr, len, prot): 匿名メモリを割り当てる。addrが0の場合はカーネルが場所を決める
+fn sys_mmap(addr: u64, len: u64, prot: u64, _: u64, _: u64) -> SyscallResult {
+    if len == 0 || !addr.is_multiple_of(4096) {
+        return Err(SyscallError::InvalidArgument);
+    }
+    let len = match len.checked_add(4095) {
+        Some(len) => len & !4095,
+        None => return Err(SyscallError::InvalidArgument),
+    };
+    let addr = if addr == 0 {
+        let placed = match process::current_pid() {
+            Some(pid) => process::with_next_mmap(pid, |next| place_mmap(next, len)).flatten(),
+            None => place_mmap(&mut KERNEL_NEXT_MMAP.lock(), len),
+        };
+        placed.ok_or(SyscallError::NoMemory)?
+    } else {
+        check_user_mapping(addr, len)?;
+        addr
+    };
+
+    let flags = if prot & PROT_WRITE != 0 {
+        PageTableFlags::WRITABLE
+    } else {
+        PageTableFlags::empty()
+    };
+    let mut mapper = unsafe { memory::active_mapper() };
+    usermode::map_user_region(
+        VirtAddr::new(addr),
+        len,
+        flags,
+        &mut mapper,
+        &mut GlobalFrameAllocator,
+    )
+    .map_err(|_| SyscallError::NoMemory)?;
+    Ok(addr)
+}
+
+// munmap(addr, len): mmapした領域のマッピングを外す。他のCPUのTLBからも消える
+fn sys_munmap(addr: u64, len: u64, _: u64, _: u64, _: u64) -> SyscallResult {
+    if len == 0 || !addr.is_multiple_of(4096) {
+        return Err(SyscallError::InvalidArgument);
+    }
+    validate_user_range(addr, len, false).map_err(|_| SyscallError::InvalidArgument)?;
+    let mut mapper = unsafe { memory::active_mapper() };
+    memory::unmap_region(VirtAddr::new(addr), len, &mut mapper)
+        .map_err(|_| SyscallError::InvalidArgument)?;
+    Ok(0)
+}
+
+// mprotect(addr, len, prot): マップ済みの領域の書き込み権限を変える
+fn sys_mprotect(addr: u64, len: u64, prot: u64, _: u64, _: u64) -> SyscallResult {
+    if len == 0 || !addr.is_multiple_of(4096) {
+        return Err(SyscallError::InvalidArgument);
+    }
+    validate_user_range(addr, len, false).map_err(|_| SyscallError::InvalidArgument)?;
+    let mut flags = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+    if prot & PROT_WRITE != 0 {
+        flags |= PageTableFlags::WRITABLE;
+    }
+    let mut mapper = unsafe { memory::active_mapper() };
+    memory::protect_region(VirtAddr::new(addr), len, flags, &mut mapper)
+        .map_err(|_| SyscallError::InvalidArgument)?;
+    Ok(0)
+}
+
+// getpid(): プロセスに属さないカーネルのスレッドから呼ばれた場合は0を返す
+fn sys_getpid(_: u64, _: u64, _: u64, _: u64, _: u64) -> SyscallResult {
+    Ok(process::current_pid().map_or(0, |pid| pid.as_u64()))
+}
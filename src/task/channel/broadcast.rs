@@ -0,0 +1,281 @@
+use super::SendError;
+use crate::sync::{SpinLock, WaitQueue};
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use core::fmt;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecvError {
+    // 送信側が全て捨てられ、残っている値も全て受け取った
+    Closed,
+    // 受け取るのが遅れて、古い値がこの数だけ上書きされた。次は残っている中で一番古い値を受け取る
+    Lagged(u64),
+}
+
+impl fmt::Display for RecvError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RecvError::Closed => write!(f, "channel closed"),
+            RecvError::Lagged(missed) => write!(f, "receiver lagged by {} values", missed),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TryRecvError {
+    Empty,
+    Closed,
+    Lagged(u64),
+}
+
+impl fmt::Display for TryRecvError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TryRecvError::Empty => write!(f, "channel empty"),
+            TryRecvError::Closed => write!(f, "channel closed"),
+            TryRecvError::Lagged(missed) => write!(f, "receiver lagged by {} values", missed),
+        }
+    }
+}
+
+struct State<T> {
+    // 直近capacity個の値。古いものから並ぶ
+    buffer: VecDeque<T>,
+    capacity: usize,
+    // bufferの先頭の値の通し番号
+    head: u64,
+    senders: usize,
+    receivers: usize,
+}
+
+impl<T> State<T> {
+    // 次に送られる値の通し番号
+    fn tail(&self) -> u64 {
+        self.head + self.buffer.len() as u64
+    }
+}
+
+struct Shared<T> {
+    state: SpinLock<State<T>>,
+    // 値が届くのを待っている受信側
+    receivers_waiting: WaitQueue,
+}
+
+// 送った値を全ての受信側に届けるチャネルを作る
+// 直近capacity個の値を残し、遅れた受信側は上書きされた分を読み飛ばす。送信側は待たされない
+pub fn channel<T: Clone>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    assert!(capacity > 0, "channel capacity must be positive");
+    let shared = Arc::new(Shared {
+        state: SpinLock::new(State {
+            buffer: VecDeque::with_capacity(capacity),
+            capacity,
+            head: 0,
+            senders: 1,
+            receivers: 1,
+        }),
+        receivers_waiting: WaitQueue::new(),
+    });
+    (
+        Sender {
+            shared: shared.clone(),
+        },
+        Receiver { shared, next: 0 },
+    )
+}
+
+pub struct Sender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T: Clone> Sender<T> {
+    // 値を送って受信側を全て起こし、送った時点の受信側の数を返す
+    // 待たずにメモリも確保しないので、割り込みハンドラからも呼び出せる
+    pub fn send(&self, value: T) -> Result<usize, SendError<T>> {
+        let (receivers, overwritten) = {
+            let mut state = self.shared.state.lock();
+            if state.receivers == 0 {
+                return Err(SendError(value));
+            }
+            let overwritten = if state.buffer.len() == state.capacity {
+                state.head += 1;
+                state.buffer.pop_front()
+            } else {
+                None
+            };
+            state.buffer.push_back(value);
+            (state.receivers, overwritten)
+        };
+        drop(overwritten);
+        self.shared.receivers_waiting.wake_all();
+        Ok(receivers)
+    }
+
+    // これから送られる値を受け取る受信側を作る
+    pub fn subscribe(&self) -> Receiver<T> {
+        let mut state = self.shared.state.lock();
+        state.receivers += 1;
+        Receiver {
+            shared: self.shared.clone(),
+            next: state.tail(),
+        }
+    }
+
+    pub fn receiver_count(&self) -> usize {
+        self.shared.state.lock().receivers
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.shared.state.lock().senders += 1;
+        Sender {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let last = {
+            let mut state = self.shared.state.lock();
+            state.senders -= 1;
+            state.senders == 0
+        };
+        if last {
+            self.shared.receivers_waiting.wake_all();
+        }
+    }
+}
+
+pub struct Receiver<T> {
+    shared: Arc<Shared<T>>,
+    // 次に受け取る値の通し番号
+    next: u64,
+}
+
+impl<T: Clone> Receiver<T> {
+    // 値が届くまで待つ
+    pub fn recv(&mut self) -> RecvFuture<'_, T> {
+        RecvFuture { receiver: self }
+    }
+
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        let state = self.shared.state.lock();
+        if self.next < state.head {
+            let missed = state.head - self.next;
+            self.next = state.head;
+            return Err(TryRecvError::Lagged(missed));
+        }
+        if self.next == state.tail() {
+            return Err(if state.senders == 0 {
+                TryRecvError::Closed
+            } else {
+                TryRecvError::Empty
+            });
+        }
+        let value = state.buffer[(self.next - state.head) as usize].clone();
+        self.next += 1;
+        Ok(value)
+    }
+
+    // 待たずに結果が決まる場合はそれを返す
+    fn recv_ready(&mut self) -> Option<Result<T, RecvError>> {
+        match self.try_recv() {
+            Ok(value) => Some(Ok(value)),
+            Err(TryRecvError::Lagged(missed)) => Some(Err(RecvError::Lagged(missed))),
+            Err(TryRecvError::Closed) => Some(Err(RecvError::Closed)),
+            Err(TryRecvError::Empty) => None,
+        }
+    }
+
+    // 同じ位置から受け取りを続ける受信側を作る
+    pub fn resubscribe(&self) -> Receiver<T> {
+        self.shared.state.lock().receivers += 1;
+        Receiver {
+            shared: self.shared.clone(),
+            next: self.next,
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        // 最後の受信側が捨てられたら、もう誰も読まない値をロックの外で捨てる
+        let buffer = {
+            let mut state = self.shared.state.lock();
+            state.receivers -= 1;
+            if state.receivers == 0 {
+                state.head += state.buffer.len() as u64;
+                core::mem::take(&mut state.buffer)
+            } else {
+                VecDeque::new()
+            }
+        };
+        drop(buffer);
+    }
+}
+
+pub struct RecvFuture<'a, T> {
+    receiver: &'a mut Receiver<T>,
+}
+
+impl<T: Clone> Future for RecvFuture<'_, T> {
+    type Output = Result<T, RecvError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<T, RecvError>> {
+        if let Some(result) = self.receiver.recv_ready() {
+            return Poll::Ready(result);
+        }
+        self.receiver.shared.receivers_waiting.register(cx.waker());
+        // 登録する前に送られた場合に起こされないままにならないよう、もう一度試す
+        match self.receiver.recv_ready() {
+            Some(result) => Poll::Ready(result),
+            None => Poll::Pending,
+        }
+    }
+}
+
+#[test_case]
+fn test_broadcast_reaches_every_receiver() {
+    use crate::task::{simple_executor::SimpleExecutor, Task};
+    use core::sync::atomic::{AtomicU64, Ordering};
+
+    static SUM: AtomicU64 = AtomicU64::new(0);
+    let (sender, receiver) = channel(4);
+    let receivers = [receiver, sender.subscribe(), sender.subscribe()];
+    assert_eq!(sender.receiver_count(), 3);
+    let mut executor = SimpleExecutor::new();
+    for mut receiver in receivers {
+        executor.spawn(Task::new(async move {
+            while let Ok(value) = receiver.recv().await {
+                SUM.fetch_add(value, Ordering::SeqCst);
+            }
+        }));
+    }
+    executor.spawn(Task::new(async move {
+        for value in 1..=3 {
+            sender.send(value).expect("no receivers");
+        }
+    }));
+    executor.run();
+    assert_eq!(SUM.load(Ordering::SeqCst), 3 * (1 + 2 + 3));
+}
+
+#[test_case]
+fn test_broadcast_lagged_receiver_skips_overwritten() {
+    let (sender, mut receiver) = channel(2);
+    for value in 0..5 {
+        sender.send(value).expect("no receivers");
+    }
+    assert_eq!(receiver.try_recv(), Err(TryRecvError::Lagged(3)));
+    assert_eq!(receiver.try_recv(), Ok(3));
+    let mut late = sender.subscribe();
+    assert_eq!(late.try_recv(), Err(TryRecvError::Empty));
+    drop(sender);
+    assert_eq!(receiver.try_recv(), Ok(4));
+    assert_eq!(receiver.try_recv(), Err(TryRecvError::Closed));
+    assert_eq!(late.try_recv(), Err(TryRecvError::Closed));
+}
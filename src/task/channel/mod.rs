@@ -0,0 +1,85 @@
+// 非同期タスクの間で値を送るチャネル
+// 内部はSpinLockで守っていて、送信側のtry_sendやsendは割り込みハンドラからも呼び出せる
+// 受信側は値が無いときにタスクを眠らせ、値が届くとexecutorのwakerで起こされる
+use core::fmt;
+
+pub mod broadcast;
+pub mod mpsc;
+pub mod oneshot;
+
+// 受信側が全て捨てられたので送れなかった。送ろうとした値を返す
+#[derive(PartialEq, Eq)]
+pub struct SendError<T>(pub T);
+
+impl<T> fmt::Debug for SendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("SendError(..)")
+    }
+}
+
+impl<T> fmt::Display for SendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "channel closed")
+    }
+}
+
+#[derive(PartialEq, Eq)]
+pub enum TrySendError<T> {
+    // バッファがいっぱい
+    Full(T),
+    // 受信側が全て捨てられた
+    Closed(T),
+}
+
+impl<T> TrySendError<T> {
+    pub fn into_inner(self) -> T {
+        match self {
+            TrySendError::Full(value) | TrySendError::Closed(value) => value,
+        }
+    }
+}
+
+impl<T> fmt::Debug for TrySendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TrySendError::Full(_) => f.write_str("Full(..)"),
+            TrySendError::Closed(_) => f.write_str("Closed(..)"),
+        }
+    }
+}
+
+impl<T> fmt::Display for TrySendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TrySendError::Full(_) => write!(f, "channel full"),
+            TrySendError::Closed(_) => write!(f, "channel closed"),
+        }
+    }
+}
+
+// 送信側が全て捨てられ、もう値が届かない
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecvError;
+
+impl fmt::Display for RecvError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "channel closed")
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TryRecvError {
+    // まだ値が届いていない
+    Empty,
+    // 送信側が全て捨てられ、もう値が届かない
+    Closed,
+}
+
+impl fmt::Display for TryRecvError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TryRecvError::Empty => write!(f, "channel empty"),
+            TryRecvError::Closed => write!(f, "channel closed"),
+        }
+    }
+}
@@ -0,0 +1,289 @@
+use super::{SendError, TryRecvError, TrySendError};
+use crate::sync::{SpinLock, WaitQueue};
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
+use futures_util::stream::Stream;
+
+struct State<T> {
+    queue: VecDeque<T>,
+    capacity: usize,
+    senders: usize,
+    receiver_dropped: bool,
+    // 値が届くのを待っている受信側のwaker
+    receiver_waker: Option<Waker>,
+}
+
+struct Shared<T> {
+    state: SpinLock<State<T>>,
+    // キューが空くのを待っている送信側
+    senders_waiting: WaitQueue,
+}
+
+// 容量がcapacityの複数送信・単一受信のチャネルを作る
+// キューは最初に確保するので、割り込みハンドラからtry_sendしてもメモリを確保しない
+pub fn channel<T>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    assert!(capacity > 0, "channel capacity must be positive");
+    let shared = Arc::new(Shared {
+        state: SpinLock::new(State {
+            queue: VecDeque::with_capacity(capacity),
+            capacity,
+            senders: 1,
+            receiver_dropped: false,
+            receiver_waker: None,
+        }),
+        senders_waiting: WaitQueue::new(),
+    });
+    (
+        Sender {
+            shared: shared.clone(),
+        },
+        Receiver { shared },
+    )
+}
+
+pub struct Sender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> Sender<T> {
+    // キューに空きができるまで待ってから送る
+    pub fn send(&self, value: T) -> SendFuture<'_, T> {
+        SendFuture {
+            sender: self,
+            value: Some(value),
+        }
+    }
+
+    // 待たずに送る。割り込みハンドラからはこちらを使う
+    pub fn try_send(&self, value: T) -> Result<(), TrySendError<T>> {
+        let waker = {
+            let mut state = self.shared.state.lock();
+            if state.receiver_dropped {
+                return Err(TrySendError::Closed(value));
+            }
+            if state.queue.len() == state.capacity {
+                return Err(TrySendError::Full(value));
+            }
+            state.queue.push_back(value);
+            state.receiver_waker.take()
+        };
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+        Ok(())
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.shared.state.lock().receiver_dropped
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.shared.state.lock().capacity
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.shared.state.lock().senders += 1;
+        Sender {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        // 最後の送信側が捨てられたら、受信側を起こしてNoneを返させる
+        let waker = {
+            let mut state = self.shared.state.lock();
+            state.senders -= 1;
+            if state.senders == 0 {
+                state.receiver_waker.take()
+            } else {
+                None
+            }
+        };
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+pub struct SendFuture<'a, T> {
+    sender: &'a Sender<T>,
+    value: Option<T>,
+}
+
+// valueをピン留めしたまま使うことはないので、Tに関係なく動かせる
+impl<T> Unpin for SendFuture<'_, T> {}
+
+impl<T> Future for SendFuture<'_, T> {
+    type Output = Result<(), SendError<T>>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), SendError<T>>> {
+        let value = self
+            .value
+            .take()
+            .expect("SendFuture polled after completion");
+        let value = match self.sender.try_send(value) {
+            Ok(()) => return Poll::Ready(Ok(())),
+            Err(TrySendError::Closed(value)) => return Poll::Ready(Err(SendError(value))),
+            Err(TrySendError::Full(value)) => value,
+        };
+        self.sender.shared.senders_waiting.register(cx.waker());
+        // 登録する前に空いた場合に起こされないままにならないよう、もう一度試す
+        match self.sender.try_send(value) {
+            Ok(()) => Poll::Ready(Ok(())),
+            Err(TrySendError::Closed(value)) => Poll::Ready(Err(SendError(value))),
+            Err(TrySendError::Full(value)) => {
+                self.value = Some(value);
+                Poll::Pending
+            }
+        }
+    }
+}
+
+pub struct Receiver<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> Receiver<T> {
+    // 値が届くまで待つ。送信側が全て捨てられてキューが空になったらNoneを返す
+    pub fn recv(&mut self) -> RecvFuture<'_, T> {
+        RecvFuture { receiver: self }
+    }
+
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        let value = {
+            let mut state = self.shared.state.lock();
+            match state.queue.pop_front() {
+                Some(value) => value,
+                None if state.senders == 0 => return Err(TryRecvError::Closed),
+                None => return Err(TryRecvError::Empty),
+            }
+        };
+        // Mutexと同じく、起こしたタスクのfutureが捨てられていても他の送信側が進めるように全員を起こす
+        self.shared.senders_waiting.wake_all();
+        Ok(value)
+    }
+
+    pub fn poll_recv(&mut self, cx: &mut Context) -> Poll<Option<T>> {
+        loop {
+            match self.try_recv() {
+                Ok(value) => return Poll::Ready(Some(value)),
+                Err(TryRecvError::Closed) => return Poll::Ready(None),
+                Err(TryRecvError::Empty) => {}
+            }
+            let mut state = self.shared.state.lock();
+            // ロックを外している間に届いた場合は、もう一度取り出しに行く
+            if state.queue.is_empty() && state.senders > 0 {
+                state.receiver_waker = Some(cx.waker().clone());
+                return Poll::Pending;
+            }
+        }
+    }
+
+    // キューに残っている値の数
+    pub fn len(&self) -> usize {
+        self.shared.state.lock().queue.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<T> Stream for Receiver<T> {
+    type Item = T;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<T>> {
+        self.get_mut().poll_recv(cx)
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        // 残っている値はロックの外で捨てる
+        let queue = {
+            let mut state = self.shared.state.lock();
+            state.receiver_dropped = true;
+            state.receiver_waker = None;
+            core::mem::take(&mut state.queue)
+        };
+        drop(queue);
+        self.shared.senders_waiting.wake_all();
+    }
+}
+
+pub struct RecvFuture<'a, T> {
+    receiver: &'a mut Receiver<T>,
+}
+
+impl<T> Future for RecvFuture<'_, T> {
+    type Output = Option<T>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<T>> {
+        self.receiver.poll_recv(cx)
+    }
+}
+
+#[test_case]
+fn test_mpsc_waits_for_space() {
+    use crate::task::{simple_executor::SimpleExecutor, Task};
+    use alloc::vec::Vec;
+
+    let (sender, mut receiver) = channel(1);
+    let received = Arc::new(SpinLock::new(Vec::new()));
+    let mut executor = SimpleExecutor::new();
+    for start in [0, 10] {
+        let sender = sender.clone();
+        executor.spawn(Task::new(async move {
+            for i in start..start + 3 {
+                sender.send(i).await.expect("receiver dropped");
+            }
+        }));
+    }
+    drop(sender);
+    let task_received = received.clone();
+    executor.spawn(Task::new(async move {
+        while let Some(value) = receiver.recv().await {
+            task_received.lock().push(value);
+        }
+    }));
+    executor.run();
+
+    let mut received = core::mem::take(&mut *received.lock());
+    received.sort();
+    assert_eq!(received, [0, 1, 2, 10, 11, 12]);
+}
+
+#[test_case]
+fn test_mpsc_try_send() {
+    let (sender, mut receiver) = channel(2);
+    assert_eq!(sender.try_send(1), Ok(()));
+    // 割り込みハンドラと同じく、割り込みを止めた状態でも送れる
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        assert_eq!(sender.try_send(2), Ok(()));
+        assert_eq!(sender.try_send(3), Err(TrySendError::Full(3)));
+    });
+    assert_eq!(receiver.try_recv(), Ok(1));
+    assert_eq!(receiver.len(), 1);
+    drop(receiver);
+    assert!(sender.is_closed());
+    assert_eq!(sender.try_send(4), Err(TrySendError::Closed(4)));
+}
+
+#[test_case]
+fn test_mpsc_closed_after_senders_dropped() {
+    let (sender, mut receiver) = channel(4);
+    let second = sender.clone();
+    sender.try_send(1).expect("send failed");
+    drop(sender);
+    assert_eq!(receiver.try_recv(), Ok(1));
+    assert_eq!(receiver.try_recv(), Err(TryRecvError::Empty));
+    drop(second);
+    assert_eq!(receiver.try_recv(), Err(TryRecvError::Closed));
+}
@@ -0,0 +1,149 @@
+use super::{RecvError, TryRecvError};
+use crate::sync::SpinLock;
+use alloc::sync::Arc;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
+
+struct State<T> {
+    value: Option<T>,
+    // 値を受け取るまで待っている受信側のwaker
+    waker: Option<Waker>,
+    sender_dropped: bool,
+    receiver_dropped: bool,
+}
+
+type Shared<T> = Arc<SpinLock<State<T>>>;
+
+// 1つの値を1回だけ送るチャネルを作る
+pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
+    let state = Arc::new(SpinLock::new(State {
+        value: None,
+        waker: None,
+        sender_dropped: false,
+        receiver_dropped: false,
+    }));
+    (
+        Sender {
+            state: state.clone(),
+        },
+        Receiver { state },
+    )
+}
+
+pub struct Sender<T> {
+    state: Shared<T>,
+}
+
+impl<T> Sender<T> {
+    // 値を送って受信側を起こす。待たないので割り込みハンドラからも呼び出せる
+    // 受信側が既に捨てられていた場合は値を返す
+    pub fn send(self, value: T) -> Result<(), T> {
+        let waker = {
+            let mut state = self.state.lock();
+            if state.receiver_dropped {
+                return Err(value);
+            }
+            state.value = Some(value);
+            state.waker.take()
+        };
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+        Ok(())
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.state.lock().receiver_dropped
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let waker = {
+            let mut state = self.state.lock();
+            state.sender_dropped = true;
+            state.waker.take()
+        };
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+// 値が届くまで待つfuture。値を送らずに送信側が捨てられるとRecvErrorを返す
+pub struct Receiver<T> {
+    state: Shared<T>,
+}
+
+impl<T> Receiver<T> {
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        let mut state = self.state.lock();
+        match state.value.take() {
+            Some(value) => Ok(value),
+            None if state.sender_dropped => Err(TryRecvError::Closed),
+            None => Err(TryRecvError::Empty),
+        }
+    }
+}
+
+impl<T> Future for Receiver<T> {
+    type Output = Result<T, RecvError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<T, RecvError>> {
+        let mut state = self.state.lock();
+        if let Some(value) = state.value.take() {
+            return Poll::Ready(Ok(value));
+        }
+        if state.sender_dropped {
+            return Poll::Ready(Err(RecvError));
+        }
+        state.waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        // 受け取らなかった値はロックの外で捨てる
+        let value = {
+            let mut state = self.state.lock();
+            state.receiver_dropped = true;
+            state.waker = None;
+            state.value.take()
+        };
+        drop(value);
+    }
+}
+
+#[test_case]
+fn test_oneshot_delivers_value() {
+    use crate::task::{simple_executor::SimpleExecutor, Task};
+    use core::sync::atomic::{AtomicU64, Ordering};
+
+    static RESULT: AtomicU64 = AtomicU64::new(0);
+    let (sender, receiver) = channel();
+    let mut executor = SimpleExecutor::new();
+    executor.spawn(Task::new(async move {
+        RESULT.store(receiver.await.expect("sender dropped"), Ordering::SeqCst);
+    }));
+    executor.spawn(Task::new(async move {
+        sender.send(7).expect("receiver dropped");
+    }));
+    executor.run();
+    assert_eq!(RESULT.load(Ordering::SeqCst), 7);
+}
+
+#[test_case]
+fn test_oneshot_closed_ends() {
+    let (sender, mut receiver) = channel::<u64>();
+    assert_eq!(receiver.try_recv(), Err(TryRecvError::Empty));
+    drop(sender);
+    assert_eq!(receiver.try_recv(), Err(TryRecvError::Closed));
+
+    let (sender, receiver) = channel();
+    assert!(!sender.is_closed());
+    drop(receiver);
+    assert!(sender.is_closed());
+    assert_eq!(sender.send(1), Err(1));
+}
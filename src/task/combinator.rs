@@ -0,0 +1,153 @@
+// 複数のfutureを同時に待つためのfutureとマクロ
+// futures_utilのjoin!はasync-await-macro featureの手続きマクロを、select!はさらにstdを必要とするので、
+// futures_util::futureのjoinとselectを組み合わせる簡単なマクロをここで用意する
+use alloc::boxed::Box;
+use core::future::Future;
+use core::pin::Pin;
+
+pub use futures_util::future::{join, select, Either, Join, Select};
+
+// select!の中で使う。Unpinでないfutureも渡せるよう、ヒープに置いてピン留めしてからselectする
+#[doc(hidden)]
+pub fn select_boxed<A: Future, B: Future>(a: A, b: B) -> Select<Pin<Box<A>>, Pin<Box<B>>> {
+    select(Box::pin(a), Box::pin(b))
+}
+
+// 全てのfutureが完了するまで待ち、結果をタプルで返す。asyncの中で使う。4つまで
+// let (a, b) = join!(future_a, future_b);
+#[macro_export]
+macro_rules! join {
+    ($a:expr $(,)?) => {
+        ($a.await,)
+    };
+    ($a:expr, $b:expr $(,)?) => {
+        $crate::task::combinator::join($a, $b).await
+    };
+    ($a:expr, $b:expr, $c:expr $(,)?) => {
+        match $crate::task::combinator::join($a, $crate::task::combinator::join($b, $c)).await {
+            (a, (b, c)) => (a, b, c),
+        }
+    };
+    ($a:expr, $b:expr, $c:expr, $d:expr $(,)?) => {
+        match $crate::task::combinator::join(
+            $crate::task::combinator::join($a, $b),
+            $crate::task::combinator::join($c, $d),
+        )
+        .await
+        {
+            ((a, b), (c, d)) => (a, b, c, d),
+        }
+    };
+}
+
+// 最初に完了したfutureの結果をパターンに束縛し、その腕の式を評価する。asyncの中で使う
+// 残りのfutureは捨てる。同時に完了できる場合は上の腕を優先する
+// 腕はmatchで振り分けるので、パターンは必ず一致するもの（変数、_、タプルの分解など）に限る
+// Some(x)のような一致しないことがあるパターンはコンパイルエラーになる
+// select! {
+//     key = keyboard.next() => handle_key(key),
+//     _ = timer::sleep(duration) => on_timeout(),
+// }
+#[macro_export]
+macro_rules! select {
+    ($($pattern:pat = $future:expr => $body:expr),+ $(,)?) => {
+        {
+            let output = $crate::__select_futures!($($future),+).await;
+            $crate::__select_arms!(output; $($pattern = $future => $body),+)
+        }
+    };
+}
+
+// select!の中で使う。futureをselectで右に入れ子にして1つのfutureにする
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __select_futures {
+    ($future:expr) => {
+        $future
+    };
+    ($future:expr, $($rest:expr),+) => {
+        $crate::task::combinator::select_boxed($future, $crate::__select_futures!($($rest),+))
+    };
+}
+
+// select!の中で使う。入れ子になったEitherを開いて、完了したfutureの腕を評価する
+// selectは完了しなかった方のfutureも返すが、使わずに捨てる
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __select_arms {
+    ($output:ident; $pattern:pat = $future:expr => $body:expr) => {
+        match $output {
+            $pattern => $body,
+        }
+    };
+    ($output:ident; $pattern:pat = $future:expr => $body:expr, $($rest:tt)+) => {
+        match $output {
+            $crate::task::combinator::Either::Left(($pattern, _)) => $body,
+            $crate::task::combinator::Either::Right((output, _)) => {
+                $crate::__select_arms!(output; $($rest)+)
+            }
+        }
+    };
+}
+
+#[test_case]
+fn test_join_waits_for_every_future() {
+    use super::{simple_executor::SimpleExecutor, Task};
+    use core::sync::atomic::{AtomicU64, Ordering};
+
+    static SUM: AtomicU64 = AtomicU64::new(0);
+    let mut executor = SimpleExecutor::new();
+    executor.spawn(Task::new(async {
+        let (a, b, c) = crate::join!(async { 1 }, YieldOnce::new(2), async { 3 });
+        SUM.store(a + b + c, Ordering::SeqCst);
+    }));
+    executor.run();
+    assert_eq!(SUM.load(Ordering::SeqCst), 6);
+}
+
+#[test_case]
+fn test_select_returns_first_ready() {
+    use super::{simple_executor::SimpleExecutor, Task};
+    use core::sync::atomic::{AtomicU64, Ordering};
+
+    static RESULT: AtomicU64 = AtomicU64::new(0);
+    let mut executor = SimpleExecutor::new();
+    executor.spawn(Task::new(async {
+        let result = crate::select! {
+            _ = core::future::pending::<()>() => 1,
+            value = YieldOnce::new(2) => value,
+            value = YieldOnce::new(3) => value,
+        };
+        RESULT.store(result, Ordering::SeqCst);
+    }));
+    executor.run();
+    assert_eq!(RESULT.load(Ordering::SeqCst), 2);
+}
+
+#[cfg(test)]
+use core::task::{Context, Poll};
+
+// 一度Pendingを返してから値を返す
+#[cfg(test)]
+struct YieldOnce<T>(Option<T>, bool);
+
+#[cfg(test)]
+impl<T> YieldOnce<T> {
+    fn new(value: T) -> Self {
+        YieldOnce(Some(value), false)
+    }
+}
+
+#[cfg(test)]
+impl<T: Unpin> Future for YieldOnce<T> {
+    type Output = T;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<T> {
+        if self.1 {
+            return Poll::Ready(self.0.take().expect("YieldOnce polled after completion"));
+        }
+        self.1 = true;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
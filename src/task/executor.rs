@@ -1,95 +1,654 @@
-use super::{Task, TaskId};
-use alloc::{collections::BTreeMap, sync::Arc, task::Wake};
+use super::join::{self, JoinHandle};
+use super::run_queue::RunQueue;
+use super::unwind;
+use super::{Priority, Task, TaskId};
+use crate::sync::SpinLock;
+use crate::time::Instant;
+use crate::{apic, ipi, percpu, serial_println, thread};
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::string::String;
+use alloc::vec::Vec;
+use alloc::{sync::Arc, task::Wake};
+use core::fmt;
+use core::future::Future;
+use core::panic::Location;
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering};
 use core::task::{Context, Poll, Waker};
-use crossbeam_queue::ArrayQueue;
+use core::time::Duration;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
 
+// pollのたびに自分を起こし続けるタスクが、この回数続けてpollされたら一度だけLowのキューに回す
+// 優先度の高い忙しいタスクが他のタスクを飢えさせないようにする
+const POLL_BUDGET: u32 = 16;
+
+// 全てのCPUで共有するタスク
+// pollしている間はtaskのロックを取り、他のCPUが同時にpollしないようにする
+struct TaskEntry {
+    task: Mutex<Task>,
+    waker: Arc<TaskWaker>,
+    // poll中でもタスクの一覧を表示できるように、Taskから移しておく
+    name: Option<String>,
+    location: &'static Location<'static>,
+}
+
+// 割り込みハンドラからは触らない
+static TASKS: Mutex<BTreeMap<TaskId, Arc<TaskEntry>>> = Mutex::new(BTreeMap::new());
+// パニックして捨てられたタスクの数
+static FAILED_TASKS: AtomicU64 = AtomicU64::new(0);
+// Spawnerから追加されたタスク。各CPUのexecutorがループのたびにTASKSへ移す
+// 割り込みハンドラからも追加できるようにSpinLockで守る
+static INCOMING: SpinLock<VecDeque<Task>> = SpinLock::new(VecDeque::new());
+
+// 各CPUはpercpuの自分のキューからタスクを取り出して実行し、空になったら他のCPUのキューから盗む
 pub struct Executor {
-    tasks: BTreeMap<TaskId, Task>,
-    task_queue: Arc<ArrayQueue<TaskId>>,
-    waker_cache: BTreeMap<TaskId, Waker>,
+    _private: (),
 }
 
 impl Executor {
     pub fn new() -> Self {
-        Executor {
-            tasks: BTreeMap::new(),
-            task_queue: Arc::new(ArrayQueue::new(100)),
-            waker_cache: BTreeMap::new(),
+        Executor { _private: () }
+    }
+
+    #[track_caller]
+    pub fn spawn<F>(&mut self, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        spawn(future)
+    }
+
+    #[track_caller]
+    pub fn spawn_with_priority<F>(&mut self, priority: Priority, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        spawn_with_priority(priority, future)
+    }
+
+    pub fn run(&mut self) -> ! {
+        run()
+    }
+
+    pub fn block_on<F: Future>(&mut self, future: F) -> F::Output {
+        block_on(future)
+    }
+
+    pub fn run_until_idle(&mut self) {
+        run_until_idle()
+    }
+
+    pub fn spawner(&self) -> Spawner {
+        spawner()
+    }
+
+    pub fn tasks(&self) -> Vec<TaskInfo> {
+        tasks()
+    }
+
+    pub fn dump_tasks(&self) {
+        dump_tasks()
+    }
+}
+
+impl Default for Executor {
+    fn default() -> Self {
+        Executor::new()
+    }
+}
+
+// 実行中のタスクや割り込みハンドラからタスクを追加するためのハンドル
+// タスクは共有のキューに入り、次にexecutorがループを回ったときに実行され始める
+#[derive(Debug, Clone)]
+pub struct Spawner {
+    _private: (),
+}
+
+impl Spawner {
+    #[track_caller]
+    pub fn spawn<F>(&self, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        Builder::new().spawn_shared(future)
+    }
+
+    #[track_caller]
+    pub fn spawn_with_priority<F>(&self, priority: Priority, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        Builder::new().priority(priority).spawn_shared(future)
+    }
+}
+
+pub fn spawner() -> Spawner {
+    Spawner { _private: () }
+}
+
+// 名前や優先度を指定してタスクを作る
+#[derive(Debug, Default)]
+pub struct Builder {
+    name: Option<String>,
+    priority: Priority,
+}
+
+impl Builder {
+    pub fn new() -> Self {
+        Builder::default()
+    }
+
+    // タスクの一覧に表示する名前
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    pub fn priority(mut self, priority: Priority) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    // executor::spawnと同じく、実行中のCPUのキューに追加する
+    #[track_caller]
+    pub fn spawn<F>(self, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        let (task, handle) = self.build(future);
+        spawn_task(task);
+        handle
+    }
+
+    // Spawnerと同じく共有のキューに追加し、次にdrain_incomingしたCPUが受け取る
+    // TASKSのロックを取らないので、割り込みハンドラからも呼び出せる
+    #[track_caller]
+    pub fn spawn_shared<F>(self, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        let (task, handle) = self.build(future);
+        INCOMING.lock().push_back(task);
+        wake_idle_cpu();
+        handle
+    }
+
+    #[track_caller]
+    fn build<F>(self, future: F) -> (Task, JoinHandle<F::Output>)
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        let (mut task, handle) = join::task(future);
+        task.priority = self.priority;
+        task.name = self.name;
+        (task, handle)
+    }
+}
+
+// futureをタスクとして実行中のCPUのキューに追加し、結果を待つJoinHandleを返す
+// どのCPUからでも呼び出せるが、TASKSのロックを取るので割り込みハンドラからはSpawnerを使う
+#[track_caller]
+pub fn spawn<F>(future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    Builder::new().spawn(future)
+}
+
+#[track_caller]
+pub fn spawn_with_priority<F>(priority: Priority, future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    Builder::new().priority(priority).spawn(future)
+}
+
+fn spawn_task(mut task: Task) {
+    let task_id = task.id;
+    let priority = task.priority;
+    let entry = Arc::new(TaskEntry {
+        name: task.name.take(),
+        location: task.location,
+        task: Mutex::new(task),
+        waker: Arc::new(TaskWaker {
+            task_id,
+            priority,
+            cpu: AtomicUsize::new(percpu::cpu_id()),
+            queued: AtomicBool::new(true),
+            over_budget: AtomicBool::new(false),
+            busy_polls: AtomicU32::new(0),
+            polls: AtomicU64::new(0),
+            wakeups: AtomicU64::new(0),
+            busy_nanos: AtomicU64::new(0),
+            last_wakeup: AtomicU64::new(Instant::now().as_nanos()),
+        }),
+    });
+    if TASKS.lock().insert(task_id, entry).is_some() {
+        panic!("task with some ID already in tasks");
+        // 同じIDのタスクがすでにtasks内に存在
+    }
+    local_queue().lock().push(priority, task_id);
+    wake_idle_cpu();
+}
+
+// 眠っているCPUがあれば起こしてタスクを盗ませる
+fn wake_idle_cpu() {
+    if let Some(cpu) = percpu::cpus().find(|cpu| cpu.id() != percpu::cpu_id() && cpu.is_halted()) {
+        wake_cpu(cpu);
+    }
+}
+
+// Spawnerから追加されたタスクを実行中のCPUのキューに移す
+fn drain_incoming() {
+    // spawn_taskはTASKSのロックを取るので、1つずつ取り出してINCOMINGのロックを外してから呼ぶ
+    loop {
+        let task = INCOMING.lock().pop_front();
+        match task {
+            Some(task) => spawn_task(task),
+            None => break,
+        }
+    }
+}
+
+// 実行中のCPUでexecutorを動かす。BSPはExecutor::run、APはsmpの初期化の最後に呼び出す
+pub fn run() -> ! {
+    loop {
+        drain_incoming();
+        run_ready_tasks();
+        // APにはタイマー割り込みが無くスレッドが切り替わらないので、ここで実行待ちのスレッドに譲る
+        thread::yield_now();
+        sleep_unless(|| has_work() || thread::has_ready_threads());
+    }
+}
+
+// futureが完了するまで実行中のCPUでexecutorを動かし、futureの結果を返す
+// 待っている間は他のタスクを実行し、実行できるものが無ければhltで眠る
+// 起動時の初期化やテストで使う。タスクの中から呼び出すとそのタスクを止めてしまうのでパニックする
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    let cpu = percpu::current();
+    assert!(
+        cpu.current_task().is_none(),
+        "block_on called from inside a task"
+    );
+    futures_util::pin_mut!(future);
+    let waker = Arc::new(BlockOnWaker {
+        cpu: cpu.id(),
+        woken: AtomicBool::new(true),
+    });
+    let task_waker = Waker::from(waker.clone());
+    let mut context = Context::from_waker(&task_waker);
+    loop {
+        // poll中に起こされた場合はもう一度pollする必要があるので、pollの前に下ろす
+        if waker.woken.swap(false, Ordering::SeqCst) {
+            if let Poll::Ready(output) = future.as_mut().poll(&mut context) {
+                return output;
+            }
+        }
+        drain_incoming();
+        while !waker.woken.load(Ordering::SeqCst) {
+            if !run_next_task() {
+                break;
+            }
         }
+        sleep_unless(|| waker.woken.load(Ordering::SeqCst) || has_work());
     }
+}
 
-    pub fn spawn(&mut self, task: Task) {
-        let task_id = task.id;
-        if self.tasks.insert(task.id, task).is_some() {
-            panic!("task with some ID already in tasks");
-            // 同じIDのタスクがすでにtasks内に存在
+// 実行中のCPUのキューと他のCPUから盗めるタスクが無くなるまで実行中のCPUでexecutorを動かして戻る
+// 他のCPUでpoll中のタスクや、wakerが呼ばれるのを待っているタスクは待たない
+pub fn run_until_idle() {
+    assert!(
+        percpu::current().current_task().is_none(),
+        "run_until_idle called from inside a task"
+    );
+    loop {
+        drain_incoming();
+        if !run_next_task() {
+            break;
         }
-        self.task_queue.push(task_id).expect("queue full");
     }
+}
 
-    fn run_ready_tasks(&mut self) {
-        // 借用チェッカのエラーを回避する多面selfを分配する
-        let Self {
-            tasks,
-            task_queue,
-            waker_cache,
-        } = self;
+// タスクをexecutorから取り除き、futureを捨ててデストラクタを実行する
+// JoinHandleにはJoinError::Cancelledが返る。タスクがまだ残っていた場合はtrueを返す
+// 他のCPUでpoll中の場合は、pollが終わったところでそのCPUがfutureを捨てる
+pub fn abort(task_id: TaskId) -> bool {
+    let entry = TASKS.lock().remove(&task_id);
+    // futureのデストラクタがspawnやabortを呼んでもよいように、ロックを外してから捨てる
+    let removed = entry.is_some();
+    drop(entry);
+    removed
+}
 
-        while let Ok(task_id) = task_queue.pop() {
-            let task = match tasks.get_mut(&task_id) {
-                Some(task) => task,
-                None => continue, // タスクが存在しない
-            };
+// タスクのスケジューリングの統計
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TaskStats {
+    pub priority: Priority,
+    // pollされた回数
+    pub polls: u64,
+    // wakerが呼ばれた回数。キューに入っている間の重複した呼び出しも数える
+    pub wakeups: u64,
+    // pollにかかった時間の合計
+    pub busy: Duration,
+}
+
+pub fn task_stats(task_id: TaskId) -> Option<TaskStats> {
+    let entry = TASKS.lock().get(&task_id)?.clone();
+    Some(entry.waker.stats())
+}
+
+// executorに残っている全てのタスクの統計
+pub fn all_task_stats() -> Vec<(TaskId, TaskStats)> {
+    let entries: Vec<_> = TASKS
+        .lock()
+        .iter()
+        .map(|(&task_id, entry)| (task_id, entry.clone()))
+        .collect();
+    entries
+        .into_iter()
+        .map(|(task_id, entry)| (task_id, entry.waker.stats()))
+        .collect()
+}
 
-            let waker = waker_cache
-                .entry(task_id)
-                .or_insert_with(|| TaskWaker::new(task_id, task_queue.clone()));
-            let mut context = Context::from_waker(waker);
-            match task.poll(&mut context) {
-                Poll::Ready(()) => {
-                    // タスクが完了したのでタスクとそのキャッシュされたwakerを取り除く
-                    tasks.remove(&task_id);
-                    waker_cache.remove(&task_id);
-                }
-                Poll::Pending => {}
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TaskState {
+    // このCPUでpoll中
+    Running { cpu: usize },
+    // 起こされてキューに入っている
+    Queued,
+    // wakerが呼ばれるのを待っている
+    Waiting,
+}
+
+// タスクの一覧の1行
+#[derive(Debug, Clone)]
+pub struct TaskInfo {
+    pub id: TaskId,
+    pub name: Option<String>,
+    // タスクを作った場所
+    pub location: &'static Location<'static>,
+    pub state: TaskState,
+    pub stats: TaskStats,
+    // 最後に起こされてから、またはspawnされてからの時間
+    pub since_wakeup: Duration,
+}
+
+impl fmt::Display for TaskInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "task {:>4} {:<16} {:<6} {:<14} polls {:>8} last wakeup {:?} ago ({})",
+            self.id.as_u64(),
+            self.name.as_deref().unwrap_or("-"),
+            alloc::format!("{:?}", self.stats.priority),
+            alloc::format!("{:?}", self.state),
+            self.stats.polls,
+            self.since_wakeup,
+            self.location
+        )
+    }
+}
+
+// executorに残っている全てのタスクの状態
+pub fn tasks() -> Vec<TaskInfo> {
+    let entries: Vec<_> = TASKS
+        .lock()
+        .iter()
+        .map(|(&task_id, entry)| (task_id, entry.clone()))
+        .collect();
+    let now = Instant::now().as_nanos();
+    entries
+        .into_iter()
+        .map(|(task_id, entry)| {
+            let running_on = percpu::cpus().find(|cpu| cpu.current_task() == Some(task_id.0));
+            let state = match running_on {
+                Some(cpu) => TaskState::Running { cpu: cpu.id() },
+                None if entry.waker.queued.load(Ordering::SeqCst) => TaskState::Queued,
+                None => TaskState::Waiting,
+            };
+            let last_wakeup = entry.waker.last_wakeup.load(Ordering::Relaxed);
+            TaskInfo {
+                id: task_id,
+                name: entry.name.clone(),
+                location: entry.location,
+                state,
+                stats: entry.waker.stats(),
+                since_wakeup: Duration::from_nanos(now.saturating_sub(last_wakeup)),
             }
+        })
+        .collect()
+}
+
+// タスクの一覧をシリアルに出力する。止まっているように見えるときに、どのタスクが待っているかを調べる
+pub fn dump_tasks() {
+    let tasks = tasks();
+    serial_println!("{} tasks:", tasks.len());
+    for task in tasks {
+        serial_println!("  {}", task);
+    }
+}
+
+fn local_queue() -> &'static SpinLock<RunQueue> {
+    &percpu::current().task_queue
+}
+
+fn run_ready_tasks() {
+    while run_next_task() {}
+}
+
+// キューからタスクを1つ取り出してpollする。実行できるタスクが無ければfalseを返す
+fn run_next_task() -> bool {
+    let queue = local_queue();
+    // ロックを持ったままpollしないように、取り出す文でロックを外す
+    let next = queue.lock().pop();
+    let task_id = match next.or_else(steal) {
+        Some(task_id) => task_id,
+        None => return false,
+    };
+    let entry = match TASKS.lock().get(&task_id) {
+        Some(entry) => entry.clone(),
+        None => return true, // タスクが存在しない
+    };
+    // 他のCPUがpoll中の場合は、終わった後にそのCPUがもう一度pollするように、そのCPUのキューに戻す
+    // 自分のキューに戻すと、pollが終わるまで取り出しては戻すことを繰り返してしまう
+    let mut task = match entry.task.try_lock() {
+        Some(task) => task,
+        None => {
+            let poller = percpu::get(entry.waker.cpu.load(Ordering::Relaxed));
+            poller.task_queue.lock().push(entry.waker.priority, task_id);
+            return true;
         }
+    };
+    // 予算を使い切ったタスクは一度Lowのキューの後ろに回し、他のタスクを先に動かす
+    if entry.waker.over_budget.swap(false, Ordering::Relaxed) {
+        drop(task);
+        queue.lock().push(Priority::Low, task_id);
+        return true;
     }
 
-    pub fn run(&mut self) -> ! {
-        loop {
-            self.run_ready_tasks();
-            self.sleep_if_idle();
+    // 次に起こされたときはこのCPUのキューに入る
+    entry.waker.cpu.store(percpu::cpu_id(), Ordering::Relaxed);
+    // poll中に起こされた場合はもう一度pollする必要があるので、pollの前に下ろす
+    entry.waker.queued.store(false, Ordering::SeqCst);
+    let waker = Waker::from(entry.waker.clone());
+    let mut context = Context::from_waker(&waker);
+    let cpu = percpu::current();
+    cpu.set_current_task(Some(task_id.0));
+    let start = Instant::now();
+    let poll = unwind::catch(|| task.poll(&mut context));
+    entry.waker.record_poll(start.elapsed());
+    cpu.set_current_task(None);
+    match poll {
+        Ok(Poll::Ready(())) => {
+            drop(task);
+            // タスクが完了したのでタスクとそのwakerを取り除く
+            // futureはロックを外した後、entryと一緒に捨てる
+            let removed = TASKS.lock().remove(&task_id);
+            drop(removed);
+        }
+        Ok(Poll::Pending) => drop(task),
+        Err(payload) => {
+            // 他のCPUがentryを持っていても、futureはパニックしたこのCPUで捨てる
+            let future = task.take_future();
+            drop(task);
+            let removed = TASKS.lock().remove(&task_id);
+            serial_println!(
+                "task {} ({}) panicked: {}",
+                task_id.as_u64(),
+                entry.name.as_deref().unwrap_or("-"),
+                unwind::message(&payload)
+            );
+            FAILED_TASKS.fetch_add(1, Ordering::Relaxed);
+            // JoinHandleにJoinError::Panickedが返るように、パニックの処理を終える前に捨てる
+            drop(future);
+            drop(payload);
+            unwind::finish();
+            drop(removed);
         }
     }
+    true
+}
 
-    fn sleep_if_idle(&self) {
-        use x86_64::instructions::interrupts::{self, enable_and_hlt};
+// パニックして捨てられたタスクの数。unwind featureが無効な場合はいつも0
+pub fn failed_task_count() -> u64 {
+    FAILED_TASKS.load(Ordering::Relaxed)
+}
 
-        interrupts::disable();
-        if self.task_queue.is_empty() {
-            enable_and_hlt();
-        } else {
-            interrupts::enable();
-        }
+// 他のCPUのキューから半分のタスクを自分のキューに移し、1つを返す
+fn steal() -> Option<TaskId> {
+    let id = percpu::cpu_id();
+    let local = local_queue();
+    let mut cpus = percpu::cpus().skip_while(|cpu| cpu.id() <= id);
+    let mut wrapped = percpu::cpus().take_while(|cpu| cpu.id() < id);
+    let victims = core::iter::from_fn(|| cpus.next().or_else(|| wrapped.next()));
+
+    for victim in victims {
+        // 2つのCPUが互いに盗み合ってもデッドロックしないよう、相手のロックを外してから自分のキューに入れる
+        let mut stolen = victim.task_queue.lock().split_half();
+        let first = match stolen.pop() {
+            Some(task_id) => task_id,
+            None => continue,
+        };
+        local.lock().append(&mut stolen);
+        return Some(first);
+    }
+    None
+}
+
+fn has_work() -> bool {
+    !INCOMING.lock().is_empty() || percpu::cpus().any(|cpu| !cpu.task_queue.lock().is_empty())
+}
+
+// readyが偽のままなら、割り込みかIPIで起こされるまで眠る
+fn sleep_unless(ready: impl Fn() -> bool) {
+    let cpu = percpu::current();
+    interrupts::disable();
+    // 他のCPUはキューに追加した後でhaltedを見てIPIを送るので、確認より先に立てておく
+    cpu.set_halted(true);
+    if ready() {
+        cpu.set_halted(false);
+        interrupts::enable();
+    } else {
+        interrupts::enable_and_hlt();
+        cpu.set_halted(false);
     }
 }
 
+// 眠っているCPUをIPIで起こす
+fn wake_cpu(cpu: &percpu::PerCpu) {
+    ipi::send(
+        ipi::Destination::Cpu(cpu.apic_id()),
+        ipi::Ipi::Fixed(apic::WAKEUP_VECTOR),
+    );
+}
+
 struct TaskWaker {
     task_id: TaskId,
-    task_queue: Arc<ArrayQueue<TaskId>>,
+    // タスクを最後にpollしたCPU。起こしたときはこのCPUのキューに入れる
+    cpu: AtomicUsize,
+    // タスクがどれかのキューに入っているか。pollされるまでに何度起こされても1回だけキューに入れる
+    queued: AtomicBool,
+    priority: Priority,
+    // POLL_BUDGETを使い切った。次にキューから取り出したときはpollせずにLowのキューに回す
+    over_budget: AtomicBool,
+    // pollの間に自分を起こし続けた回数。pollしているCPUだけが更新する
+    busy_polls: AtomicU32,
+    polls: AtomicU64,
+    wakeups: AtomicU64,
+    busy_nanos: AtomicU64,
+    // 最後に起こされた時刻のInstant::as_nanos
+    last_wakeup: AtomicU64,
 }
 
 impl TaskWaker {
+    fn record_poll(&self, elapsed: Duration) {
+        self.polls.fetch_add(1, Ordering::Relaxed);
+        self.busy_nanos
+            .fetch_add(elapsed.as_nanos() as u64, Ordering::Relaxed);
+        // pollの間に起こされてすでにキューに入っていれば、休まずに動き続けている
+        if !self.queued.load(Ordering::SeqCst) {
+            self.busy_polls.store(0, Ordering::Relaxed);
+        } else if self.busy_polls.fetch_add(1, Ordering::Relaxed) + 1 >= POLL_BUDGET {
+            self.busy_polls.store(0, Ordering::Relaxed);
+            self.over_budget.store(true, Ordering::Relaxed);
+        }
+    }
+
+    fn stats(&self) -> TaskStats {
+        TaskStats {
+            priority: self.priority,
+            polls: self.polls.load(Ordering::Relaxed),
+            wakeups: self.wakeups.load(Ordering::Relaxed),
+            busy: Duration::from_nanos(self.busy_nanos.load(Ordering::Relaxed)),
+        }
+    }
+
     fn wake_task(&self) {
-        self.task_queue.push(self.task_id).expect("task_queue full");
+        self.wakeups.fetch_add(1, Ordering::Relaxed);
+        self.last_wakeup
+            .store(Instant::now().as_nanos(), Ordering::Relaxed);
+        if self.queued.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        let target = self.cpu.load(Ordering::Relaxed);
+        let cpu = percpu::cpus()
+            .find(|cpu| cpu.id() == target)
+            .unwrap_or_else(percpu::current);
+        cpu.task_queue.lock().push(self.priority, self.task_id);
+        if cpu.id() != percpu::cpu_id() && cpu.is_halted() {
+            wake_cpu(cpu);
+        }
     }
+}
 
-    fn new(task_id: TaskId, task_queue: Arc<ArrayQueue<TaskId>>) -> Waker {
-        Waker::from(Arc::new(TaskWaker {
-            task_id,
-            task_queue,
-        }))
+// block_onに渡したfutureのwaker。futureはキューに入らないので、フラグを立てて眠っているCPUを起こす
+struct BlockOnWaker {
+    cpu: usize,
+    woken: AtomicBool,
+}
+
+impl Wake for BlockOnWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.woken.store(true, Ordering::SeqCst);
+        if self.cpu == percpu::cpu_id() {
+            return;
+        }
+        if let Some(cpu) = percpu::cpus().find(|cpu| cpu.id() == self.cpu && cpu.is_halted()) {
+            wake_cpu(cpu);
+        }
     }
 }
 
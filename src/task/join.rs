@@ -0,0 +1,216 @@
+use super::{Task, TaskId};
+use crate::sync::SpinLock;
+use alloc::sync::Arc;
+use core::fmt;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
+
+// タスクが結果を返さずに終わった理由
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JoinError {
+    // タスクが完了する前に捨てられた
+    Cancelled,
+    // タスクの中でパニックした
+    // unwind featureが無効な場合はパニックするとカーネル全体が止まるので、このエラーが返ることはない
+    Panicked,
+}
+
+impl fmt::Display for JoinError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            JoinError::Cancelled => write!(f, "task was cancelled"),
+            JoinError::Panicked => write!(f, "task panicked"),
+        }
+    }
+}
+
+enum Stage<T> {
+    Running,
+    Finished(Result<T, JoinError>),
+    // JoinHandleが結果を受け取った
+    Taken,
+}
+
+struct JoinState<T> {
+    stage: Stage<T>,
+    // 結果を待っているJoinHandleのwaker
+    waker: Option<Waker>,
+    // JoinHandleが捨てられた後は結果を保存しない
+    detached: bool,
+}
+
+type Shared<T> = Arc<SpinLock<JoinState<T>>>;
+
+// タスクの結果を書き込む側。完了せずに捨てられた場合はキャンセルされたか、パニックしたことを書き込む
+struct Completion<T> {
+    state: Option<Shared<T>>,
+}
+
+impl<T> Completion<T> {
+    fn finish(&mut self, result: Result<T, JoinError>) {
+        let state = match self.state.take() {
+            Some(state) => state,
+            None => return,
+        };
+        let waker = {
+            let mut state = state.lock();
+            if !state.detached {
+                state.stage = Stage::Finished(result);
+            }
+            state.waker.take()
+        };
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+impl<T> Drop for Completion<T> {
+    fn drop(&mut self) {
+        let error = if super::unwind::is_panicking() {
+            JoinError::Panicked
+        } else {
+            JoinError::Cancelled
+        };
+        self.finish(Err(error));
+    }
+}
+
+// futureを実行するTaskと、その結果を受け取るJoinHandleを作る
+#[track_caller]
+pub(crate) fn task<F>(future: F) -> (Task, JoinHandle<F::Output>)
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    let state = Arc::new(SpinLock::new(JoinState {
+        stage: Stage::Running,
+        waker: None,
+        detached: false,
+    }));
+    let mut completion = Completion {
+        state: Some(state.clone()),
+    };
+    let task = Task::new(async move {
+        let output = future.await;
+        completion.finish(Ok(output));
+    });
+    let handle = JoinHandle { id: task.id, state };
+    (task, handle)
+}
+
+// spawnしたタスクの結果を待つfuture
+// 捨てるとタスクは切り離されて実行を続け、結果は捨てられる
+pub struct JoinHandle<T> {
+    id: TaskId,
+    state: Shared<T>,
+}
+
+impl<T> JoinHandle<T> {
+    pub fn id(&self) -> TaskId {
+        self.id
+    }
+
+    pub fn is_finished(&self) -> bool {
+        !matches!(self.state.lock().stage, Stage::Running)
+    }
+
+    // タスクを止める。このJoinHandleはJoinError::Cancelledを返す
+    pub fn abort(&self) {
+        self.abort_handle().abort();
+    }
+
+    // JoinHandleを手放した後もタスクを止められるようにする
+    pub fn abort_handle(&self) -> AbortHandle {
+        AbortHandle { id: self.id }
+    }
+
+    // 結果を待たずにタスクを実行させ続ける
+    pub fn detach(self) {
+        drop(self);
+    }
+}
+
+impl<T> Future for JoinHandle<T> {
+    type Output = Result<T, JoinError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<T, JoinError>> {
+        let mut state = self.state.lock();
+        match core::mem::replace(&mut state.stage, Stage::Taken) {
+            Stage::Finished(result) => Poll::Ready(result),
+            Stage::Running => {
+                state.stage = Stage::Running;
+                state.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+            Stage::Taken => panic!("JoinHandle polled after completion"),
+        }
+    }
+}
+
+impl<T> Drop for JoinHandle<T> {
+    fn drop(&mut self) {
+        // 結果はロックの外で捨てる。Tのdropが他のロックを取るかもしれないので
+        let stage = {
+            let mut state = self.state.lock();
+            state.detached = true;
+            state.waker = None;
+            core::mem::replace(&mut state.stage, Stage::Taken)
+        };
+        drop(stage);
+    }
+}
+
+// executorで実行中のタスクを止めるためのハンドル
+#[derive(Debug, Clone)]
+pub struct AbortHandle {
+    id: TaskId,
+}
+
+impl AbortHandle {
+    pub fn id(&self) -> TaskId {
+        self.id
+    }
+
+    // タスクがまだ残っていて止めた場合はtrueを返す
+    pub fn abort(&self) -> bool {
+        super::executor::abort(self.id)
+    }
+}
+
+impl<T> fmt::Debug for JoinHandle<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("JoinHandle").field("id", &self.id).finish()
+    }
+}
+
+#[test_case]
+fn test_join_handle_returns_output() {
+    use super::simple_executor::SimpleExecutor;
+    use core::sync::atomic::{AtomicU64, Ordering};
+
+    static RESULT: AtomicU64 = AtomicU64::new(0);
+    let (task, handle) = task(async { 42u64 });
+    let mut executor = SimpleExecutor::new();
+    executor.spawn(Task::new(async move {
+        RESULT.store(handle.await.expect("task failed"), Ordering::SeqCst);
+    }));
+    executor.spawn(task);
+    executor.run();
+    assert_eq!(RESULT.load(Ordering::SeqCst), 42);
+}
+
+#[test_case]
+fn test_dropped_task_is_cancelled() {
+    let (task, mut handle) = task(async { 1 });
+    assert!(!handle.is_finished());
+    drop(task);
+    assert!(handle.is_finished());
+    let waker = super::simple_executor::dummy_waker();
+    let mut context = Context::from_waker(&waker);
+    assert_eq!(
+        Pin::new(&mut handle).poll(&mut context),
+        Poll::Ready(Err(JoinError::Cancelled))
+    );
+}
@@ -1,22 +1,26 @@
 use core::{
     pin::Pin,
+    sync::atomic::{AtomicBool, Ordering},
     task::{Context, Poll},
 };
 
+use super::executor;
 use crate::{print, println};
 use conquer_once::spin::OnceCell;
 use crossbeam_queue::ArrayQueue;
 use futures_util::stream::{Stream, StreamExt};
 use futures_util::task::AtomicWaker;
-use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
+use pc_keyboard::{layouts, DecodedKey, HandleControl, KeyCode, Keyboard, ScancodeSet1};
 
 static SCANCODE_QUEUE: OnceCell<ArrayQueue<u8>> = OnceCell::uninit();
 static WAKER: AtomicWaker = AtomicWaker::new();
+// ScancodeStreamが存在するか。読み出す側は1つだけ
+static STREAM_OPEN: AtomicBool = AtomicBool::new(false);
 
 // キーボード割り込みハンドラから呼び出される
 pub(crate) fn add_scancode(scancode: u8) {
     if let Ok(queue) = SCANCODE_QUEUE.try_get() {
-        if let Err(_) = queue.push(scancode) {
+        if queue.push(scancode).is_err() {
             println!("WARNING: scancode queue full; dropping keyboard input");
             // スキャンコードキューがいっぱいのため、キーボード入力を取りこぼしています
         } else {
@@ -33,14 +37,27 @@ pub struct ScancodeStream {
 }
 
 impl ScancodeStream {
+    // 同時に1つしか作れないので、Defaultは用意しない
+    #[allow(clippy::new_without_default)]
     pub fn new() -> Self {
-        SCANCODE_QUEUE
-            .try_init_once(|| ArrayQueue::new(100))
-            .expect("ScancodeStream::new should only be called once");
+        assert!(
+            !STREAM_OPEN.swap(true, Ordering::SeqCst),
+            "only one ScancodeStream may exist at a time"
+        );
+        // キューは最初のストリームで作り、後のストリームでは作り直さない
+        let _ = SCANCODE_QUEUE.try_init_once(|| ArrayQueue::new(100));
         ScancodeStream { _private: () }
     }
 }
 
+// print_keyboardのタスクが止められた後に、また新しいストリームを作れるようにする
+impl Drop for ScancodeStream {
+    fn drop(&mut self) {
+        WAKER.take();
+        STREAM_OPEN.store(false, Ordering::SeqCst);
+    }
+}
+
 impl Stream for ScancodeStream {
     type Item = u8;
 
@@ -71,6 +88,8 @@ pub async fn print_keyboard() {
             if let Some(key) = keyboard.process_keyevent(key_event) {
                 match key {
                     DecodedKey::Unicode(character) => print!("{}", character),
+                    // F12でexecutorのタスクの一覧をシリアルに出力する
+                    DecodedKey::RawKey(KeyCode::F12) => executor::dump_tasks(),
                     DecodedKey::RawKey(key) => print!("{:?}", key),
                 }
             }
@@ -1,24 +1,58 @@
 use alloc::boxed::Box;
+use alloc::string::String;
 use core::{
     future::Future,
+    panic::Location,
     pin::Pin,
     sync::atomic::{AtomicU64, Ordering},
     task::{Context, Poll},
 };
 
+pub mod channel;
+pub mod combinator;
 pub mod executor;
+pub mod join;
 pub mod keyboard;
+pub(crate) mod run_queue;
 pub mod simple_executor;
+pub mod timer;
+pub mod unwind;
+
+// executorがタスクを選ぶときの優先度のクラス
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    // 割り込みで起こされるI/Oのタスクなど、すぐに応答させたいもの
+    High,
+    #[default]
+    Normal,
+    // 他に仕事が無いときに進めればよいもの
+    Low,
+}
+
+impl Priority {
+    pub(crate) const COUNT: usize = 3;
+}
+
 
 pub struct Task {
     id: TaskId,
-    future: Pin<Box<dyn Future<Output = ()>>>,
+    priority: Priority,
+    // executorのタスクの一覧に表示する名前。executor::Builderで付ける
+    name: Option<String>,
+    // タスクを作った場所。名前が無くてもどのタスクか分かるようにする
+    location: &'static Location<'static>,
+    // 他のCPUに盗まれて実行されることがあるのでSendにする
+    future: Pin<Box<dyn Future<Output = ()> + Send>>,
 }
 
 impl Task {
-    pub fn new(future: impl Future<Output = ()> + 'static) -> Self {
+    #[track_caller]
+    pub fn new(future: impl Future<Output = ()> + Send + 'static) -> Self {
         Task {
             id: TaskId::new(),
+            priority: Priority::default(),
+            name: None,
+            location: Location::caller(),
             future: Box::pin(future),
         }
     }
@@ -26,14 +60,23 @@ impl Task {
     fn poll(&mut self, context: &mut Context) -> Poll<()> {
         self.future.as_mut().poll(context)
     }
+
+    // パニックしたタスクのfutureを取り出す。残ったTaskはpollするとすぐに完了する
+    fn take_future(&mut self) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+        core::mem::replace(&mut self.future, Box::pin(core::future::ready(())))
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
-struct TaskId(u64);
+pub struct TaskId(u64);
 
 impl TaskId {
     fn new() -> Self {
         static NEXT_ID: AtomicU64 = AtomicU64::new(0);
         TaskId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
     }
+
+    pub fn as_u64(&self) -> u64 {
+        self.0
+    }
 }
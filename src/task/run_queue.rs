@@ -0,0 +1,111 @@
+use super::{Priority, TaskId};
+use alloc::collections::VecDeque;
+
+// 上のクラスから続けてこの回数取り出したら、下のクラスのタスクを1つ取り出す
+const STARVATION_LIMIT: usize = 8;
+
+// CPUごとの実行待ちのタスク。優先度のクラスごとにFIFOを持つ
+pub(crate) struct RunQueue {
+    queues: [VecDeque<TaskId>; Priority::COUNT],
+    // 下のクラスに待っているタスクがあるのに上のクラスから取り出した回数
+    streak: usize,
+}
+
+impl RunQueue {
+    pub(crate) const fn new() -> Self {
+        RunQueue {
+            queues: [VecDeque::new(), VecDeque::new(), VecDeque::new()],
+            streak: 0,
+        }
+    }
+
+    pub(crate) fn push(&mut self, priority: Priority, task_id: TaskId) {
+        self.queues[priority as usize].push_back(task_id);
+    }
+
+    // 基本は優先度の高いクラスから取り出すが、下のクラスが飢えないように時々そちらを先にする
+    pub(crate) fn pop(&mut self) -> Option<TaskId> {
+        let highest = self.queues.iter().position(|queue| !queue.is_empty())?;
+        let lowest = self.queues.iter().rposition(|queue| !queue.is_empty())?;
+        if highest == lowest {
+            self.streak = 0;
+            return self.queues[highest].pop_front();
+        }
+        if self.streak >= STARVATION_LIMIT {
+            self.streak = 0;
+            return self.queues[lowest].pop_front();
+        }
+        self.streak += 1;
+        self.queues[highest].pop_front()
+    }
+
+    #[cfg(test)]
+    pub(crate) fn len(&self) -> usize {
+        self.queues.iter().map(VecDeque::len).sum()
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.queues.iter().all(VecDeque::is_empty)
+    }
+
+    // 各クラスの後ろ半分を取り出す。他のCPUが盗むときに使う
+    pub(crate) fn split_half(&mut self) -> RunQueue {
+        let mut stolen = RunQueue::new();
+        for (queue, stolen_queue) in self.queues.iter_mut().zip(stolen.queues.iter_mut()) {
+            let len = queue.len();
+            *stolen_queue = queue.split_off(len / 2);
+        }
+        stolen
+    }
+
+    pub(crate) fn append(&mut self, other: &mut RunQueue) {
+        for (queue, other_queue) in self.queues.iter_mut().zip(other.queues.iter_mut()) {
+            queue.append(other_queue);
+        }
+    }
+}
+
+#[test_case]
+fn test_higher_priority_first() {
+    let mut queue = RunQueue::new();
+    queue.push(Priority::Low, TaskId(1));
+    queue.push(Priority::High, TaskId(2));
+    queue.push(Priority::Normal, TaskId(3));
+    assert_eq!(queue.pop(), Some(TaskId(2)));
+    assert_eq!(queue.pop(), Some(TaskId(3)));
+    assert_eq!(queue.pop(), Some(TaskId(1)));
+    assert_eq!(queue.pop(), None);
+}
+
+#[test_case]
+fn test_low_priority_is_not_starved() {
+    let mut queue = RunQueue::new();
+    queue.push(Priority::Low, TaskId(0));
+    for i in 1..=STARVATION_LIMIT as u64 + 1 {
+        queue.push(Priority::High, TaskId(i));
+    }
+    let position = core::iter::from_fn(|| queue.pop())
+        .position(|task_id| task_id == TaskId(0))
+        .expect("low priority task was not popped");
+    assert_eq!(position, STARVATION_LIMIT);
+}
+
+#[test_case]
+fn test_split_half_takes_back_of_each_class() {
+    let mut queue = RunQueue::new();
+    for i in 0..4 {
+        queue.push(Priority::Normal, TaskId(i));
+    }
+    queue.push(Priority::Low, TaskId(4));
+    queue.push(Priority::Low, TaskId(5));
+
+    let mut stolen = queue.split_half();
+    assert_eq!((queue.len(), stolen.len()), (3, 3));
+    assert_eq!(stolen.pop(), Some(TaskId(2)));
+    assert_eq!(stolen.pop(), Some(TaskId(3)));
+    assert_eq!(stolen.pop(), Some(TaskId(5)));
+
+    queue.append(&mut stolen);
+    assert!(stolen.is_empty());
+    assert_eq!(queue.len(), 3);
+}
@@ -0,0 +1,281 @@
+use crate::sync::SpinLock;
+use crate::time;
+use alloc::boxed::Box;
+use alloc::collections::{BTreeSet, BinaryHeap};
+use core::{
+    cmp::{Ordering, Reverse},
+    fmt,
+    future::Future,
+    pin::Pin,
+    sync::atomic::{self, AtomicU64},
+    task::{Context, Poll, Waker},
+    time::Duration,
+};
+use futures_util::stream::Stream;
+use lazy_static::lazy_static;
+
+// 期限（tick）の早い順に取り出せるタイマーのヒープ
+lazy_static! {
+    static ref TIMERS: SpinLock<Timers> = SpinLock::new(Timers::new());
+}
+
+struct Timers {
+    heap: BinaryHeap<Reverse<TimerEntry>>,
+    // 取り消されたがheapに残っているタイマーのID。取り出すときに読み飛ばす
+    cancelled: BTreeSet<u64>,
+}
+
+impl Timers {
+    fn new() -> Self {
+        Timers {
+            heap: BinaryHeap::new(),
+            cancelled: BTreeSet::new(),
+        }
+    }
+
+    // 取り消されていないタイマーの数
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        self.heap.len().saturating_sub(self.cancelled.len())
+    }
+
+    fn cancel(&mut self, id: u64) {
+        self.cancelled.insert(id);
+        // 期限の来ないタイマーが残り続けないよう、取り消した分が半分を超えたらまとめて取り除く
+        if self.cancelled.len() * 2 > self.heap.len() {
+            let cancelled = core::mem::take(&mut self.cancelled);
+            let mut entries = core::mem::take(&mut self.heap).into_vec();
+            entries.retain(|Reverse(entry)| !cancelled.contains(&entry.id));
+            self.heap = BinaryHeap::from(entries);
+        }
+    }
+
+    // 期限を迎えたタイマーを1つ取り出す
+    fn pop_expired(&mut self, now: u64) -> Option<TimerEntry> {
+        while let Some(Reverse(entry)) = self.heap.peek() {
+            if entry.deadline > now {
+                return None;
+            }
+            let Reverse(entry) = self.heap.pop()?;
+            if !self.cancelled.remove(&entry.id) {
+                return Some(entry);
+            }
+        }
+        None
+    }
+}
+
+struct TimerEntry {
+    deadline: u64,
+    id: u64,
+    waker: Waker,
+}
+
+impl PartialEq for TimerEntry {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for TimerEntry {}
+
+impl PartialOrd for TimerEntry {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for TimerEntry {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.deadline, self.id).cmp(&(other.deadline, other.id))
+    }
+}
+
+// 期限になったらwakerを起こすようにタイマーを登録し、取り消すためのIDを返す
+fn register(deadline: u64, waker: Waker) -> u64 {
+    static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+    let id = NEXT_ID.fetch_add(1, atomic::Ordering::Relaxed);
+
+    // タイマー割り込みハンドラも同じロックを取るが、SpinLockは持っている間割り込みを止める
+    TIMERS.lock().heap.push(Reverse(TimerEntry {
+        deadline,
+        id,
+        waker,
+    }));
+    id
+}
+
+// 登録したタイマーを取り消す。期限まで待たずに捨てられたSleepが残り続けないようにする
+fn cancel(id: u64) {
+    TIMERS.lock().cancel(id);
+}
+
+// タイマー割り込みハンドラから呼び出され、期限を迎えたタイマーのwakerを起こす
+// wakerがタイマーを登録し直してもデッドロックしないよう、ロックを外してから起こす
+pub(crate) fn wake_expired(now: u64) {
+    loop {
+        let entry = match TIMERS.lock().pop_expired(now) {
+            Some(entry) => entry,
+            None => break,
+        };
+        entry.waker.wake();
+    }
+}
+
+// 指定されたtickを過ぎたら完了するFuture
+pub struct Sleep {
+    deadline: u64,
+    waker: Option<Waker>,
+    // 登録したタイマーのID
+    timer_id: Option<u64>,
+}
+
+impl Sleep {
+    fn until(deadline: u64) -> Self {
+        Sleep {
+            deadline,
+            waker: None,
+            timer_id: None,
+        }
+    }
+
+    pub fn deadline(&self) -> u64 {
+        self.deadline
+    }
+
+    pub fn is_elapsed(&self) -> bool {
+        time::ticks() >= self.deadline
+    }
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if self.is_elapsed() {
+            return Poll::Ready(());
+        }
+
+        // 同じwakerで既に登録済みなら再登録しない
+        let registered = matches!(&self.waker, Some(waker) if waker.will_wake(cx.waker()));
+        if !registered {
+            // 前のwakerで登録したタイマーは不要になる
+            if let Some(id) = self.timer_id.take() {
+                cancel(id);
+            }
+            self.waker = Some(cx.waker().clone());
+            self.timer_id = Some(register(self.deadline, cx.waker().clone()));
+        }
+
+        // 登録中に期限を過ぎた場合に起こし損ねないよう再確認する
+        if self.is_elapsed() {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+// 期限を過ぎたタイマーはタイマー割り込みハンドラが取り除く
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        if let Some(id) = self.timer_id {
+            if !self.is_elapsed() {
+                cancel(id);
+            }
+        }
+    }
+}
+
+// 指定された時間が経過するまで待つ。Duration::MAXのように長すぎる場合は期限が来ない
+pub fn sleep(duration: Duration) -> Sleep {
+    Sleep::until(time::ticks().saturating_add(time::duration_to_ticks(duration)))
+}
+
+// timeoutの期限までにfutureが完了しなかった
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Elapsed;
+
+impl fmt::Display for Elapsed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "deadline has elapsed")
+    }
+}
+
+// futureが期限までに完了しなければElapsedを返すFuture。期限を過ぎたらfutureは捨てる
+pub struct Timeout<F: Future> {
+    future: Pin<Box<F>>,
+    sleep: Sleep,
+}
+
+impl<F: Future> Future for Timeout<F> {
+    type Output = Result<F::Output, Elapsed>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<F::Output, Elapsed>> {
+        // 期限と同時に完了した場合は結果を返す
+        if let Poll::Ready(output) = self.future.as_mut().poll(cx) {
+            return Poll::Ready(Ok(output));
+        }
+        match Pin::new(&mut self.sleep).poll(cx) {
+            Poll::Ready(()) => Poll::Ready(Err(Elapsed)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+// futureをdurationが経過するまで待つ
+pub fn timeout<F: Future>(future: F, duration: Duration) -> Timeout<F> {
+    Timeout {
+        future: Box::pin(future),
+        sleep: sleep(duration),
+    }
+}
+
+// 一定の間隔で値を返すStream
+pub struct Interval {
+    period: u64,
+    sleep: Sleep,
+}
+
+impl Stream for Interval {
+    type Item = u64;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<u64>> {
+        if Pin::new(&mut self.sleep).poll(cx).is_pending() {
+            return Poll::Pending;
+        }
+
+        let fired = self.sleep.deadline;
+        // 処理が遅れて期限を過ぎてしまった分はまとめてスキップする
+        let now = time::ticks();
+        let mut next = fired.saturating_add(self.period);
+        if next <= now {
+            next = now.saturating_add(self.period);
+        }
+        self.sleep = Sleep::until(next);
+        Poll::Ready(Some(fired))
+    }
+}
+
+// 最初の値はすぐに返し、その後periodごとに値を返す
+pub fn interval(period: Duration) -> Interval {
+    let period = time::duration_to_ticks(period);
+    assert!(period > 0, "interval period must be non-zero");
+    Interval {
+        period,
+        sleep: Sleep::until(time::ticks()),
+    }
+}
+
+#[test_case]
+fn test_dropped_sleep_is_unregistered() {
+    let timers = || TIMERS.lock().len();
+    let before = timers();
+    let mut sleep = sleep(Duration::MAX);
+    let waker = super::simple_executor::dummy_waker();
+    let mut context = Context::from_waker(&waker);
+    assert_eq!(Pin::new(&mut sleep).poll(&mut context), Poll::Pending);
+    assert_eq!(timers(), before + 1);
+    drop(sleep);
+    assert_eq!(timers(), before);
+}
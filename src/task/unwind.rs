@@ -0,0 +1,71 @@
+// タスクの中のパニックを、そのタスクだけの失敗にする（unwind featureが有効な場合のみ）
+// パニックハンドラからunwind_task_panicを呼び出すと、poll中のタスクのスタックをexecutorまで巻き戻す
+// 巻き戻す間にスタック上のガードが捨てられ、持っていたロックは外れる
+// featureが無効な場合やタスクの外でのパニックは、これまでどおりパニックハンドラがカーネルを止める
+use crate::percpu;
+use alloc::boxed::Box;
+use core::any::Any;
+use core::panic::PanicInfo;
+
+// パニックを捕まえたときに渡される値。unwind_task_panicはパニックの表示をStringにして渡す
+pub(crate) type Payload = Box<dyn Any + Send>;
+
+pub fn is_enabled() -> bool {
+    cfg!(feature = "unwind")
+}
+
+// パニックハンドラの最初に呼び出す。poll中のタスクのパニックならexecutorまで巻き戻して戻らない
+// 巻き戻せない場合はそのまま戻るので、呼び出し側はいつもどおりカーネルを止める
+pub fn unwind_task_panic(info: &PanicInfo) {
+    #[cfg(feature = "unwind")]
+    {
+        let cpu = match percpu::try_current() {
+            Some(cpu) => cpu,
+            None => return,
+        };
+        // 割り込みハンドラのスタックフレームは巻き戻せない
+        if cpu.current_task().is_none() || cpu.in_interrupt() {
+            return;
+        }
+        // タスクを捨てている途中のパニックは巻き戻さない
+        if cpu.task_panicking() {
+            return;
+        }
+        cpu.set_task_panicking(true);
+        let message = alloc::format!("{}", info);
+        // catchが見つからない場合はデストラクタを呼ばずに戻ってくる
+        // 例えばpoll中に切り替わった別のスレッドでパニックした場合
+        let _ = unwinding::panic::begin_panic(Box::new(message));
+        cpu.set_task_panicking(false);
+    }
+    #[cfg(not(feature = "unwind"))]
+    let _ = info;
+}
+
+// fを実行し、その中で巻き戻されたパニックを捕まえる
+#[cfg(feature = "unwind")]
+pub(crate) fn catch<R>(f: impl FnOnce() -> R) -> Result<R, Payload> {
+    unwinding::panic::catch_unwind(f)
+}
+
+#[cfg(not(feature = "unwind"))]
+pub(crate) fn catch<R>(f: impl FnOnce() -> R) -> Result<R, Payload> {
+    Ok(f())
+}
+
+// 失敗したタスクを捨てている途中か。JoinHandleにJoinError::Panickedを返すのに使う
+pub(crate) fn is_panicking() -> bool {
+    percpu::try_current().is_some_and(|cpu| cpu.task_panicking())
+}
+
+// 失敗したタスクを捨て終わったときに呼び出す
+pub(crate) fn finish() {
+    percpu::current().set_task_panicking(false);
+}
+
+pub(crate) fn message(payload: &Payload) -> &str {
+    payload
+        .downcast_ref::<alloc::string::String>()
+        .map(|message| message.as_str())
+        .unwrap_or("unknown panic")
+}
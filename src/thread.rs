@@ -0,0 +1,556 @@
+use alloc::{boxed::Box, collections::BTreeMap, sync::Arc, vec, vec::Vec};
+use core::arch::global_asm;
+use core::marker::PhantomData;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+use crate::sync::{SpinLock, SpinLockGuard};
+use crate::{apic, gdt, ipi, memory, percpu, syscall, time, usermode};
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::PhysFrame;
+use x86_64::VirtAddr;
+
+// カーネルスレッドのスタックサイズ
+pub const THREAD_STACK_SIZE: usize = 4096 * 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ThreadId(u64);
+
+impl ThreadId {
+    fn new() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        ThreadId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub(crate) fn from_u64(id: u64) -> Self {
+        ThreadId(id)
+    }
+
+    pub fn as_u64(&self) -> u64 {
+        self.0
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThreadState {
+    Ready,
+    Running,
+    // 指定されたtickまで眠っている
+    Sleeping(u64),
+    // 他のスレッドの終了を待っている
+    Blocked,
+    Finished,
+}
+
+struct Thread {
+    id: ThreadId,
+    state: ThreadState,
+    // スレッドを作ったCPU。スレッドは他のCPUに移らず、起こすときもこのCPUの実行待ちキューに入れる
+    cpu: usize,
+    // switch_contextで保存したスタックポインタ
+    saved_rsp: u64,
+    // スレッドのスタック。saved_rspが指しているので、スレッドが終わるまで確保したままにしておく
+    // 起動時のスレッドはブートローダのスタックを使うためNone
+    _stack: Option<Vec<u8>>,
+    entry: Option<Box<dyn FnOnce() + Send>>,
+    joiners: Vec<ThreadId>,
+    detached: bool,
+    // ユーザーモードから割り込みやシステムコールで入るときのスタック
+    trap_stack: Option<Vec<u8>>,
+    // ユーザーモードを終了したときの戻り先（usermodeのUSER_RETURN_RSP）
+    user_return_rsp: u64,
+    // 切り替えたときの割り込みハンドラの入れ子の深さ（percpuのinterrupt_depth）
+    // タイマー割り込みで切り替わったスレッドはハンドラの中で止まっているので1以上になる
+    interrupt_depth: u32,
+    // ユーザープロセスのレベル4テーブル。カーネルスレッドはNone
+    address_space: Option<PhysFrame>,
+}
+
+impl Thread {
+    fn trap_stack_top(&self) -> Option<VirtAddr> {
+        let stack = self.trap_stack.as_ref()?;
+        Some(VirtAddr::from_ptr(stack.as_ptr()) + stack.len())
+    }
+}
+
+// 実行中のスレッド、アイドルスレッド、実行待ちのキューはpercpuのデータに置く
+struct Scheduler {
+    // 保存したスタックポインタのアドレスが変わらないようにBoxに入れる
+    threads: BTreeMap<ThreadId, Box<Thread>>,
+}
+
+// SpinLockは外したときに割り込みを元に戻すので、switch_contextの前に割り込みが有効にならないよう
+// スケジューラのロックは割り込みを無効にした状態で取る
+static SCHEDULER: SpinLock<Option<Scheduler>> = SpinLock::new(None);
+
+extern "C" {
+    fn switch_context(old_rsp: *mut u64, new_rsp: u64);
+}
+
+// callee-savedレジスタとRFLAGSを現在のスタックに保存して別のスレッドのスタックに切り替える
+global_asm!(
+    ".global switch_context",
+    "switch_context:",
+    "push rbp",
+    "push rbx",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    "pushfq",
+    "mov [rdi], rsp",
+    "mov rsp, rsi",
+    "popfq",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop rbx",
+    "pop rbp",
+    "ret",
+);
+
+// 起動時に実行しているコードをスレッドとして登録し、アイドルスレッドを作る
+// ヒープの初期化後に各CPUで1度ずつ呼び出す
+pub fn init() {
+    let boot = Box::new(Thread {
+        id: ThreadId::new(),
+        state: ThreadState::Running,
+        cpu: percpu::cpu_id(),
+        saved_rsp: 0,
+        _stack: None,
+        entry: None,
+        joiners: Vec::new(),
+        trap_stack: None,
+        user_return_rsp: 0,
+        interrupt_depth: 0,
+        address_space: None,
+        detached: true,
+    });
+    let idle = new_thread(Box::new(idle_loop));
+    let cpu = percpu::current();
+    cpu.set_current_thread(boot.id);
+    cpu.set_idle_thread(idle.id);
+
+    interrupts::without_interrupts(|| {
+        let mut guard = SCHEDULER.lock();
+        let scheduler = guard.get_or_insert_with(|| Scheduler {
+            threads: BTreeMap::new(),
+        });
+        scheduler.threads.insert(boot.id, boot);
+        scheduler.threads.insert(idle.id, idle);
+    });
+}
+
+// APにはタイマー割り込みが無いので、起こされたら自分で実行待ちのスレッドに切り替える
+fn idle_loop() {
+    loop {
+        interrupts::disable();
+        if has_ready_threads() {
+            interrupts::enable();
+        } else {
+            interrupts::enable_and_hlt();
+        }
+        yield_now();
+    }
+}
+
+// 最初にswitch_contextで切り替えたときにthread_trampolineへ戻るようなスタックを作る
+fn new_thread(entry: Box<dyn FnOnce() + Send>) -> Box<Thread> {
+    let mut stack = vec![0u8; THREAD_STACK_SIZE];
+    let top = (stack.as_mut_ptr() as u64 + THREAD_STACK_SIZE as u64) & !0xf;
+    let initial: [u64; 9] = [
+        0x2, // RFLAGS（割り込み無効）
+        0,   // r15
+        0,   // r14
+        0,   // r13
+        0,   // r12
+        0,   // rbx
+        0,   // rbp
+        thread_trampoline as *const () as u64,
+        0, // thread_trampolineのリターンアドレス（戻らない）
+    ];
+    let saved_rsp = top - (initial.len() * 8) as u64;
+    unsafe {
+        core::ptr::copy_nonoverlapping(initial.as_ptr(), saved_rsp as *mut u64, initial.len());
+    }
+
+    Box::new(Thread {
+        id: ThreadId::new(),
+        state: ThreadState::Ready,
+        cpu: percpu::cpu_id(),
+        saved_rsp,
+        _stack: Some(stack),
+        entry: Some(entry),
+        joiners: Vec::new(),
+        trap_stack: None,
+        user_return_rsp: 0,
+        interrupt_depth: 0,
+        address_space: None,
+        detached: false,
+    })
+}
+
+extern "C" fn thread_trampoline() -> ! {
+    let entry = interrupts::without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        let scheduler = scheduler.as_mut().expect("scheduler not initialized");
+        let current = percpu::current().current_thread()?;
+        scheduler
+            .threads
+            .get_mut(&current)
+            .and_then(|thread| thread.entry.take())
+    });
+    interrupts::enable();
+    if let Some(entry) = entry {
+        entry();
+    }
+    exit();
+}
+
+// 次に実行するスレッドを選んでコンテキストを切り替える。割り込みを無効にした状態で呼び出す
+fn schedule(scheduler: &mut Option<Scheduler>) -> Option<(*mut u64, u64)> {
+    let scheduler = scheduler.as_mut()?;
+    let cpu = percpu::current();
+
+    // 眠っているスレッドのうち時間になったものを実行可能にする
+    // タイマー割り込みはBSPにしか来ないので、他のCPUのスレッドもここで起こす
+    let now = time::ticks();
+    for thread in scheduler.threads.values_mut() {
+        if let ThreadState::Sleeping(deadline) = thread.state {
+            if deadline <= now {
+                make_ready(thread);
+            }
+        }
+    }
+
+    let mut ready = cpu.run_queue.lock();
+
+    let current_id = cpu.current_thread()?;
+    let idle_id = cpu.idle_thread()?;
+    let current_state = scheduler.threads[&current_id].state;
+    let next_id = match ready.pop_front() {
+        Some(id) => id,
+        // 実行中のスレッドが続けられるならそのまま、そうでなければアイドルスレッドに切り替える
+        None if current_state == ThreadState::Running => return None,
+        None => idle_id,
+    };
+    if next_id == current_id {
+        return None;
+    }
+
+    if current_state == ThreadState::Running {
+        scheduler.threads.get_mut(&current_id)?.state = ThreadState::Ready;
+        if current_id != idle_id {
+            ready.push_back(current_id);
+        }
+    }
+    let current = scheduler.threads.get_mut(&current_id)?;
+    current.user_return_rsp = usermode::user_return_rsp();
+    current.interrupt_depth = cpu.interrupt_depth();
+    let old_rsp = &mut current.saved_rsp as *mut u64;
+
+    let next = scheduler.threads.get_mut(&next_id)?;
+    next.state = ThreadState::Running;
+    unsafe { switch_user_context(next) };
+    // 切り替え先は自分が止まったときの深さから続ける。新しいスレッドは割り込みハンドラの外から始まる
+    cpu.set_interrupt_depth(next.interrupt_depth);
+    let new_rsp = next.saved_rsp;
+    cpu.set_current_thread(next_id);
+
+    Some((old_rsp, new_rsp))
+}
+
+// スレッドを持ち主のCPUの実行待ちキューに入れる。スケジューラのロックを取った状態で呼び出す
+// 他のCPUはタイマー割り込みで切り替えないので、IPIで起こして切り替えさせる
+fn make_ready(thread: &mut Thread) {
+    thread.state = ThreadState::Ready;
+    let owner = percpu::get(thread.cpu);
+    owner.run_queue.lock().push_back(thread.id);
+    if thread.cpu != percpu::cpu_id() {
+        ipi::send(
+            ipi::Destination::Cpu(owner.apic_id()),
+            ipi::Ipi::Fixed(apic::WAKEUP_VECTOR),
+        );
+    }
+}
+
+// 実行中のCPUに実行を待っているスレッドがあるか
+pub(crate) fn has_ready_threads() -> bool {
+    !percpu::current().run_queue.lock().is_empty()
+}
+
+// 切り替え先のスレッドのアドレス空間、ring 0のスタック、ユーザーモードの戻り先を設定する
+unsafe fn switch_user_context(next: &Thread) {
+    let level_4_frame = next
+        .address_space
+        .unwrap_or_else(memory::kernel_level_4_frame);
+    let (current_frame, flags) = Cr3::read();
+    if current_frame != level_4_frame {
+        Cr3::write(level_4_frame, flags);
+    }
+
+    let trap_stack_top = next.trap_stack_top();
+    gdt::set_kernel_stack(trap_stack_top.unwrap_or_else(gdt::default_kernel_stack));
+    syscall::set_kernel_stack(trap_stack_top.unwrap_or_else(syscall::default_kernel_stack));
+    usermode::set_user_return_rsp(next.user_return_rsp);
+}
+
+// ロックを解放してからスレッドを切り替える
+fn switch(mut guard: SpinLockGuard<Option<Scheduler>>) {
+    let switch_to = schedule(&mut guard);
+    drop(guard);
+    if let Some((old_rsp, new_rsp)) = switch_to {
+        unsafe { switch_context(old_rsp, new_rsp) };
+    }
+}
+
+// タイマー割り込みハンドラからEOIを送った後に呼び出され、実行中のスレッドを切り替える
+pub(crate) fn preempt() {
+    if percpu::try_current().is_some_and(|cpu| !cpu.preemptible()) {
+        return;
+    }
+    // 割り込みハンドラ内なので、ロックが取れない場合（初期化中など）は何もしない
+    if let Some(guard) = SCHEDULER.try_lock() {
+        switch(guard);
+    }
+}
+
+// 持っている間は、このCPUで実行中のスレッドをタイマー割り込みで切り替えない
+// 他のスレッドが割り込みを止めたまま取ろうとするロック（ヒープのアロケータなど）を持ったまま
+// 切り替わると、そのスレッドが回り続けて元のスレッドに戻らずデッドロックする
+pub(crate) struct PreemptGuard {
+    // 取ったCPUで外さなければならないのでSendにしない
+    _not_send: PhantomData<*mut ()>,
+}
+
+pub(crate) fn disable_preemption() -> PreemptGuard {
+    if let Some(cpu) = percpu::try_current() {
+        cpu.disable_preemption();
+    }
+    PreemptGuard {
+        _not_send: PhantomData,
+    }
+}
+
+impl Drop for PreemptGuard {
+    fn drop(&mut self) {
+        if let Some(cpu) = percpu::try_current() {
+            cpu.enable_preemption();
+        }
+    }
+}
+
+// 他のスレッドに実行を譲る
+pub fn yield_now() {
+    interrupts::without_interrupts(|| switch(SCHEDULER.lock()));
+}
+
+// 指定された時間が経過するまで実行を止める
+pub fn sleep(duration: Duration) {
+    let deadline = time::ticks() + time::duration_to_ticks(duration);
+    interrupts::without_interrupts(|| {
+        let mut guard = SCHEDULER.lock();
+        if let Some(scheduler) = guard.as_mut() {
+            let current = current_id();
+            if let Some(thread) = scheduler.threads.get_mut(&current) {
+                thread.state = ThreadState::Sleeping(deadline);
+            }
+        }
+        switch(guard);
+    });
+    // スケジューラの初期化前やスレッドが1つしかない場合は割り込みを待つ
+    while time::ticks() < deadline {
+        x86_64::instructions::hlt();
+    }
+}
+
+// 実行中のスレッドにユーザーモード用のスタックを用意し、TSSとSYSCALLのスタックに設定する
+// 複数のスレッドがユーザーモードを使っても割り込み時のスタックが衝突しないようにする
+pub fn prepare_user_mode() {
+    let has_trap_stack = interrupts::without_interrupts(|| {
+        let guard = SCHEDULER.lock();
+        match guard.as_ref() {
+            Some(scheduler) => scheduler.threads[&current_id()].trap_stack.is_some(),
+            // スケジューラの初期化前は静的なスタックをそのまま使う
+            None => true,
+        }
+    });
+    if has_trap_stack {
+        return;
+    }
+
+    let stack = vec![0u8; THREAD_STACK_SIZE];
+    interrupts::without_interrupts(|| {
+        let mut guard = SCHEDULER.lock();
+        if let Some(scheduler) = guard.as_mut() {
+            let current = current_id();
+            if let Some(thread) = scheduler.threads.get_mut(&current) {
+                thread.trap_stack = Some(stack);
+                unsafe { switch_user_context(thread) };
+            }
+        }
+    });
+}
+
+// 実行中のスレッドのアドレス空間を切り替える。Noneの場合はカーネルのアドレス空間に戻す
+pub fn set_address_space(level_4_frame: Option<PhysFrame>) {
+    interrupts::without_interrupts(|| {
+        let mut guard = SCHEDULER.lock();
+        if let Some(scheduler) = guard.as_mut() {
+            let current = current_id();
+            if let Some(thread) = scheduler.threads.get_mut(&current) {
+                thread.address_space = level_4_frame;
+            }
+        }
+        let level_4_frame = level_4_frame.unwrap_or_else(memory::kernel_level_4_frame);
+        let (current_frame, flags) = Cr3::read();
+        if current_frame != level_4_frame {
+            unsafe { Cr3::write(level_4_frame, flags) };
+        }
+    });
+}
+
+pub fn current() -> Option<ThreadId> {
+    percpu::current().current_thread()
+}
+
+// スケジューラの初期化後は必ず実行中のスレッドがある
+fn current_id() -> ThreadId {
+    current().expect("thread::init has not been called")
+}
+
+// 実行中のスレッドを終了し、終了を待っているスレッドを起こす
+pub fn exit() -> ! {
+    interrupts::disable();
+    let mut guard = SCHEDULER.lock();
+    if let Some(scheduler) = guard.as_mut() {
+        let current = current_id();
+        let joiners = match scheduler.threads.get_mut(&current) {
+            Some(thread) => {
+                thread.state = ThreadState::Finished;
+                core::mem::take(&mut thread.joiners)
+            }
+            None => Vec::new(),
+        };
+        for joiner in joiners {
+            if let Some(thread) = scheduler.threads.get_mut(&joiner) {
+                if thread.state == ThreadState::Blocked {
+                    make_ready(thread);
+                }
+            }
+        }
+    }
+    switch(guard);
+    unreachable!("finished thread was scheduled again");
+}
+
+// 新しいカーネルスレッドを作って実行可能にする
+pub fn spawn<F, T>(f: F) -> JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let result = Arc::new(Mutex::new(None));
+    let slot = result.clone();
+    let thread = new_thread(Box::new(move || {
+        let value = f();
+        interrupts::without_interrupts(|| *slot.lock() = Some(value));
+    }));
+    let id = thread.id;
+
+    // 終了して誰も待っていないスレッドを片付ける
+    // 割り込みハンドラ内でメモリを解放しないようにここで行う
+    let finished = interrupts::without_interrupts(|| {
+        let mut guard = SCHEDULER.lock();
+        let scheduler = guard.as_mut().expect("thread::init has not been called");
+        let current = current_id();
+        let finished: Vec<Box<Thread>> = {
+            let ids: Vec<ThreadId> = scheduler
+                .threads
+                .values()
+                .filter(|t| t.state == ThreadState::Finished && t.detached && t.id != current)
+                .map(|t| t.id)
+                .collect();
+            ids.iter()
+                .filter_map(|id| scheduler.threads.remove(id))
+                .collect()
+        };
+
+        scheduler.threads.insert(id, thread);
+        // 割り込みハンドラ内でready queueが再確保されないよう、全スレッド分の容量を確保しておく
+        let mut ready = percpu::current().run_queue.lock();
+        let additional = scheduler.threads.len().saturating_sub(ready.len());
+        ready.reserve(additional);
+        ready.push_back(id);
+        finished
+    });
+    drop(finished);
+
+    JoinHandle { id, result }
+}
+
+// スレッドの終了を待って結果を受け取るためのハンドル
+pub struct JoinHandle<T> {
+    id: ThreadId,
+    result: Arc<Mutex<Option<T>>>,
+}
+
+impl<T> JoinHandle<T> {
+    pub fn id(&self) -> ThreadId {
+        self.id
+    }
+
+    pub fn is_finished(&self) -> bool {
+        interrupts::without_interrupts(|| {
+            SCHEDULER
+                .lock()
+                .as_ref()
+                .and_then(|s| s.threads.get(&self.id).map(|t| t.state))
+                .is_none_or(|state| state == ThreadState::Finished)
+        })
+    }
+
+    // スレッドが終了するまで待ち、戻り値を返す
+    // スレッドがパニックなどで値を返さずに終了した場合はNone
+    pub fn join(self) -> Option<T> {
+        loop {
+            let finished = interrupts::without_interrupts(|| {
+                let mut guard = SCHEDULER.lock();
+                let scheduler = guard.as_mut()?;
+                let current = current_id();
+                let target = scheduler.threads.get_mut(&self.id)?;
+                if target.state == ThreadState::Finished {
+                    // メモリの解放はロックの外で行う
+                    return scheduler.threads.remove(&self.id);
+                }
+                target.joiners.push(current);
+                if let Some(thread) = scheduler.threads.get_mut(&current) {
+                    thread.state = ThreadState::Blocked;
+                }
+                switch(guard);
+                None
+            });
+            if finished.is_some() || self.is_finished() {
+                break;
+            }
+        }
+        let result = interrupts::without_interrupts(|| self.result.lock().take());
+        result
+    }
+}
+
+impl<T> Drop for JoinHandle<T> {
+    // joinされなかったスレッドは終了後にspawnで片付ける
+    fn drop(&mut self) {
+        interrupts::without_interrupts(|| {
+            if let Some(scheduler) = SCHEDULER.lock().as_mut() {
+                if let Some(thread) = scheduler.threads.get_mut(&self.id) {
+                    thread.detached = true;
+                }
+            }
+        });
+    }
+}
@@ -0,0 +1,167 @@
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::time::Duration;
+use x86_64::structures::paging::{FrameAllocator, Mapper, Size4KiB};
+
+pub mod hpet;
+pub mod pit;
+pub mod rtc;
+pub mod tsc;
+
+pub use rtc::DateTime;
+
+// タイマー割り込みの周波数（1秒あたりの割り込み回数）
+pub const TIMER_HZ: u64 = 100;
+
+// 起動してからのタイマー割り込みの回数
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+// タイマー割り込みを発生させているハードウェア
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClockSource {
+    Pit,
+    Hpet,
+}
+
+static HPET_CLOCK: AtomicBool = AtomicBool::new(false);
+
+// 起動時にRTCから読んだUNIX時刻と、その時点のtick数
+static BOOT_UNIX_TIME: AtomicU64 = AtomicU64::new(0);
+static BOOT_TICKS: AtomicU64 = AtomicU64::new(0);
+
+pub fn init() {
+    pit::set_frequency(TIMER_HZ as u32);
+    tsc::calibrate();
+
+    BOOT_UNIX_TIME.store(rtc::read().to_unix_timestamp(), Ordering::Relaxed);
+    BOOT_TICKS.store(ticks(), Ordering::Relaxed);
+}
+
+// HPETが使える場合はPITの代わりにタイマー割り込みの発生源にする
+// レガシー置き換えルーティングでRTCのIRQ8もHPETに移るが、rtc::enable_periodicはそれに合わせてHPETを使う
+// メモリのマップが必要なためヒープの初期化後に呼び出す
+pub fn init_hpet(
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> ClockSource {
+    match hpet::init(mapper, frame_allocator) {
+        Ok(()) => {
+            hpet::start_periodic(TIMER_HZ);
+            HPET_CLOCK.store(true, Ordering::Relaxed);
+            // PITより正確なHPETで校正し直す。それまでのInstantからの続きで数える
+            tsc::calibrate();
+        }
+        Err(err) => {
+            crate::println!("HPET unavailable ({:?}); using PIT", err);
+        }
+    }
+    clock_source()
+}
+
+pub fn clock_source() -> ClockSource {
+    if HPET_CLOCK.load(Ordering::Relaxed) {
+        ClockSource::Hpet
+    } else {
+        ClockSource::Pit
+    }
+}
+
+// タイマー割り込みハンドラから呼び出される
+pub(crate) fn tick() {
+    let now = TICKS.fetch_add(1, Ordering::Relaxed) + 1;
+    crate::task::timer::wake_expired(now);
+}
+
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+// 起動してからの経過時間
+pub fn uptime() -> Duration {
+    ticks_to_duration(ticks())
+}
+
+// RTCから読んだ起動時の時刻に単調増加するtick数を足して現在の日時を求める
+pub fn wall_clock() -> DateTime {
+    let elapsed = ticks_to_duration(ticks() - BOOT_TICKS.load(Ordering::Relaxed));
+    DateTime::from_unix_timestamp(BOOT_UNIX_TIME.load(Ordering::Relaxed) + elapsed.as_secs())
+}
+
+// 指定された時間以上経過するのに必要なtick数を返す（切り上げ）
+pub fn duration_to_ticks(duration: Duration) -> u64 {
+    let nanos_per_tick = 1_000_000_000 / TIMER_HZ as u128;
+    let ticks = duration.as_nanos().div_ceil(nanos_per_tick);
+    ticks.min(u64::MAX as u128) as u64
+}
+
+pub fn ticks_to_duration(ticks: u64) -> Duration {
+    Duration::from_nanos(ticks.saturating_mul(1_000_000_000 / TIMER_HZ))
+}
+
+// 起動してからの時間をナノ秒単位で表す時刻
+// TSC、HPETのメインカウンタの順に使えるものを使い、どちらも使えない場合はtimer割り込みの精度になる
+// ロックを取らないので割り込みハンドラからも使える
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Instant(u64);
+
+impl Instant {
+    pub fn now() -> Self {
+        if let Some(nanos) = tsc::to_nanos(tsc::read()) {
+            Instant(nanos)
+        } else if hpet::is_enabled() {
+            Instant(hpet::nanos())
+        } else {
+            Instant(uptime().as_nanos() as u64)
+        }
+    }
+
+    pub fn as_nanos(&self) -> u64 {
+        self.0
+    }
+
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        Duration::from_nanos(self.0.saturating_sub(earlier.0))
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        Instant::now().duration_since(*self)
+    }
+}
+
+impl core::ops::Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, duration: Duration) -> Instant {
+        Instant(self.0.saturating_add(duration.as_nanos() as u64))
+    }
+}
+
+// 処理にかかった時間を計測する
+pub fn measure<T>(f: impl FnOnce() -> T) -> (T, Duration) {
+    let start = Instant::now();
+    let result = f();
+    (result, start.elapsed())
+}
+
+#[test_case]
+fn test_instant_is_monotonic() {
+    let first = Instant::now();
+    let second = Instant::now();
+    assert!(second >= first);
+    assert!(first.elapsed() >= second.duration_since(first));
+}
+
+#[test_case]
+fn test_instant_does_not_go_back_after_recalibration() {
+    let before = Instant::now();
+    tsc::calibrate();
+    assert!(Instant::now() >= before);
+}
+
+#[test_case]
+fn test_duration_to_ticks_rounds_up() {
+    assert_eq!(duration_to_ticks(Duration::from_millis(0)), 0);
+    assert_eq!(duration_to_ticks(Duration::from_millis(1)), 1);
+    assert_eq!(duration_to_ticks(Duration::from_millis(10)), 1);
+    assert_eq!(duration_to_ticks(Duration::from_millis(11)), 2);
+    assert_eq!(duration_to_ticks(Duration::from_secs(1)), TIMER_HZ);
+}
@@ -0,0 +1,201 @@
+use crate::acpi::{self, SdtHeader};
+use crate::memory;
+use core::ptr;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+use x86_64::structures::paging::{FrameAllocator, Mapper, Size4KiB};
+use x86_64::PhysAddr;
+
+// ACPIのHPETテーブル
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+struct HpetTable {
+    header: SdtHeader,
+    event_timer_block_id: u32,
+    address_space_id: u8,
+    register_bit_width: u8,
+    register_bit_offset: u8,
+    reserved: u8,
+    base_address: u64,
+    hpet_number: u8,
+    minimum_tick: u16,
+    page_protection: u8,
+}
+
+// レジスタのオフセット
+const GENERAL_CAPABILITIES: u64 = 0x000;
+const GENERAL_CONFIGURATION: u64 = 0x010;
+const MAIN_COUNTER: u64 = 0x0f0;
+
+const fn timer_configuration(timer: u8) -> u64 {
+    0x100 + 0x20 * timer as u64
+}
+
+const fn timer_comparator(timer: u8) -> u64 {
+    0x108 + 0x20 * timer as u64
+}
+
+// GENERAL_CONFIGURATIONのビット
+const ENABLE_CNF: u64 = 1 << 0;
+// タイマー0をIRQ0, タイマー1をIRQ8に接続する（PITとRTCを置き換える）
+// 有効にするとPITとRTCの割り込みは届かなくなるため、RTCの周期割り込みはタイマー1で代わりに発生させる
+const LEG_RT_CNF: u64 = 1 << 1;
+const LEG_RT_CAP: u64 = 1 << 15;
+
+// タイマー設定レジスタのビット
+const TN_INT_ENB_CNF: u64 = 1 << 2;
+const TN_TYPE_CNF: u64 = 1 << 3;
+const TN_PER_INT_CAP: u64 = 1 << 4;
+const TN_VAL_SET_CNF: u64 = 1 << 6;
+
+// マップ済みのレジスタの仮想アドレス、未初期化の場合は0
+static BASE: AtomicU64 = AtomicU64::new(0);
+// メインカウンタの周期（フェムト秒）
+static PERIOD_FS: AtomicU64 = AtomicU64::new(0);
+// メインカウンタを0から動かし始めたときの起動してからの時間（ナノ秒）
+// それまでのInstantより戻らないよう、カウンタから求めた時間に足す
+static START_NANOS: AtomicU64 = AtomicU64::new(0);
+
+const FEMTOS_PER_SEC: u64 = 1_000_000_000_000_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HpetError {
+    // ACPIにHPETテーブルが存在しない
+    NotPresent,
+    // レジスタをマップできなかった
+    MapFailed,
+    // レガシー置き換えルーティングに対応していない
+    NoLegacyReplacement,
+    // 使おうとしたタイマーが周期モードに対応していない
+    NoPeriodicTimer,
+}
+
+unsafe fn read(offset: u64) -> u64 {
+    ptr::read_volatile((BASE.load(Ordering::Relaxed) + offset) as *const u64)
+}
+
+unsafe fn write(offset: u64, value: u64) {
+    ptr::write_volatile((BASE.load(Ordering::Relaxed) + offset) as *mut u64, value)
+}
+
+pub fn is_enabled() -> bool {
+    BASE.load(Ordering::Relaxed) != 0
+}
+
+// ACPIのHPETテーブルからHPETを探してレジスタをマップし、メインカウンタを動かす
+pub fn init(
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), HpetError> {
+    let table_addr = acpi::find_table(b"HPET").ok_or(HpetError::NotPresent)?;
+    let table: HpetTable = unsafe { acpi::read_table(table_addr) };
+
+    let base = memory::map_mmio(
+        PhysAddr::new(table.base_address),
+        1024,
+        mapper,
+        frame_allocator,
+    )
+    .map_err(|_| HpetError::MapFailed)?;
+    BASE.store(base.as_u64(), Ordering::Relaxed);
+
+    let capabilities = unsafe { read(GENERAL_CAPABILITIES) };
+    if capabilities & LEG_RT_CAP == 0 {
+        BASE.store(0, Ordering::Relaxed);
+        return Err(HpetError::NoLegacyReplacement);
+    }
+    if unsafe { read(timer_configuration(0)) } & TN_PER_INT_CAP == 0 {
+        BASE.store(0, Ordering::Relaxed);
+        return Err(HpetError::NoPeriodicTimer);
+    }
+    PERIOD_FS.store(capabilities >> 32, Ordering::Relaxed);
+
+    unsafe {
+        write(
+            GENERAL_CONFIGURATION,
+            read(GENERAL_CONFIGURATION) & !ENABLE_CNF,
+        );
+        write(MAIN_COUNTER, 0);
+        START_NANOS.store(crate::time::uptime().as_nanos() as u64, Ordering::Relaxed);
+        write(
+            GENERAL_CONFIGURATION,
+            read(GENERAL_CONFIGURATION) | ENABLE_CNF,
+        );
+    }
+    Ok(())
+}
+
+// メインカウンタの周波数 (Hz)
+pub fn frequency() -> u64 {
+    FEMTOS_PER_SEC / PERIOD_FS.load(Ordering::Relaxed).max(1)
+}
+
+pub fn counter() -> u64 {
+    unsafe { read(MAIN_COUNTER) }
+}
+
+// メインカウンタから求めた起動してからの時間（ナノ秒）
+pub fn nanos() -> u64 {
+    START_NANOS.load(Ordering::Relaxed) + counts_to_nanos(counter())
+}
+
+// レガシー置き換えルーティングでIRQ0とIRQ8がHPETに接続されているか
+pub fn legacy_routing() -> bool {
+    is_enabled() && unsafe { read(GENERAL_CONFIGURATION) } & LEG_RT_CNF != 0
+}
+
+pub fn duration_to_counts(duration: Duration) -> u64 {
+    (duration.as_nanos() * 1_000_000 / PERIOD_FS.load(Ordering::Relaxed).max(1) as u128) as u64
+}
+
+pub fn counts_to_nanos(counts: u64) -> u64 {
+    (counts as u128 * PERIOD_FS.load(Ordering::Relaxed) as u128 / 1_000_000) as u64
+}
+
+// タイマー0をhzの周期割り込みに設定し、レガシー置き換えルーティングでIRQ0に接続する
+// 以降はPITの代わりにHPETがタイマー割り込みを発生させる。RTCのIRQ8もタイマー1に置き換わる
+pub fn start_periodic(hz: u64) {
+    unsafe { start_timer_periodic(0, hz) };
+}
+
+// タイマー1をhzの周期割り込みに設定する。レガシー置き換えルーティング中はIRQ8に届く
+// IRQ8を使えなくなったRTCの周期割り込みの代わりに使う
+pub fn start_irq8_periodic(hz: u64) -> Result<(), HpetError> {
+    if unsafe { read(timer_configuration(1)) } & TN_PER_INT_CAP == 0 {
+        return Err(HpetError::NoPeriodicTimer);
+    }
+    unsafe { start_timer_periodic(1, hz) };
+    Ok(())
+}
+
+unsafe fn start_timer_periodic(timer: u8, hz: u64) {
+    let period = frequency() / hz.max(1);
+    write(
+        GENERAL_CONFIGURATION,
+        read(GENERAL_CONFIGURATION) & !ENABLE_CNF,
+    );
+    let config = read(timer_configuration(timer));
+    write(
+        timer_configuration(timer),
+        config | TN_INT_ENB_CNF | TN_TYPE_CNF | TN_VAL_SET_CNF,
+    );
+    // VAL_SET_CNFを立てた後の1回目の書き込みはコンパレータ、2回目は周期になる
+    write(timer_comparator(timer), read(MAIN_COUNTER) + period);
+    write(timer_comparator(timer), period);
+    write(
+        GENERAL_CONFIGURATION,
+        read(GENERAL_CONFIGURATION) | LEG_RT_CNF | ENABLE_CNF,
+    );
+}
+
+// メインカウンタでms待ち、開始時と終了時にreadを呼び出した結果を返す
+pub fn measure<T>(ms: u32, mut read: impl FnMut() -> T) -> (T, T) {
+    let counts = duration_to_counts(Duration::from_millis(ms as u64));
+    let start_counter = counter();
+    let start = read();
+    while counter().wrapping_sub(start_counter) < counts {
+        core::hint::spin_loop();
+    }
+    let end = read();
+    (start, end)
+}
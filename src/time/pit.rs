@@ -0,0 +1,58 @@
+use x86_64::instructions::port::Port;
+
+// PITの入力クロック周波数 (Hz)
+pub const PIT_FREQUENCY: u32 = 1_193_182;
+
+const CHANNEL_0_PORT: u16 = 0x40;
+const CHANNEL_2_PORT: u16 = 0x42;
+const COMMAND_PORT: u16 = 0x43;
+// bit 0: チャンネル2のゲート, bit 1: スピーカー, bit 5: チャンネル2の出力
+const CHANNEL_2_GATE_PORT: u16 = 0x61;
+
+// チャンネル0, lobyte/hibyteアクセス, モード3（矩形波）, バイナリ
+const CHANNEL_0_SQUARE_WAVE: u8 = 0b0011_0110;
+// チャンネル2, lobyte/hibyteアクセス, モード0（カウント終了で出力がHIGH）, バイナリ
+const CHANNEL_2_ONESHOT: u8 = 0b1011_0000;
+
+// チャンネル0の割り込み周波数を設定する
+pub fn set_frequency(hz: u32) {
+    let divisor = (PIT_FREQUENCY / hz).clamp(1, u16::MAX as u32) as u16;
+
+    let mut command: Port<u8> = Port::new(COMMAND_PORT);
+    let mut channel_0: Port<u8> = Port::new(CHANNEL_0_PORT);
+    unsafe {
+        command.write(CHANNEL_0_SQUARE_WAVE);
+        channel_0.write((divisor & 0xff) as u8);
+        channel_0.write((divisor >> 8) as u8);
+    }
+}
+
+// チャンネル2をワンショットで動かし、開始時と終了時にreadを呼び出した結果を返す
+// 他のクロックの校正に使う（最大約54ms）
+pub fn measure_oneshot<T>(ms: u32, mut read: impl FnMut() -> T) -> (T, T) {
+    let count = (PIT_FREQUENCY as u64 * ms as u64 / 1000).clamp(1, u16::MAX as u64) as u16;
+
+    let mut command: Port<u8> = Port::new(COMMAND_PORT);
+    let mut channel_2: Port<u8> = Port::new(CHANNEL_2_PORT);
+    let mut gate: Port<u8> = Port::new(CHANNEL_2_GATE_PORT);
+    unsafe {
+        // スピーカーを切ってゲートをLOWにする
+        let value = gate.read() & !0b11;
+        gate.write(value);
+
+        command.write(CHANNEL_2_ONESHOT);
+        channel_2.write((count & 0xff) as u8);
+        channel_2.write((count >> 8) as u8);
+
+        // ゲートをHIGHにしてカウントを開始する
+        gate.write(value | 0b01);
+        let start = read();
+        while gate.read() & 0b10_0000 == 0 {
+            core::hint::spin_loop();
+        }
+        let end = read();
+
+        gate.write(value);
+        (start, end)
+    }
+}
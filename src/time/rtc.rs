@@ -0,0 +1,278 @@
+use super::hpet;
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::instructions::port::Port;
+
+const CMOS_ADDRESS: u16 = 0x70;
+const CMOS_DATA: u16 = 0x71;
+// アドレスポートのbit 7を立てるとNMIが無効になる
+const NMI_DISABLE: u8 = 0x80;
+
+const REG_SECONDS: u8 = 0x00;
+const REG_MINUTES: u8 = 0x02;
+const REG_HOURS: u8 = 0x04;
+const REG_DAY: u8 = 0x07;
+const REG_MONTH: u8 = 0x08;
+const REG_YEAR: u8 = 0x09;
+const REG_STATUS_A: u8 = 0x0a;
+const REG_STATUS_B: u8 = 0x0b;
+const REG_STATUS_C: u8 = 0x0c;
+const REG_STATUS_D: u8 = 0x0d;
+
+// Status Aのビット
+const UPDATE_IN_PROGRESS: u8 = 1 << 7;
+// Status Bのビット
+const HOUR_24: u8 = 1 << 1;
+const BINARY_MODE: u8 = 1 << 2;
+const PERIODIC_INTERRUPT: u8 = 1 << 6;
+// 12時間表示の場合の時のレジスタのPMビット
+const HOUR_PM: u8 = 1 << 7;
+
+// RTCのIRQ番号
+pub const RTC_IRQ: u8 = 8;
+
+// RTCの周期割り込みの回数
+static PERIODIC_TICKS: AtomicU64 = AtomicU64::new(0);
+
+// アドレスポートとデータポートの間に割り込みハンドラがアドレスを書き換えないよう、割り込みを止めて読み書きする
+// 読み書きした後はNMIを有効に戻し、アドレスは読んでも副作用の無いStatus Dに向けておく
+fn read_register(reg: u8) -> u8 {
+    let mut address: Port<u8> = Port::new(CMOS_ADDRESS);
+    let mut data: Port<u8> = Port::new(CMOS_DATA);
+    without_interrupts(|| unsafe {
+        address.write(NMI_DISABLE | reg);
+        let value = data.read();
+        address.write(REG_STATUS_D);
+        value
+    })
+}
+
+fn write_register(reg: u8, value: u8) {
+    let mut address: Port<u8> = Port::new(CMOS_ADDRESS);
+    let mut data: Port<u8> = Port::new(CMOS_DATA);
+    without_interrupts(|| unsafe {
+        address.write(NMI_DISABLE | reg);
+        data.write(value);
+        address.write(REG_STATUS_D);
+    })
+}
+
+fn update_in_progress() -> bool {
+    read_register(REG_STATUS_A) & UPDATE_IN_PROGRESS != 0
+}
+
+fn bcd_to_binary(value: u8) -> u8 {
+    (value & 0x0f) + (value >> 4) * 10
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct RawTime {
+    second: u8,
+    minute: u8,
+    hour: u8,
+    day: u8,
+    month: u8,
+    year: u8,
+}
+
+fn read_raw() -> RawTime {
+    while update_in_progress() {
+        core::hint::spin_loop();
+    }
+    RawTime {
+        second: read_register(REG_SECONDS),
+        minute: read_register(REG_MINUTES),
+        hour: read_register(REG_HOURS),
+        day: read_register(REG_DAY),
+        month: read_register(REG_MONTH),
+        year: read_register(REG_YEAR),
+    }
+}
+
+// 日付と時刻 (UTC)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    // 1970-01-01 00:00:00からの秒数に変換する
+    pub fn to_unix_timestamp(&self) -> u64 {
+        let days = days_from_civil(self.year as i64, self.month as i64, self.day as i64);
+        days as u64 * 86400 + self.hour as u64 * 3600 + self.minute as u64 * 60 + self.second as u64
+    }
+
+    pub fn from_unix_timestamp(timestamp: u64) -> Self {
+        let (year, month, day) = civil_from_days((timestamp / 86400) as i64);
+        let seconds = timestamp % 86400;
+        DateTime {
+            year: year as u16,
+            month: month as u8,
+            day: day as u8,
+            hour: (seconds / 3600) as u8,
+            minute: (seconds / 60 % 60) as u8,
+            second: (seconds % 60) as u8,
+        }
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+// 1970-01-01からの日数を求める (Howard Hinnantのアルゴリズム)
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+// CMOSから現在の日時を読む
+// 更新中に読むと値が壊れるため、2回続けて同じ値が読めるまで繰り返す
+pub fn read() -> DateTime {
+    let mut raw = read_raw();
+    loop {
+        let again = read_raw();
+        if again == raw {
+            break;
+        }
+        raw = again;
+    }
+
+    let status_b = read_register(REG_STATUS_B);
+    let pm = raw.hour & HOUR_PM != 0;
+    let mut hour = raw.hour & !HOUR_PM;
+    let convert = |value: u8| {
+        if status_b & BINARY_MODE != 0 {
+            value
+        } else {
+            bcd_to_binary(value)
+        }
+    };
+    hour = convert(hour);
+    if status_b & HOUR_24 == 0 {
+        // 12時間表示では12時が0時/12時を表す
+        hour %= 12;
+        if pm {
+            hour += 12;
+        }
+    }
+
+    DateTime {
+        // CMOSのcenturyレジスタは存在が保証されないため2000年代とする
+        year: 2000 + convert(raw.year) as u16,
+        month: convert(raw.month),
+        day: convert(raw.day),
+        hour,
+        minute: convert(raw.minute),
+        second: convert(raw.second),
+    }
+}
+
+// RTCの周期割り込みを有効にする。周波数は 32768 >> (rate - 1) Hz (rateは3..=15)
+// HPETのレガシー置き換えルーティングが有効な場合はIRQ8がHPETに使われてRTCの割り込みが届かないため、
+// 同じ周波数でHPETのタイマー1を動かす
+pub fn enable_periodic(rate: u8) -> Result<(), hpet::HpetError> {
+    let rate = rate.clamp(3, 15);
+    if hpet::legacy_routing() {
+        hpet::start_irq8_periodic(32768 >> (rate - 1))?;
+    } else {
+        without_interrupts(|| {
+            let status_a = read_register(REG_STATUS_A);
+            write_register(REG_STATUS_A, (status_a & 0xf0) | rate);
+            let status_b = read_register(REG_STATUS_B);
+            write_register(REG_STATUS_B, status_b | PERIODIC_INTERRUPT);
+            // 前回の割り込みを確認済みにしないと次の割り込みが来ない
+            read_register(REG_STATUS_C);
+        });
+    }
+    crate::interrupts::unmask_irq(RTC_IRQ);
+    Ok(())
+}
+
+// RTC割り込みハンドラから呼び出される
+pub(crate) fn handle_interrupt() {
+    // Status Cを読んで割り込みを確認済みにする
+    read_register(REG_STATUS_C);
+    PERIODIC_TICKS.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn periodic_ticks() -> u64 {
+    PERIODIC_TICKS.load(Ordering::Relaxed)
+}
+
+#[test_case]
+fn test_unix_timestamp_round_trip() {
+    let epoch = DateTime::from_unix_timestamp(0);
+    assert_eq!(
+        epoch,
+        DateTime {
+            year: 1970,
+            month: 1,
+            day: 1,
+            hour: 0,
+            minute: 0,
+            second: 0
+        }
+    );
+
+    // 2024-02-29 12:34:56 UTC
+    let leap_day = DateTime::from_unix_timestamp(1_709_210_096);
+    assert_eq!((leap_day.year, leap_day.month, leap_day.day), (2024, 2, 29));
+    assert_eq!(
+        (leap_day.hour, leap_day.minute, leap_day.second),
+        (12, 34, 56)
+    );
+    assert_eq!(leap_day.to_unix_timestamp(), 1_709_210_096);
+}
+
+#[test_case]
+fn test_bcd_to_binary() {
+    assert_eq!(bcd_to_binary(0x00), 0);
+    assert_eq!(bcd_to_binary(0x59), 59);
+    assert_eq!(bcd_to_binary(0x12), 12);
+}
+
+#[test_case]
+fn test_periodic_interrupt_advances_ticks() {
+    // 64Hz
+    enable_periodic(10).expect("periodic interrupt unavailable");
+    let start = periodic_ticks();
+    let deadline = super::ticks() + super::TIMER_HZ;
+    while periodic_ticks() < start + 2 {
+        assert!(
+            super::ticks() < deadline,
+            "RTC periodic interrupt did not fire"
+        );
+        x86_64::instructions::hlt();
+    }
+}
@@ -0,0 +1,65 @@
+use super::{hpet, pit};
+use core::arch::x86_64::{__cpuid, _rdtsc};
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::instructions::interrupts;
+
+// 校正済みのTSCの周波数 (Hz)、未校正の場合は0
+static TSC_HZ: AtomicU64 = AtomicU64::new(0);
+// 最後に校正したときのTSCの値と、そのときのInstantの値（ナノ秒）
+// 校正し直しても、それまでに返したInstantより戻らないようにこの時点から数える
+static BASE_TSC: AtomicU64 = AtomicU64::new(0);
+static BASE_NANOS: AtomicU64 = AtomicU64::new(0);
+
+// 校正に使う時間 (ms)
+const CALIBRATION_MS: u32 = 10;
+
+// CPUIDで不変TSC（電源状態や周波数変更の影響を受けない）に対応しているかを確認する
+pub fn is_invariant() -> bool {
+    let max_extended_leaf = __cpuid(0x8000_0000).eax;
+    if max_extended_leaf < 0x8000_0007 {
+        return false;
+    }
+    let advanced_power_management = __cpuid(0x8000_0007);
+    advanced_power_management.edx & (1 << 8) != 0
+}
+
+pub fn read() -> u64 {
+    unsafe { _rdtsc() }
+}
+
+// HPET（なければPITのチャンネル2）で一定時間待ち、その間に進んだTSCの値から周波数を求める
+pub fn calibrate() -> Option<u64> {
+    if !is_invariant() {
+        return None;
+    }
+
+    let (start, end) = if hpet::is_enabled() {
+        hpet::measure(CALIBRATION_MS, read)
+    } else {
+        pit::measure_oneshot(CALIBRATION_MS, read)
+    };
+    let hz = (end - start) * 1000 / CALIBRATION_MS as u64;
+    // 基準と周波数を書き換える間に割り込みハンドラが時刻を読まないようにする
+    // 他のCPUが起動する前に呼び出すので、他のCPUが途中の値を読むことはない
+    interrupts::without_interrupts(|| {
+        let base_nanos = super::Instant::now().as_nanos();
+        BASE_TSC.store(read(), Ordering::Relaxed);
+        BASE_NANOS.store(base_nanos, Ordering::Relaxed);
+        TSC_HZ.store(hz, Ordering::Relaxed);
+    });
+    Some(hz)
+}
+
+pub fn frequency() -> Option<u64> {
+    match TSC_HZ.load(Ordering::Relaxed) {
+        0 => None,
+        hz => Some(hz),
+    }
+}
+
+// TSCの値を起動してからの時間（ナノ秒）に変換する、未校正の場合はNone
+pub fn to_nanos(tsc: u64) -> Option<u64> {
+    let hz = frequency()?;
+    let elapsed = tsc.saturating_sub(BASE_TSC.load(Ordering::Relaxed));
+    Some(BASE_NANOS.load(Ordering::Relaxed) + (elapsed as u128 * 1_000_000_000 / hz as u128) as u64)
+}
@@ -0,0 +1,128 @@
+use crate::sync::{SpinLock, SpinLockGuard};
+use crate::{apic, ipi, percpu};
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use x86_64::instructions::{interrupts, tlb};
+use x86_64::structures::paging::{Page, Size4KiB};
+use x86_64::VirtAddr;
+
+// これより多いページを無効にする場合はページごとのinvlpgではなくTLB全体を捨てる
+const FLUSH_ALL_THRESHOLD: u64 = 32;
+
+// シュートダウンに参加していないCPUの世代
+const NOT_JOINED: u64 = u64::MAX;
+
+// 同時に行えるシュートダウンは1つだけ
+// 参加するCPUの追加もこのロックを取って行う
+static LOCK: SpinLock<()> = SpinLock::new(());
+// シュートダウンのたびに増える世代。各CPUはpercpuに処理済みの世代を持つ
+static GENERATION: AtomicU64 = AtomicU64::new(0);
+// 無効にする範囲の先頭アドレスとページ数
+static START: AtomicU64 = AtomicU64::new(0);
+static PAGES: AtomicU64 = AtomicU64::new(0);
+// まだ応答していないCPUの数
+static PENDING: AtomicUsize = AtomicUsize::new(0);
+// シュートダウンに参加しているCPUの数
+static CPUS: AtomicUsize = AtomicUsize::new(0);
+
+// 実行中のCPUをシュートダウンの対象に加える
+// IDTの初期化が終わってから呼び出す
+pub fn init_cpu() {
+    let _guard = lock();
+    percpu::current().set_tlb_generation(GENERATION.load(Ordering::SeqCst));
+    CPUS.fetch_add(1, Ordering::SeqCst);
+}
+
+// 割り込みが無効な状態でロックを待っている間もシュートダウンに応答する
+// 応答しないとロックを持っているCPUがこちらの応答を待ち続けてデッドロックする
+fn lock() -> SpinLockGuard<'static, ()> {
+    loop {
+        if let Some(guard) = LOCK.try_lock() {
+            return guard;
+        }
+        handle_pending();
+        core::hint::spin_loop();
+    }
+}
+
+// 実行中のCPUのTLBから範囲内のページを無効にする
+pub fn flush_local(start: VirtAddr, pages: u64) {
+    if pages > FLUSH_ALL_THRESHOLD {
+        tlb::flush_all();
+        return;
+    }
+    let start = Page::<Size4KiB>::containing_address(start);
+    for i in 0..pages {
+        tlb::flush((start + i).start_address());
+    }
+}
+
+// startからsizeバイトの範囲のマッピングを全てのCPUのTLBから無効にする
+// ページテーブルを書き換えた後に呼び出す。戻ったときには他のCPUも古いマッピングを使わない
+pub fn shootdown(start: VirtAddr, size: u64) {
+    let first = Page::<Size4KiB>::containing_address(start);
+    let last = Page::<Size4KiB>::containing_address(start + (size.max(1) - 1));
+    let pages = last - first + 1;
+    flush_local(first.start_address(), pages);
+
+    if !apic::is_enabled() || CPUS.load(Ordering::SeqCst) <= 1 {
+        return;
+    }
+    // ロックを持ったままプリエンプトされると、同じCPUの次のスレッドがロックを待ち続ける
+    interrupts::without_interrupts(|| {
+        let _guard = lock();
+        let others = CPUS.load(Ordering::SeqCst) - 1;
+        START.store(first.start_address().as_u64(), Ordering::SeqCst);
+        PAGES.store(pages, Ordering::SeqCst);
+        PENDING.store(others, Ordering::SeqCst);
+        // 範囲を書き込んでから世代を進める。世代が変わったのを見たCPUは新しい範囲を読む
+        let generation = GENERATION.fetch_add(1, Ordering::SeqCst) + 1;
+        percpu::current().set_tlb_generation(generation);
+
+        // 他のCPUは割り込みが無効でもlockやSpinLock、RwLockを待つ間に応答するので、待っている間も割り込みは止めたままでよい
+        // 割り込みを止めたままそれ以外の方法で回り続けるCPUがあると、ここで待ち続ける
+        ipi::broadcast(apic::TLB_SHOOTDOWN_VECTOR);
+        while PENDING.load(Ordering::SeqCst) != 0 {
+            core::hint::spin_loop();
+        }
+    });
+}
+
+// 実行中のCPUがまだ処理していないシュートダウンがあれば、TLBを無効にして応答する
+// TLB_SHOOTDOWN_VECTORの割り込みハンドラと、割り込みを止めてロックを待つ間に呼び出される
+// 割り込みハンドラからも呼ばれるので、割り込みを止めた状態で呼び出す
+pub(crate) fn handle_pending() {
+    // シュートダウンが起こらない間は、percpuの初期化前でも呼べるようにGSを読まずに戻る
+    if CPUS.load(Ordering::Relaxed) <= 1 {
+        return;
+    }
+    let cpu = match percpu::try_current() {
+        Some(cpu) => cpu,
+        None => return,
+    };
+    let done = cpu.tlb_generation();
+    let generation = GENERATION.load(Ordering::SeqCst);
+    if done == NOT_JOINED || done == generation {
+        return;
+    }
+    flush_local(
+        VirtAddr::new(START.load(Ordering::SeqCst)),
+        PAGES.load(Ordering::SeqCst),
+    );
+    cpu.set_tlb_generation(generation);
+    PENDING.fetch_sub(1, Ordering::SeqCst);
+}
+
+// シュートダウンが行われた回数
+pub fn generation() -> u64 {
+    GENERATION.load(Ordering::SeqCst)
+}
+
+#[test_case]
+fn test_shootdown_on_single_cpu_flushes_locally() {
+    use x86_64::structures::paging::PageSize;
+
+    let before = generation();
+    shootdown(VirtAddr::new(Size4KiB::SIZE * 16), Size4KiB::SIZE);
+    // 他のCPUがいなければIPIを送らないので世代は変わらない
+    assert_eq!(generation(), before);
+}
@@ -0,0 +1,202 @@
+use crate::{gdt, memory, percpu};
+use core::arch::global_asm;
+use x86_64::structures::paging::{
+    mapper::MapToError, FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB, Translate,
+};
+use x86_64::VirtAddr;
+
+// ユーザープログラムのコードとスタックを置く仮想アドレス
+pub const USER_CODE_START: u64 = 0x_1000_0000_0000;
+pub const USER_STACK_TOP: u64 = 0x_7fff_ffff_f000;
+pub const USER_STACK_SIZE: u64 = 4096 * 4;
+
+// 例外を起こしたユーザープログラムの終了コード
+pub const FAULT_EXIT_CODE: u64 = u64::MAX;
+
+extern "C" {
+    fn usermode_enter(entry: u64, stack: u64, code_selector: u64, data_selector: u64) -> u64;
+    fn usermode_exit(code: u64) -> !;
+    fn usermode_return(code: u64) -> !;
+}
+
+// ユーザーモードに入る直前のカーネルのスタックポインタはpercpuのgs:[24]に記録し、
+// ユーザーモードから戻るときにこの値を復元してenter_user_modeの呼び出し元に戻る
+global_asm!(
+    ".global usermode_enter",
+    "usermode_enter:",
+    // callee-savedレジスタとRFLAGSを保存し、戻る場所としてスタックポインタを記録する
+    "push rbp",
+    "push rbx",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    "pushfq",
+    "mov qword ptr gs:[24], rsp",
+    // iretq用のフレーム: SS, RSP, RFLAGS(IF=1), CS, RIP
+    "push rcx",
+    "push rsi",
+    "push 0x202",
+    "push rdx",
+    "push rdi",
+    // カーネルの値がユーザーに漏れないようにレジスタを消去する
+    "xor eax, eax",
+    "xor ebx, ebx",
+    "xor ecx, ecx",
+    "xor edx, edx",
+    "xor esi, esi",
+    "xor edi, edi",
+    "xor ebp, ebp",
+    "xor r8d, r8d",
+    "xor r9d, r9d",
+    "xor r10d, r10d",
+    "xor r11d, r11d",
+    "xor r12d, r12d",
+    "xor r13d, r13d",
+    "xor r14d, r14d",
+    "xor r15d, r15d",
+    // ユーザーモードの間はカーネルのGSのベースをIA32_KERNEL_GS_BASEに退避しておく
+    // SWAPGSからiretqまでに割り込みが入らないようにする（IFはiretqで復元される）
+    "cli",
+    "swapgs",
+    "iretq",
+    "",
+    // rdiの値を終了コードとしてenter_user_modeの呼び出し元に戻る
+    // int 0x80はring 3から呼ばれるので、まずカーネルのGSに切り替える
+    ".global usermode_exit",
+    "usermode_exit:",
+    "swapgs",
+    // カーネルから(GSが切り替え済みの状態で)戻る場合の入口
+    ".global usermode_return",
+    "usermode_return:",
+    "mov rax, rdi",
+    "mov rsp, qword ptr gs:[24]",
+    "popfq",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop rbx",
+    "pop rbp",
+    "ret",
+);
+
+// スレッドを切り替えるときに、スレッドごとの戻り先を保存・復元する
+pub(crate) fn user_return_rsp() -> u64 {
+    percpu::current().user_return_rsp()
+}
+
+pub(crate) unsafe fn set_user_return_rsp(rsp: u64) {
+    percpu::current().set_user_return_rsp(rsp);
+}
+
+/// # Safety
+// ring 3に移ってentryから実行し、ユーザープログラムが終了したら終了コードを返す
+// entryとstackはユーザーがアクセスできるページにマップされていなければならない
+pub unsafe fn enter_user_mode(entry: VirtAddr, stack: VirtAddr) -> u64 {
+    usermode_enter(
+        entry.as_u64(),
+        stack.as_u64(),
+        gdt::user_code_selector().0 as u64,
+        gdt::user_data_selector().0 as u64,
+    )
+}
+
+// ユーザーモードからカーネルに戻るための割り込み（int 0x80）のエントリ
+pub fn exit_entry_address() -> VirtAddr {
+    VirtAddr::new(usermode_exit as *const () as u64)
+}
+
+/// # Safety
+// enter_user_modeで実行中のユーザープログラムを終了し、codeを終了コードとして呼び出し元に戻る
+// ユーザーモードから入ったカーネルの処理からのみ呼び出せる
+pub unsafe fn exit_to_kernel(code: u64) -> ! {
+    usermode_return(code)
+}
+
+/// # Safety
+// ring 3で起きた例外のハンドラから、GSをユーザーの値に戻して割り込みを止めた状態で呼び出す
+// 実行中のユーザープログラムを終了し、FAULT_EXIT_CODEを終了コードとして呼び出し元に戻る
+pub(crate) unsafe fn exit_faulted_program() -> ! {
+    usermode_exit(FAULT_EXIT_CODE)
+}
+
+// ユーザーがアクセスできる領域を新しいフレームに割り当ててゼロで初期化する
+pub fn map_user_region(
+    start: VirtAddr,
+    size: u64,
+    flags: PageTableFlags,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), MapToError<Size4KiB>> {
+    let start_page = Page::<Size4KiB>::containing_address(start);
+    let end_page = Page::containing_address(start + (size.max(1) - 1));
+    let flags = flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+    // 上位のページテーブルの権限はページごとのフラグで制限する
+    let parent_flags =
+        PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+
+    for page in Page::range_inclusive(start_page, end_page) {
+        let frame = frame_allocator
+            .allocate_frame()
+            .ok_or(MapToError::FrameAllocationFailed)?;
+        let frame_ptr = memory::phys_to_virt(frame.start_address()).as_mut_ptr::<u8>();
+        unsafe {
+            frame_ptr.write_bytes(0, 4096);
+            mapper
+                .map_to_with_table_flags(page, frame, flags, parent_flags, frame_allocator)?
+                .flush();
+        }
+    }
+    Ok(())
+}
+
+// ページテーブルでマップ済みの領域に物理メモリ経由でデータを書き込む
+// 書き込み不可のページやアクティブでないページテーブルにも書き込める
+pub fn copy_to_user(mapper: &impl Translate, dest: VirtAddr, data: &[u8]) {
+    let mut offset = 0;
+    while offset < data.len() {
+        let addr = dest + offset;
+        let phys = mapper
+            .translate_addr(addr)
+            .expect("copy_to_user: destination not mapped");
+        let page_remaining = 4096 - (addr.as_u64() % 4096) as usize;
+        let len = page_remaining.min(data.len() - offset);
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                data[offset..].as_ptr(),
+                memory::phys_to_virt(phys).as_mut_ptr::<u8>(),
+                len,
+            );
+        }
+        offset += len;
+    }
+}
+
+// コードとスタックをユーザー空間にマップする
+pub fn load_user_code(
+    code: &[u8],
+    mapper: &mut (impl Mapper<Size4KiB> + Translate),
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(VirtAddr, VirtAddr), MapToError<Size4KiB>> {
+    let entry = VirtAddr::new(USER_CODE_START);
+    map_user_region(
+        entry,
+        code.len() as u64,
+        PageTableFlags::empty(),
+        mapper,
+        frame_allocator,
+    )?;
+    copy_to_user(mapper, entry, code);
+
+    let stack_top = VirtAddr::new(USER_STACK_TOP);
+    map_user_region(
+        stack_top - USER_STACK_SIZE,
+        USER_STACK_SIZE,
+        PageTableFlags::WRITABLE,
+        mapper,
+        frame_allocator,
+    )?;
+
+    Ok((entry, stack_top))
+}
@@ -0,0 +1,121 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(toy_rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::time::Duration;
+use futures_util::stream::StreamExt;
+use toy_rust_os::task::{simple_executor::SimpleExecutor, timer, Task};
+use toy_rust_os::{hlt_loop, time};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use toy_rust_os::allocator;
+    use toy_rust_os::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    toy_rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+
+    test_main();
+    hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    toy_rust_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn sleep_waits_for_duration() {
+    let start = time::ticks();
+    let mut executor = SimpleExecutor::new();
+    executor.spawn(Task::new(async {
+        timer::sleep(Duration::from_millis(50)).await;
+    }));
+    executor.run();
+    assert!(time::ticks() - start >= time::duration_to_ticks(Duration::from_millis(50)));
+}
+
+#[test_case]
+fn interval_fires_periodically() {
+    let start = time::ticks();
+    let mut executor = SimpleExecutor::new();
+    executor.spawn(Task::new(async {
+        let mut interval = timer::interval(Duration::from_millis(20));
+        for _ in 0..3 {
+            interval.next().await;
+        }
+    }));
+    executor.run();
+    // 最初の値はすぐに返るため、2周期分以上経過している
+    assert!(time::ticks() - start >= 2 * time::duration_to_ticks(Duration::from_millis(20)));
+}
+
+#[test_case]
+fn timeout_elapses_for_slow_future() {
+    use core::sync::atomic::{AtomicBool, Ordering};
+
+    static ELAPSED: AtomicBool = AtomicBool::new(false);
+    let mut executor = SimpleExecutor::new();
+    executor.spawn(Task::new(async {
+        let slow = timer::sleep(Duration::from_millis(200));
+        let result = timer::timeout(slow, Duration::from_millis(20)).await;
+        ELAPSED.store(result == Err(timer::Elapsed), Ordering::SeqCst);
+    }));
+    executor.run();
+    assert!(ELAPSED.load(Ordering::SeqCst));
+}
+
+#[test_case]
+fn timeout_returns_output_of_fast_future() {
+    use core::sync::atomic::{AtomicU64, Ordering};
+
+    static RESULT: AtomicU64 = AtomicU64::new(0);
+    let mut executor = SimpleExecutor::new();
+    executor.spawn(Task::new(async {
+        let fast = async {
+            timer::sleep(Duration::from_millis(10)).await;
+            9
+        };
+        let result = timer::timeout(fast, Duration::from_millis(200)).await;
+        RESULT.store(result.expect("timed out"), Ordering::SeqCst);
+    }));
+    executor.run();
+    assert_eq!(RESULT.load(Ordering::SeqCst), 9);
+}
+
+#[test_case]
+fn select_and_join_wait_on_timers() {
+    use core::sync::atomic::{AtomicU64, Ordering};
+
+    static FIRST: AtomicU64 = AtomicU64::new(0);
+    let start = time::ticks();
+    let mut executor = SimpleExecutor::new();
+    executor.spawn(Task::new(async {
+        let first = toy_rust_os::select! {
+            _ = timer::sleep(Duration::from_millis(100)) => 100,
+            _ = timer::sleep(Duration::from_millis(10)) => 10,
+        };
+        FIRST.store(first, Ordering::SeqCst);
+        toy_rust_os::join!(
+            timer::sleep(Duration::from_millis(20)),
+            timer::sleep(Duration::from_millis(40)),
+        );
+    }));
+    executor.run();
+    assert_eq!(FIRST.load(Ordering::SeqCst), 10);
+    // join!は遅い方のタイマーまで待つ
+    let waited = time::ticks() - start;
+    assert!(waited >= time::duration_to_ticks(Duration::from_millis(50)));
+    assert!(waited < time::duration_to_ticks(Duration::from_millis(100)));
+}
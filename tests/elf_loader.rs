@@ -0,0 +1,118 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(toy_rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use toy_rust_os::elf::{Elf, ElfError};
+use toy_rust_os::hlt_loop;
+use toy_rust_os::loader::{self, LoadError};
+use toy_rust_os::memory::GlobalFrameAllocator;
+use x86_64::structures::paging::mapper::TranslateResult;
+use x86_64::structures::paging::{FrameAllocator, PageTableFlags, Translate};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+// "hello from ELF\n"を出力してargcを終了コードとして返す (tests/programs/hello.s)
+static HELLO_ELF: &[u8] = include_bytes!("programs/hello.elf");
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use toy_rust_os::allocator;
+    use toy_rust_os::memory::{self, BootInfoFrameAllocator};
+
+    toy_rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    memory::install_frame_allocator(frame_allocator);
+
+    test_main();
+    hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    toy_rust_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn parse_hello_elf() {
+    let elf = Elf::parse(HELLO_ELF).expect("failed to parse hello.elf");
+    assert_eq!(elf.entry(), 0x1000_0000_1000);
+    assert!(elf
+        .program_headers()
+        .any(|ph| ph.p_type == toy_rust_os::elf::PT_LOAD));
+}
+
+#[test_case]
+fn reject_invalid_elf() {
+    assert_eq!(
+        Elf::parse(&HELLO_ELF[..16]).err(),
+        Some(ElfError::Truncated)
+    );
+    let mut bad_magic = [0u8; 64];
+    bad_magic.copy_from_slice(&HELLO_ELF[..64]);
+    bad_magic[0] = 0;
+    assert_eq!(Elf::parse(&bad_magic).err(), Some(ElfError::BadMagic));
+}
+
+#[test_case]
+fn run_hello_elf() {
+    let program = loader::load(
+        HELLO_ELF,
+        &["hello", "world"],
+        &["TERM=vga"],
+        &mut GlobalFrameAllocator,
+    )
+    .expect("failed to load hello.elf");
+    let code = unsafe { loader::run(&program) };
+    assert_eq!(code, 2);
+}
+
+#[test_case]
+fn reject_truncated_segment() {
+    // プログラムヘッダは読めるがセグメントのデータが欠けている
+    let result = loader::load(&HELLO_ELF[..0x1010], &[], &[], &mut GlobalFrameAllocator);
+    assert!(matches!(result, Err(LoadError::Elf(ElfError::Truncated))));
+}
+
+#[test_case]
+fn data_segment_is_not_executable() {
+    let mut program = loader::load(HELLO_ELF, &[], &[], &mut GlobalFrameAllocator)
+        .expect("failed to load hello.elf");
+    let mapper = program.address_space.mapper();
+    let flags_at = |addr: u64| match mapper.translate(VirtAddr::new(addr)) {
+        TranslateResult::Mapped { flags, .. } => flags,
+        _ => panic!("{:#x} is not mapped", addr),
+    };
+    // .textはPF_Xを持ち、.rodataは持たない
+    assert!(!flags_at(0x1000_0000_1000).contains(PageTableFlags::NO_EXECUTE));
+    assert!(flags_at(0x1000_0000_2000).contains(PageTableFlags::NO_EXECUTE));
+    assert!(!flags_at(0x1000_0000_2000).contains(PageTableFlags::WRITABLE));
+}
+
+#[test_case]
+fn reject_segment_in_kernel_slot() {
+    // 最初のPT_LOADのp_vaddrをカーネルヒープと同じレベル4エントリに書き換える
+    let mut elf = alloc::vec::Vec::from(HELLO_ELF);
+    let phoff = Elf::parse(HELLO_ELF).unwrap().header().phoff as usize;
+    elf[phoff + 16..phoff + 24].copy_from_slice(&0x4444_4444_0000u64.to_le_bytes());
+    let result = loader::load(&elf, &[], &[], &mut GlobalFrameAllocator);
+    assert!(matches!(result, Err(LoadError::BadSegment)));
+}
+
+#[test_case]
+fn freed_address_space_is_reused() {
+    let program = loader::load(HELLO_ELF, &[], &[], &mut GlobalFrameAllocator)
+        .expect("failed to load hello.elf");
+    let level_4_frame = program.address_space.level_4_frame();
+    unsafe { program.address_space.free(&mut GlobalFrameAllocator) };
+    // 最後に解放したレベル4テーブルのフレームが最初に再利用される
+    assert_eq!(GlobalFrameAllocator.allocate_frame(), Some(level_4_frame));
+}
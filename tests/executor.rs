@@ -0,0 +1,431 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(toy_rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::future::Future;
+use core::panic::PanicInfo;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use core::task::{Context, Poll};
+use core::time::Duration;
+use toy_rust_os::task::join::JoinError;
+use toy_rust_os::task::{executor, timer, Priority};
+use toy_rust_os::time::Instant;
+use toy_rust_os::{hlt_loop, percpu, smp, thread};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use toy_rust_os::allocator;
+    use toy_rust_os::memory::{self, BootInfoFrameAllocator, GlobalFrameAllocator};
+    use x86_64::VirtAddr;
+
+    toy_rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    memory::install_frame_allocator(frame_allocator);
+    thread::init();
+    smp::init(&mut mapper, &mut GlobalFrameAllocator).expect("SMP initialization failed");
+
+    test_main();
+    hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    toy_rust_os::task::unwind::unwind_task_panic(info);
+    toy_rust_os::test_panic_handler(info)
+}
+
+// テストはBSPで動き、BSPのexecutorは動かさない
+// BSPのキューに追加したタスクはAPが盗んで実行する
+fn wait_until(done: impl Fn() -> bool) {
+    let start = Instant::now();
+    while !done() {
+        assert!(
+            start.elapsed() < Duration::from_secs(5),
+            "tasks did not finish"
+        );
+        x86_64::instructions::hlt();
+    }
+}
+
+#[test_case]
+fn idle_cpus_steal_tasks() {
+    static DONE: AtomicUsize = AtomicUsize::new(0);
+    static CPUS_USED: AtomicU64 = AtomicU64::new(0);
+    const TASKS: usize = 16;
+
+    for _ in 0..TASKS {
+        executor::spawn(async {
+            for _ in 0..10_000 {
+                core::hint::spin_loop();
+            }
+            CPUS_USED.fetch_or(1 << percpu::cpu_id(), Ordering::SeqCst);
+            DONE.fetch_add(1, Ordering::SeqCst);
+        });
+    }
+    wait_until(|| DONE.load(Ordering::SeqCst) == TASKS);
+    // BSPはexecutorを動かしていないので、全てAPで実行されている
+    let used = CPUS_USED.load(Ordering::SeqCst);
+    assert_eq!(used & 1, 0);
+    assert_ne!(used, 0);
+}
+
+#[test_case]
+fn waker_wakes_task_on_other_cpu() {
+    static WOKEN_ON: AtomicUsize = AtomicUsize::new(usize::MAX);
+
+    executor::spawn(async {
+        // タイマー割り込みはBSPで処理されるので、wakerはBSPからAPのキューにタスクを戻してIPIを送る
+        timer::sleep(Duration::from_millis(20)).await;
+        WOKEN_ON.store(percpu::cpu_id(), Ordering::SeqCst);
+    });
+    wait_until(|| WOKEN_ON.load(Ordering::SeqCst) != usize::MAX);
+    assert_ne!(WOKEN_ON.load(Ordering::SeqCst), 0);
+}
+
+#[test_case]
+fn thread_on_ap_wakes_on_same_cpu() {
+    static BEFORE: AtomicUsize = AtomicUsize::new(usize::MAX);
+    static AFTER: AtomicUsize = AtomicUsize::new(usize::MAX);
+
+    executor::spawn(async {
+        // タイマー割り込みはBSPで処理されるが、起こしたスレッドは作ったAPのキューに戻る
+        let handle = thread::spawn(|| {
+            let before = percpu::cpu_id();
+            thread::sleep(Duration::from_millis(20));
+            (before, percpu::cpu_id())
+        });
+        let (before, after) = handle.join().expect("thread did not return");
+        BEFORE.store(before, Ordering::SeqCst);
+        AFTER.store(after, Ordering::SeqCst);
+    });
+    wait_until(|| AFTER.load(Ordering::SeqCst) != usize::MAX);
+    assert_ne!(BEFORE.load(Ordering::SeqCst), 0);
+    assert_eq!(AFTER.load(Ordering::SeqCst), BEFORE.load(Ordering::SeqCst));
+}
+
+#[test_case]
+fn join_handle_returns_output_of_other_task() {
+    static RESULT: AtomicU64 = AtomicU64::new(0);
+
+    let handle = executor::spawn(async { 6 * 7 });
+    executor::spawn(async move {
+        let output: u64 = handle.await.expect("task failed");
+        RESULT.store(output, Ordering::SeqCst);
+    });
+    wait_until(|| RESULT.load(Ordering::SeqCst) != 0);
+    assert_eq!(RESULT.load(Ordering::SeqCst), 42);
+}
+
+#[test_case]
+fn detached_task_keeps_running() {
+    static DONE: AtomicUsize = AtomicUsize::new(0);
+
+    executor::spawn(async {
+        DONE.store(1, Ordering::SeqCst);
+    })
+    .detach();
+    wait_until(|| DONE.load(Ordering::SeqCst) == 1);
+}
+
+#[test_case]
+fn abort_drops_future_and_cancels_join_handle() {
+    static DROPPED: AtomicUsize = AtomicUsize::new(0);
+    static CANCELLED: AtomicUsize = AtomicUsize::new(0);
+
+    struct SetOnDrop;
+
+    impl Drop for SetOnDrop {
+        fn drop(&mut self) {
+            DROPPED.store(1, Ordering::SeqCst);
+        }
+    }
+
+    // まだpollされていなくてもfutureと一緒に捨てられるように、外で作って渡す
+    let guard = SetOnDrop;
+    let handle = executor::spawn(async move {
+        let _guard = guard;
+        core::future::pending::<()>().await;
+    });
+    let abort = handle.abort_handle();
+    executor::spawn(async move {
+        if handle.await == Err(JoinError::Cancelled) {
+            CANCELLED.store(1, Ordering::SeqCst);
+        }
+    });
+    assert!(abort.abort());
+    wait_until(|| DROPPED.load(Ordering::SeqCst) == 1 && CANCELLED.load(Ordering::SeqCst) == 1);
+    assert!(!abort.abort());
+}
+
+#[test_case]
+fn task_spawns_child_with_spawner() {
+    static RESULT: AtomicU64 = AtomicU64::new(0);
+
+    let spawner = executor::spawner();
+    executor::spawn(async move {
+        let child = spawner.clone().spawn(async { 7u64 });
+        RESULT.store(child.await.expect("child failed"), Ordering::SeqCst);
+    });
+    wait_until(|| RESULT.load(Ordering::SeqCst) != 0);
+    assert_eq!(RESULT.load(Ordering::SeqCst), 7);
+}
+
+#[test_case]
+fn spawner_works_with_interrupts_disabled() {
+    static DONE: AtomicUsize = AtomicUsize::new(0);
+
+    // 割り込みハンドラの中と同じく、割り込みを止めた状態で追加する
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        executor::spawner()
+            .spawn(async {
+                DONE.store(1, Ordering::SeqCst);
+            })
+            .detach();
+    });
+    wait_until(|| DONE.load(Ordering::SeqCst) == 1);
+}
+
+#[test_case]
+fn thousands_of_tasks_do_not_overflow_queues() {
+    static DONE: AtomicUsize = AtomicUsize::new(0);
+    const TASKS: usize = 2000;
+
+    for _ in 0..TASKS {
+        executor::spawn(async {
+            // 自分を何度か起こしてキューに戻る
+            for _ in 0..3 {
+                YieldNow(false).await;
+            }
+            DONE.fetch_add(1, Ordering::SeqCst);
+        })
+        .detach();
+    }
+    wait_until(|| DONE.load(Ordering::SeqCst) == TASKS);
+}
+
+#[test_case]
+fn repeated_wakeups_are_deduplicated() {
+    static POLLS: AtomicUsize = AtomicUsize::new(0);
+
+    struct WakeManyTimes;
+
+    impl Future for WakeManyTimes {
+        type Output = ();
+
+        fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+            // 最初のpollで1000回起こし、その後は起こさずに待ち続ける
+            if POLLS.fetch_add(1, Ordering::SeqCst) == 0 {
+                for _ in 0..1000 {
+                    cx.waker().wake_by_ref();
+                }
+            }
+            Poll::Pending
+        }
+    }
+
+    let handle = executor::spawn(WakeManyTimes);
+    wait_until(|| POLLS.load(Ordering::SeqCst) >= 2);
+    let start = Instant::now();
+    while start.elapsed() < Duration::from_millis(50) {
+        core::hint::spin_loop();
+    }
+    assert_eq!(POLLS.load(Ordering::SeqCst), 2);
+    handle.abort();
+}
+
+// 一度だけPendingを返して他のタスクに譲る
+struct YieldNow(bool);
+
+impl Future for YieldNow {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if self.0 {
+            return Poll::Ready(());
+        }
+        self.0 = true;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
+
+#[test_case]
+fn task_stats_count_polls_and_wakeups() {
+    static POLLS: AtomicUsize = AtomicUsize::new(0);
+
+    struct WakeThreeTimes;
+
+    impl Future for WakeThreeTimes {
+        type Output = ();
+
+        fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+            if POLLS.fetch_add(1, Ordering::SeqCst) < 3 {
+                cx.waker().wake_by_ref();
+            }
+            Poll::Pending
+        }
+    }
+
+    let handle = executor::spawn_with_priority(Priority::Low, WakeThreeTimes);
+    // 統計はpollが戻った後に更新されるので、統計の方を待つ
+    let stats = || executor::task_stats(handle.id()).expect("task not found");
+    wait_until(|| stats().polls == 4);
+    let stats = stats();
+    assert_eq!(stats.priority, Priority::Low);
+    assert_eq!(stats.polls, 4);
+    assert_eq!(stats.wakeups, 3);
+    handle.abort();
+    assert_eq!(executor::task_stats(handle.id()), None);
+}
+
+#[test_case]
+fn mpsc_channel_between_cpus() {
+    use toy_rust_os::task::channel::mpsc;
+
+    static SUM: AtomicU64 = AtomicU64::new(0);
+    const PRODUCERS: u64 = 4;
+    const VALUES: u64 = 100;
+
+    // 容量を小さくして、送信側が受信側を待つ場面を作る
+    let (sender, mut receiver) = mpsc::channel(4);
+    for _ in 0..PRODUCERS {
+        let sender = sender.clone();
+        executor::spawn(async move {
+            for value in 1..=VALUES {
+                sender.send(value).await.expect("receiver dropped");
+            }
+        });
+    }
+    drop(sender);
+    let consumer = executor::spawn(async move {
+        while let Some(value) = receiver.recv().await {
+            SUM.fetch_add(value, Ordering::SeqCst);
+        }
+    });
+    wait_until(|| consumer.is_finished());
+    assert_eq!(
+        SUM.load(Ordering::SeqCst),
+        PRODUCERS * VALUES * (VALUES + 1) / 2
+    );
+}
+
+#[test_case]
+fn task_list_shows_name_location_and_state() {
+    use toy_rust_os::task::channel::oneshot;
+    use toy_rust_os::task::executor::{Builder, TaskState};
+
+    let (sender, receiver) = oneshot::channel::<()>();
+    let handle = Builder::new().name("waiter").spawn(async move {
+        receiver.await.expect("sender dropped");
+    });
+    let info = || {
+        executor::tasks()
+            .into_iter()
+            .find(|task| task.id == handle.id())
+            .expect("task not listed")
+    };
+    wait_until(|| info().stats.polls == 1 && info().state == TaskState::Waiting);
+    let task = info();
+    assert_eq!(task.name.as_deref(), Some("waiter"));
+    assert_eq!(task.location.file(), file!());
+    executor::dump_tasks();
+
+    sender.send(()).expect("receiver dropped");
+    // 完了したタスクはpollが戻った後に一覧から取り除かれる
+    wait_until(|| executor::tasks().iter().all(|task| task.id != handle.id()));
+    assert!(handle.is_finished());
+}
+
+#[test_case]
+fn block_on_returns_output_after_sleeping() {
+    let start = Instant::now();
+    let output = executor::block_on(async {
+        timer::sleep(Duration::from_millis(20)).await;
+        7
+    });
+    assert_eq!(output, 7);
+    assert!(start.elapsed() >= Duration::from_millis(20));
+}
+
+#[test_case]
+fn block_on_runs_spawned_tasks() {
+    // BSPのキューに入ったタスクは、APに盗まれなければblock_onの中でBSPが実行する
+    let handle = executor::spawn(async {
+        YieldNow(false).await;
+        6 * 7
+    });
+    assert_eq!(executor::block_on(handle), Ok(42));
+}
+
+#[test_case]
+fn run_until_idle_returns_while_tasks_wait() {
+    use toy_rust_os::task::channel::oneshot;
+
+    let (sender, receiver) = oneshot::channel();
+    let handle = executor::spawn(async move { receiver.await.expect("sender dropped") });
+    executor::run_until_idle();
+    // 待っているタスクは実行できないので、完了しないまま戻る
+    assert!(!handle.is_finished());
+    sender.send(5u64).expect("receiver dropped");
+    assert_eq!(executor::block_on(handle), Ok(5));
+}
+
+// unwind featureを有効にし、x86_64-toy-rust-os-unwind.jsonでビルドした場合だけ実行する
+#[cfg(feature = "unwind")]
+#[test_case]
+fn panicking_task_fails_without_stopping_executor() {
+    use toy_rust_os::sync::SpinLock;
+
+    static LOCK: SpinLock<u64> = SpinLock::new(0);
+    let failed = executor::failed_task_count();
+    let handle = executor::spawn(async {
+        let mut value = LOCK.lock();
+        *value += 1;
+        panic!("task panic isolation test");
+    });
+    assert_eq!(executor::block_on(handle), Err(JoinError::Panicked));
+    assert_eq!(executor::failed_task_count(), failed + 1);
+    // 巻き戻す間にガードが捨てられ、ロックは外れている
+    assert_eq!(*LOCK.try_lock().expect("lock still held"), 1);
+    assert!(x86_64::instructions::interrupts::are_enabled());
+    // 他のタスクはそのまま実行され続ける
+    assert_eq!(executor::block_on(executor::spawn(async { 3 })), Ok(3));
+}
+
+#[test_case]
+fn task_panics_after_preemption() {
+    static SPINNING: AtomicBool = AtomicBool::new(false);
+    static STOP: AtomicBool = AtomicBool::new(false);
+
+    // 譲らずに回り続けるスレッドに、タイマー割り込みで切り替わってから戻ってくる
+    let spinner = thread::spawn(|| {
+        SPINNING.store(true, Ordering::SeqCst);
+        while !STOP.load(Ordering::SeqCst) {
+            core::hint::spin_loop();
+        }
+    });
+    while !SPINNING.load(Ordering::SeqCst) {
+        core::hint::spin_loop();
+    }
+    // 切り替え先のタイマー割り込みハンドラの深さを引き継いでいない
+    assert!(!percpu::current().in_interrupt());
+
+    // unwind featureが有効なら、このスレッドで動かしたタスクのパニックもタスクだけの失敗になる
+    #[cfg(feature = "unwind")]
+    {
+        let handle = executor::spawn(async { panic!("task panic after preemption") });
+        assert_eq!(executor::block_on(handle), Err(JoinError::Panicked));
+    }
+    STOP.store(true, Ordering::SeqCst);
+    spinner.join();
+}
@@ -0,0 +1,97 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(toy_rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use toy_rust_os::process::{self, ProcessState};
+use toy_rust_os::{hlt_loop, thread, usermode};
+
+entry_point!(main);
+
+// "hello from ELF\n"を出力してargcを終了コードとして返す (tests/programs/hello.s)
+static HELLO_ELF: &[u8] = include_bytes!("programs/hello.elf");
+// カーネルの領域を読んでページフォールトを起こす (tests/programs/fault.s)
+static FAULT_ELF: &[u8] = include_bytes!("programs/fault.elf");
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use toy_rust_os::allocator;
+    use toy_rust_os::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    toy_rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    memory::install_frame_allocator(frame_allocator);
+    thread::init();
+
+    test_main();
+    hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    toy_rust_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn wait_returns_exit_code() {
+    let pid = process::spawn("hello", HELLO_ELF, &["hello", "a", "b"]).expect("spawn failed");
+    assert_eq!(process::wait(pid), Some(3));
+    // 回収されたプロセスはテーブルから消える
+    assert!(process::list().iter().all(|info| info.pid != pid));
+    assert_eq!(process::wait(pid), None);
+}
+
+#[test_case]
+fn process_table_lists_processes() {
+    let first = process::spawn("first", HELLO_ELF, &["first"]).expect("spawn failed");
+    let second = process::spawn("second", HELLO_ELF, &["second"]).expect("spawn failed");
+    assert_ne!(first, second);
+
+    let list = process::list();
+    let info = list
+        .iter()
+        .find(|info| info.pid == first)
+        .expect("process not listed");
+    assert_eq!(info.name, "first");
+    // テストはカーネルのスレッドで動いているので親はいない
+    assert_eq!(info.parent, None);
+
+    assert_eq!(process::wait(first), Some(1));
+    assert_eq!(process::wait(second), Some(1));
+}
+
+#[test_case]
+fn exited_process_is_zombie_until_waited() {
+    let pid = process::spawn("zombie", HELLO_ELF, &[]).expect("spawn failed");
+    while !process::list()
+        .iter()
+        .any(|info| info.pid == pid && info.state == ProcessState::Zombie)
+    {
+        thread::yield_now();
+    }
+    let info = process::list().into_iter().find(|info| info.pid == pid);
+    assert_eq!(info.and_then(|info| info.exit_code), Some(0));
+    assert_eq!(process::wait(pid), Some(0));
+}
+
+#[test_case]
+fn current_pid_outside_process_is_none() {
+    assert_eq!(process::current_pid(), None);
+}
+
+#[test_case]
+fn faulting_process_is_killed() {
+    // 例外を起こしたプロセスだけが終了し、カーネルと他のプロセスは動き続ける
+    let fault = process::spawn("fault", FAULT_ELF, &[]).expect("spawn failed");
+    let hello = process::spawn("hello", HELLO_ELF, &["hello"]).expect("spawn failed");
+    assert_eq!(process::wait(fault), Some(usermode::FAULT_EXIT_CODE));
+    assert_eq!(process::wait(hello), Some(1));
+}
@@ -0,0 +1,19 @@
+# processテスト用のユーザープログラム
+# カーネルの領域を読んでページフォールトを起こす。プロセスだけが終了し、カーネルは止まらない
+#
+# ビルド方法:
+#   as fault.s -o fault.o
+#   ld -static -nostdlib -e _start -Ttext-segment=0x100000000000 \
+#      -z max-page-size=4096 --build-id=none -s -o fault.elf fault.o
+
+    .intel_syntax noprefix
+    .global _start
+
+    .text
+_start:
+    movabs rax, 0xffff800000000000
+    mov rax, [rax]
+
+    mov edi, 0                  # ここには来ない
+    mov eax, 1                  # exit
+    syscall
@@ -0,0 +1,29 @@
+# elf_loaderテスト用のユーザープログラム
+# "hello from ELF\n"を出力し、argcを終了コードとして終了する
+#
+# ビルド方法:
+#   as hello.s -o hello.o
+#   ld -static -nostdlib -e _start -Ttext-segment=0x100000000000 \
+#      -z max-page-size=4096 --build-id=none -s -o hello.elf hello.o
+
+    .intel_syntax noprefix
+    .global _start
+
+    .text
+_start:
+    mov eax, 0                  # write
+    mov edi, 1
+    lea rsi, [rip + message]
+    mov edx, message_len
+    syscall
+
+    mov rdi, [rsp]              # argc
+    mov eax, 1                  # exit
+    syscall
+1:
+    jmp 1b
+
+    .section .rodata
+message:
+    .ascii "hello from ELF\n"
+    .set message_len, . - message
@@ -0,0 +1,72 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(toy_rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use toy_rust_os::{hlt_loop, percpu, smp, thread};
+
+entry_point!(main);
+
+// Cargo.tomlのtest-argsでQEMUを-smp 4で起動している
+const EXPECTED_CPUS: usize = 4;
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use toy_rust_os::allocator;
+    use toy_rust_os::memory::{self, BootInfoFrameAllocator, GlobalFrameAllocator};
+    use x86_64::VirtAddr;
+
+    toy_rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    memory::install_frame_allocator(frame_allocator);
+    thread::init();
+    smp::init(&mut mapper, &mut GlobalFrameAllocator).expect("SMP initialization failed");
+
+    test_main();
+    hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    toy_rust_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn madt_lists_all_cpus() {
+    let ids = smp::apic_ids().expect("no MADT");
+    assert_eq!(ids.len(), EXPECTED_CPUS);
+}
+
+#[test_case]
+fn all_cpus_are_online() {
+    assert_eq!(smp::online_cpus(), EXPECTED_CPUS);
+    assert_eq!(percpu::cpu_count(), EXPECTED_CPUS);
+}
+
+#[test_case]
+fn cpus_have_distinct_ids() {
+    let cpus: Vec<_> = percpu::cpus().collect();
+    assert_eq!(cpus.len(), EXPECTED_CPUS);
+    for (i, cpu) in cpus.iter().enumerate() {
+        assert_eq!(cpu.id(), i);
+        assert!(cpus[..i]
+            .iter()
+            .all(|other| other.apic_id() != cpu.apic_id()));
+    }
+    // テストはBSPで動いている
+    assert_eq!(percpu::cpu_id(), 0);
+}
+
+#[test_case]
+fn bsp_keeps_scheduling_threads() {
+    let handle = thread::spawn(percpu::cpu_id);
+    assert_eq!(handle.join(), Some(0));
+}
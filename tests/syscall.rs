@@ -0,0 +1,158 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(toy_rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use toy_rust_os::syscall::SyscallError;
+use toy_rust_os::{hlt_loop, usermode};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+// ring 3で実行するルーチン。それぞれ最後にexitで結果をカーネルに返す
+#[rustfmt::skip]
+const USER_CODE: &[u8] = &[
+    // 0x00: exit(7)
+    0xb8, 0x01, 0x00, 0x00, 0x00,                               // mov eax, 1
+    0xbf, 0x07, 0x00, 0x00, 0x00,                               // mov edi, 7
+    0x0f, 0x05,                                                 // syscall
+    0xeb, 0xf2,                                                 // jmp 0x00
+    // 0x0e: exit(write(1, 0xffff800000000000, 5))
+    0xb8, 0x00, 0x00, 0x00, 0x00,                               // mov eax, 0
+    0xbf, 0x01, 0x00, 0x00, 0x00,                               // mov edi, 1
+    0x48, 0xbe, 0x00, 0x00, 0x00, 0x00, 0x00, 0x80, 0xff, 0xff, // movabs rsi, 0xffff800000000000
+    0xba, 0x05, 0x00, 0x00, 0x00,                               // mov edx, 5
+    0x0f, 0x05,                                                 // syscall
+    0x48, 0x89, 0xc7,                                           // mov rdi, rax
+    0xb8, 0x01, 0x00, 0x00, 0x00,                               // mov eax, 1
+    0x0f, 0x05,                                                 // syscall
+    0xeb, 0xd9,                                                 // jmp 0x0e
+    // 0x35: exit(write(1, "hello\n", 6))
+    0xb8, 0x00, 0x00, 0x00, 0x00,                               // mov eax, 0
+    0xbf, 0x01, 0x00, 0x00, 0x00,                               // mov edi, 1
+    0x48, 0x8d, 0x35, 0x26, 0x00, 0x00, 0x00,                   // lea rsi, [rip + 0x26]
+    0xba, 0x06, 0x00, 0x00, 0x00,                               // mov edx, 6
+    0x0f, 0x05,                                                 // syscall
+    0x48, 0x89, 0xc7,                                           // mov rdi, rax
+    0xb8, 0x01, 0x00, 0x00, 0x00,                               // mov eax, 1
+    0x0f, 0x05,                                                 // syscall
+    0xeb, 0xdc,                                                 // jmp 0x35
+    // 0x59: exit(getpid())
+    0xb8, 0x05, 0x00, 0x00, 0x00,                               // mov eax, 5
+    0x0f, 0x05,                                                 // syscall
+    0x48, 0x89, 0xc7,                                           // mov rdi, rax
+    0xb8, 0x01, 0x00, 0x00, 0x00,                               // mov eax, 1
+    0x0f, 0x05,                                                 // syscall
+    0xeb, 0xed,                                                 // jmp 0x59
+    // 0x6c: "hello\n"
+    b'h', b'e', b'l', b'l', b'o', b'\n',
+    // 0x72: exit(mmap(0x444444440000, 4096, 0))
+    0xb8, 0x04, 0x00, 0x00, 0x00,                               // mov eax, 4
+    0x48, 0xbf, 0x00, 0x00, 0x44, 0x44, 0x44, 0x44, 0x00, 0x00, // movabs rdi, 0x444444440000
+    0xbe, 0x00, 0x10, 0x00, 0x00,                               // mov esi, 4096
+    0x31, 0xd2,                                                 // xor edx, edx
+    0x0f, 0x05,                                                 // syscall
+    0x48, 0x89, 0xc7,                                           // mov rdi, rax
+    0xb8, 0x01, 0x00, 0x00, 0x00,                               // mov eax, 1
+    0x0f, 0x05,                                                 // syscall
+    0xeb, 0xdc,                                                 // jmp 0x72
+];
+
+const EXIT_7: u64 = 0x00;
+const BAD_WRITE: u64 = 0x0e;
+const GOOD_WRITE: u64 = 0x35;
+const GETPID: u64 = 0x59;
+const MMAP_KERNEL_HEAP: u64 = 0x72;
+
+static mut ENTRY: (u64, u64) = (0, 0);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use toy_rust_os::allocator;
+    use toy_rust_os::memory::{self, BootInfoFrameAllocator};
+
+    toy_rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+
+    let (entry, stack) = usermode::load_user_code(USER_CODE, &mut mapper, &mut frame_allocator)
+        .expect("failed to map user code");
+    unsafe { ENTRY = (entry.as_u64(), stack.as_u64()) };
+    memory::install_frame_allocator(frame_allocator);
+
+    test_main();
+    hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    toy_rust_os::test_panic_handler(info)
+}
+
+fn run_user(offset: u64) -> u64 {
+    let (entry, stack) = unsafe { ENTRY };
+    unsafe { usermode::enter_user_mode(VirtAddr::new(entry + offset), VirtAddr::new(stack)) }
+}
+
+#[test_case]
+fn exit_returns_to_kernel() {
+    assert_eq!(run_user(EXIT_7), 7);
+}
+
+#[test_case]
+fn write_with_kernel_pointer_fails() {
+    assert_eq!(run_user(BAD_WRITE) as i64, SyscallError::Fault as i64);
+}
+
+#[test_case]
+fn write_prints_user_buffer() {
+    assert_eq!(run_user(GOOD_WRITE), 6);
+}
+
+#[test_case]
+fn getpid_returns_pid() {
+    // プロセスとして起動していないので、カーネルを表す0が返る
+    assert_eq!(run_user(GETPID), 0);
+}
+
+#[test_case]
+fn invalid_user_range_is_rejected() {
+    use toy_rust_os::syscall::validate_user_range;
+
+    assert_eq!(validate_user_range(0, 8, false), Err(SyscallError::Fault));
+    assert_eq!(
+        validate_user_range(u64::MAX - 4, 8, false),
+        Err(SyscallError::Fault)
+    );
+    let (entry, _) = unsafe { ENTRY };
+    assert_eq!(validate_user_range(entry, 8, false), Ok(()));
+    assert_eq!(
+        validate_user_range(entry, 8, true),
+        Err(SyscallError::Fault)
+    );
+}
+
+#[test_case]
+fn last_canonical_page_is_not_user_space() {
+    use toy_rust_os::syscall::{validate_user_range, USER_SPACE_END};
+
+    // 最後のページからsyscallするとsysretqで戻れないため、ユーザーには渡さない
+    const { assert!(USER_SPACE_END <= 0x_0000_8000_0000_0000 - 4096) };
+    assert_eq!(
+        validate_user_range(USER_SPACE_END, 8, false),
+        Err(SyscallError::Fault)
+    );
+}
+
+#[test_case]
+fn mmap_into_kernel_slot_is_rejected() {
+    // カーネルのヒープは全てのアドレス空間で共有しているので、ユーザーのページを置けない
+    assert_eq!(
+        run_user(MMAP_KERNEL_HEAP) as i64,
+        SyscallError::InvalidArgument as i64
+    );
+}
@@ -0,0 +1,107 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(toy_rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use core::time::Duration;
+use toy_rust_os::{hlt_loop, thread, time};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use toy_rust_os::allocator;
+    use toy_rust_os::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    toy_rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    thread::init();
+
+    test_main();
+    hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    toy_rust_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn join_returns_value() {
+    let handle = thread::spawn(|| 6 * 7);
+    assert_eq!(handle.join(), Some(42));
+}
+
+#[test_case]
+fn threads_run_concurrently() {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+    let handles: Vec<_> = (0..4)
+        .map(|_| {
+            thread::spawn(|| {
+                for _ in 0..10 {
+                    COUNTER.fetch_add(1, Ordering::Relaxed);
+                    thread::yield_now();
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join();
+    }
+    assert_eq!(COUNTER.load(Ordering::Relaxed), 40);
+}
+
+#[test_case]
+fn sleep_waits_for_duration() {
+    let start = time::ticks();
+    thread::spawn(|| thread::sleep(Duration::from_millis(30))).join();
+    assert!(time::ticks() - start >= time::duration_to_ticks(Duration::from_millis(30)));
+}
+
+#[test_case]
+fn busy_thread_is_preempted() {
+    static STOP: AtomicBool = AtomicBool::new(false);
+
+    // 譲らずに回り続けるスレッドがあっても、タイマー割り込みで他のスレッドが実行される
+    let spinner = thread::spawn(|| {
+        while !STOP.load(Ordering::Relaxed) {
+            core::hint::spin_loop();
+        }
+    });
+    let stopper = thread::spawn(|| STOP.store(true, Ordering::Relaxed));
+    spinner.join();
+    stopper.join();
+    assert!(STOP.load(Ordering::Relaxed));
+}
+
+#[test_case]
+fn preempted_threads_can_allocate() {
+    use alloc::boxed::Box;
+
+    // アロケータのロックを持ったまま切り替わると、他のスレッドの割り当てがデッドロックする
+    let handles: Vec<_> = (0..4)
+        .map(|i| {
+            thread::spawn(move || {
+                let mut sum = 0;
+                for j in 0..2000 {
+                    sum += *Box::new(i + j);
+                }
+                sum
+            })
+        })
+        .collect();
+    for (i, handle) in handles.into_iter().enumerate() {
+        assert_eq!(handle.join(), Some(i * 2000 + 1999 * 2000 / 2));
+    }
+}
@@ -0,0 +1,189 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(toy_rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::time::Duration;
+use toy_rust_os::interrupts::interrupt_count;
+use toy_rust_os::memory::{self, GlobalFrameAllocator};
+use toy_rust_os::sync::SpinLock;
+use toy_rust_os::task::executor;
+use toy_rust_os::time::Instant;
+use toy_rust_os::{apic, hlt_loop, ipi, smp, thread, tlb};
+use x86_64::structures::paging::{
+    FrameAllocator, Mapper, Page, PageTableFlags, PhysFrame, Size4KiB,
+};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use toy_rust_os::allocator;
+    use toy_rust_os::memory::BootInfoFrameAllocator;
+
+    toy_rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    memory::install_frame_allocator(frame_allocator);
+    thread::init();
+    smp::init(&mut mapper, &mut GlobalFrameAllocator).expect("SMP initialization failed");
+
+    test_main();
+    hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    toy_rust_os::test_panic_handler(info)
+}
+
+// テストで書き換えるページ。カーネルの他のマッピングと重ならない場所に置く
+const TEST_PAGE: u64 = 0x_3333_3333_0000;
+
+fn wait_until(done: impl Fn() -> bool) {
+    let start = Instant::now();
+    while !done() {
+        assert!(
+            start.elapsed() < Duration::from_secs(5),
+            "other CPUs did not respond"
+        );
+        core::hint::spin_loop();
+    }
+}
+
+// 値を書き込んだフレームを割り当てる
+fn frame_with_value(value: u64) -> PhysFrame {
+    let frame = GlobalFrameAllocator
+        .allocate_frame()
+        .expect("no frame left");
+    unsafe {
+        memory::phys_to_virt(frame.start_address())
+            .as_mut_ptr::<u64>()
+            .write_volatile(value)
+    };
+    frame
+}
+
+fn map_test_page(frame: PhysFrame) {
+    let page = Page::<Size4KiB>::containing_address(VirtAddr::new(TEST_PAGE));
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    let mut mapper = unsafe { memory::active_mapper() };
+    // マップされていなかったページなので、他のCPUのTLBには何も残っていない
+    unsafe {
+        mapper
+            .map_to(page, frame, flags, &mut GlobalFrameAllocator)
+            .expect("map_to failed")
+            .flush()
+    };
+}
+
+#[test_case]
+fn broadcast_ipi_reaches_other_cpus() {
+    let before = interrupt_count(apic::WAKEUP_VECTOR);
+    ipi::broadcast(apic::WAKEUP_VECTOR);
+    let others = smp::online_cpus() as u64 - 1;
+    wait_until(|| interrupt_count(apic::WAKEUP_VECTOR) >= before + others);
+}
+
+#[test_case]
+fn nmi_reaches_other_cpus() {
+    let before = interrupt_count(2);
+    ipi::send(ipi::Destination::AllExcludingSelf, ipi::Ipi::Nmi);
+    let others = smp::online_cpus() as u64 - 1;
+    wait_until(|| interrupt_count(2) >= before + others);
+}
+
+#[test_case]
+fn stale_mapping_is_not_reachable_from_other_cpu() {
+    // APで動き続けるタスクがREQUESTが増えるたびにTEST_PAGEを読み、SEENに書く
+    static REQUEST: AtomicU64 = AtomicU64::new(0);
+    static ANSWERED: AtomicU64 = AtomicU64::new(0);
+    static SEEN: AtomicU64 = AtomicU64::new(0);
+    static STOP: AtomicBool = AtomicBool::new(false);
+
+    map_test_page(frame_with_value(1));
+    // タスクは途中でyieldしないので、最初にpollしたAPで最後まで動く
+    executor::spawn(async {
+        let mut answered = 0;
+        while !STOP.load(Ordering::SeqCst) {
+            let request = REQUEST.load(Ordering::SeqCst);
+            if request != answered {
+                let value = unsafe { (TEST_PAGE as *const u64).read_volatile() };
+                SEEN.store(value, Ordering::SeqCst);
+                answered = request;
+                ANSWERED.store(answered, Ordering::SeqCst);
+            }
+            core::hint::spin_loop();
+        }
+    });
+    let read_on_other_cpu = |request: u64| {
+        REQUEST.store(request, Ordering::SeqCst);
+        wait_until(|| ANSWERED.load(Ordering::SeqCst) == request);
+        SEEN.load(Ordering::SeqCst)
+    };
+
+    // APのTLBに古いフレームへのマッピングを載せる
+    assert_eq!(read_on_other_cpu(1), 1);
+
+    let generation = tlb::generation();
+    let mut mapper = unsafe { memory::active_mapper() };
+    memory::unmap_region(VirtAddr::new(TEST_PAGE), 4096, &mut mapper).expect("unmap failed");
+    assert!(tlb::generation() > generation);
+    // 同じページを別のフレームにマップし直す
+    // シュートダウンされていなければAPはTLBに残った古いフレームを読む
+    map_test_page(frame_with_value(2));
+    assert_eq!(read_on_other_cpu(2), 2);
+
+    STOP.store(true, Ordering::SeqCst);
+    memory::unmap_region(VirtAddr::new(TEST_PAGE), 4096, &mut mapper).expect("unmap failed");
+}
+
+#[test_case]
+fn protect_shoots_down_other_cpus() {
+    map_test_page(frame_with_value(3));
+    let generation = tlb::generation();
+    let mut mapper = unsafe { memory::active_mapper() };
+    memory::protect_region(
+        VirtAddr::new(TEST_PAGE),
+        4096,
+        PageTableFlags::PRESENT,
+        &mut mapper,
+    )
+    .expect("protect failed");
+    // 全てのAPが応答してからprotect_regionが戻る
+    assert_eq!(tlb::generation(), generation + 1);
+    memory::unmap_region(VirtAddr::new(TEST_PAGE), 4096, &mut mapper).expect("unmap failed");
+}
+
+#[test_case]
+fn shootdown_completes_while_other_cpu_waits_for_held_spinlock() {
+    static LOCK: SpinLock<()> = SpinLock::new(());
+    static WAITING: AtomicBool = AtomicBool::new(false);
+    static DONE: AtomicBool = AtomicBool::new(false);
+
+    map_test_page(frame_with_value(4));
+    let guard = LOCK.lock();
+    executor::spawn(async {
+        WAITING.store(true, Ordering::SeqCst);
+        // 割り込みを止めたまま、BSPが持っているロックを待つ
+        drop(LOCK.lock());
+        DONE.store(true, Ordering::SeqCst);
+    });
+    wait_until(|| WAITING.load(Ordering::SeqCst));
+
+    // APはIPIを受け取れないが、ロックを待つ間に応答するのでシュートダウンは終わる
+    let generation = tlb::generation();
+    let mut mapper = unsafe { memory::active_mapper() };
+    memory::unmap_region(VirtAddr::new(TEST_PAGE), 4096, &mut mapper).expect("unmap failed");
+    assert_eq!(tlb::generation(), generation + 1);
+    drop(guard);
+    wait_until(|| DONE.load(Ordering::SeqCst));
+}
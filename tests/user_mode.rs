@@ -0,0 +1,116 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(toy_rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use toy_rust_os::{hlt_loop, usermode};
+
+entry_point!(main);
+
+// ring 3で実行する小さなルーチン
+// 0:  mov edi, 42; int 0x80; jmp $
+// 9:  mov eax, [0]; jmp $      (ページフォールト)
+// 18: hlt; jmp $               (一般保護例外)
+const USER_CODE: &[u8] = &[
+    0xbf, 0x2a, 0x00, 0x00, 0x00, 0xcd, 0x80, 0xeb, 0xfe, //
+    0x8b, 0x04, 0x25, 0x00, 0x00, 0x00, 0x00, 0xeb, 0xfe, //
+    0xf4, 0xeb, 0xfe,
+];
+const PAGE_FAULT_OFFSET: u64 = 9;
+const GENERAL_PROTECTION_OFFSET: u64 = 18;
+
+static mut ENTRY: (u64, u64) = (0, 0);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use toy_rust_os::allocator;
+    use toy_rust_os::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    toy_rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+
+    let (entry, stack) = usermode::load_user_code(USER_CODE, &mut mapper, &mut frame_allocator)
+        .expect("failed to map user code");
+    unsafe { ENTRY = (entry.as_u64(), stack.as_u64()) };
+
+    test_main();
+    hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    toy_rust_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn user_routine_returns_exit_code() {
+    use x86_64::VirtAddr;
+
+    let (entry, stack) = unsafe { ENTRY };
+    let code = unsafe { usermode::enter_user_mode(VirtAddr::new(entry), VirtAddr::new(stack)) };
+    assert_eq!(code, 42);
+}
+
+#[test_case]
+fn kernel_continues_after_user_mode() {
+    use x86_64::VirtAddr;
+
+    // 2回目もスタックが壊れずにカーネルへ戻り、割り込みが有効なままであること
+    let (entry, stack) = unsafe { ENTRY };
+    let code = unsafe { usermode::enter_user_mode(VirtAddr::new(entry), VirtAddr::new(stack)) };
+    assert_eq!(code, 42);
+    assert!(x86_64::instructions::interrupts::are_enabled());
+}
+
+#[test_case]
+fn gs_base_is_restored_after_user_mode() {
+    use toy_rust_os::percpu;
+    use x86_64::registers::model_specific::{GsBase, KernelGsBase};
+    use x86_64::VirtAddr;
+
+    // ユーザーモードから戻った後もGSのベースはpercpuのデータを指していること
+    let (entry, stack) = unsafe { ENTRY };
+    unsafe { usermode::enter_user_mode(VirtAddr::new(entry), VirtAddr::new(stack)) };
+    assert_eq!(GsBase::read(), VirtAddr::from_ptr(percpu::current()));
+    assert_eq!(KernelGsBase::read(), VirtAddr::new(0));
+    assert_eq!(percpu::cpu_id(), 0);
+}
+
+#[test_case]
+fn user_page_fault_terminates_program() {
+    use x86_64::VirtAddr;
+
+    // カーネルは止まらず、例外を起こしたプログラムだけが終了すること
+    let (entry, stack) = unsafe { ENTRY };
+    let code = unsafe {
+        usermode::enter_user_mode(
+            VirtAddr::new(entry + PAGE_FAULT_OFFSET),
+            VirtAddr::new(stack),
+        )
+    };
+    assert_eq!(code, usermode::FAULT_EXIT_CODE);
+    assert!(x86_64::instructions::interrupts::are_enabled());
+}
+
+#[test_case]
+fn user_general_protection_fault_terminates_program() {
+    use toy_rust_os::percpu;
+    use x86_64::VirtAddr;
+
+    let (entry, stack) = unsafe { ENTRY };
+    let code = unsafe {
+        usermode::enter_user_mode(
+            VirtAddr::new(entry + GENERAL_PROTECTION_OFFSET),
+            VirtAddr::new(stack),
+        )
+    };
+    assert_eq!(code, usermode::FAULT_EXIT_CODE);
+    assert_eq!(percpu::cpu_id(), 0);
+    assert!(!percpu::current().in_interrupt());
+}